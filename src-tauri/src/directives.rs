@@ -0,0 +1,159 @@
+use regex::Regex;
+
+use crate::segmentation::Segment;
+
+// Pacing instructions written as Obsidian comments, e.g. `%% md2vid: pause 2s %%`.
+// They are invisible in Obsidian's reading view and apply to the segment that follows them
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Directive {
+    // Hold an empty frame for this many seconds before the segment
+    Pause(f64),
+    // Show the segment for exactly this many seconds
+    Duration(f64),
+    // Keep the previous segment's image on screen
+    ImageHold,
+    // Leave the segment out of the video
+    Skip,
+}
+
+// Private-use characters wrapping the index of a directive left in the text for segmentation
+const MARKER_START: char = '\u{E000}';
+const MARKER_END: char = '\u{E001}';
+
+fn parse_directive(body: &str) -> Option<Directive> {
+    let re = Regex::new(r"^\s*md2vid:\s*([a-z-]+)\s*([0-9]+(?:\.[0-9]+)?)?\s*s?\s*$").unwrap();
+    let caps = re.captures(body)?;
+    let value = caps.get(2).and_then(|value| value.as_str().parse::<f64>().ok());
+
+    match (&caps[1], value) {
+        ("pause", Some(seconds)) => Some(Directive::Pause(seconds)),
+        ("duration", Some(seconds)) => Some(Directive::Duration(seconds)),
+        ("image-hold", None) => Some(Directive::ImageHold),
+        ("skip", None) => Some(Directive::Skip),
+        _ => None,
+    }
+}
+
+// Strip every `%% comment %%` from the note body. md2vid directives are replaced
+// by markers so they can be attached to the right segment after segmentation.
+// Directives that can't be read are left out and returned as warnings
+pub fn extract_directives(text: &str) -> (String, Vec<Directive>, Vec<String>) {
+    let re = Regex::new(r"(?s)%%(.*?)%%").unwrap();
+    let mut directives = Vec::new();
+    let mut warnings = Vec::new();
+
+    let result = re.replace_all(text, |caps: &regex::Captures| match parse_directive(&caps[1]) {
+        Some(directive) => {
+            directives.push(directive);
+            format!("{}{}{}", MARKER_START, directives.len() - 1, MARKER_END)
+        }
+        None => {
+            if caps[1].trim_start().starts_with("md2vid:") {
+                warnings.push(format!("Unknown md2vid directive: {}", caps[1].trim()));
+            }
+            String::new()
+        }
+    });
+
+    (result.into_owned(), directives, warnings)
+}
+
+// Move the directive markers out of the segment text and onto the segments.
+// A segment that held nothing but directives passes them on to the next one
+pub fn attach_directives(segments: Vec<Segment>, directives: &[Directive]) -> Vec<Segment> {
+    let re = Regex::new(&format!("{}([0-9]+){}", MARKER_START, MARKER_END)).unwrap();
+    let mut pending = Vec::new();
+    let mut result = Vec::new();
+
+    for mut segment in segments {
+        for caps in re.captures_iter(&segment.text) {
+            if let Some(directive) = caps[1].parse::<usize>().ok().and_then(|i| directives.get(i)) {
+                pending.push(*directive);
+            }
+        }
+        segment.text = re.replace_all(&segment.text, "").trim().to_string();
+
+        if segment.text.is_empty() {
+            continue;
+        }
+        segment.directives.append(&mut pending);
+        result.push(segment);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segmentation::SegmentKind;
+
+    #[test]
+    fn reads_valid_directives() {
+        let (text, directives, warnings) =
+            extract_directives("%% md2vid: pause 2s %%One. %%md2vid: duration 1.5%%Two. %% md2vid: image-hold %% %% md2vid: skip %%");
+        assert_eq!(
+            directives,
+            [Directive::Pause(2.0), Directive::Duration(1.5), Directive::ImageHold, Directive::Skip]
+        );
+        assert!(!text.contains("md2vid"));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn drops_malformed_directives() {
+        let malformed = [
+            "%% md2vid: pause %%",
+            "%% md2vid: pause two %%",
+            "%% md2vid: pause -2 %%",
+            "%% md2vid: duration 3 seconds %%",
+            "%% md2vid: skip 3 %%",
+            "%% md2vid: image-hold 1s %%",
+            "%% md2vid: wobble 2s %%",
+            "%% md2vid: PAUSE 2s %%",
+            "%% md2vid: %%",
+            "%% md2vid pause 2s %%",
+        ];
+        for comment in malformed {
+            let (text, directives, warnings) = extract_directives(&format!("Before {} after", comment));
+            assert!(directives.is_empty(), "{}", comment);
+            assert_eq!(text, "Before  after", "{}", comment);
+            // Without the colon it's an ordinary comment
+            if comment.starts_with("%% md2vid:") {
+                let body = comment.trim_start_matches('%').trim_end_matches('%').trim();
+                assert_eq!(warnings, [format!("Unknown md2vid directive: {}", body)]);
+            } else {
+                assert!(warnings.is_empty(), "{}", comment);
+            }
+        }
+    }
+
+    #[test]
+    fn ordinary_comments_are_not_warned_about() {
+        let (text, directives, warnings) = extract_directives("Shown %% a private note %%text");
+        assert!(directives.is_empty());
+        assert!(warnings.is_empty());
+        assert_eq!(text, "Shown text");
+    }
+
+    #[test]
+    fn unclosed_comments_stay_in_the_text() {
+        let (text, directives, _) = extract_directives("Text %% md2vid: pause 2s");
+        assert!(directives.is_empty());
+        assert_eq!(text, "Text %% md2vid: pause 2s");
+    }
+
+    #[test]
+    fn directive_only_segments_pass_directives_on() {
+        let (text, directives, _) = extract_directives("%% md2vid: pause 1 %%|%% md2vid: skip %%Shown|Next");
+        let segments = text
+            .split('|')
+            .map(|text| Segment { kind: SegmentKind::Sentence, text: text.to_string(), directives: Vec::new() })
+            .collect();
+        let segments = attach_directives(segments, &directives);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].text, "Shown");
+        assert_eq!(segments[0].directives, [Directive::Pause(1.0), Directive::Skip]);
+        assert!(segments[1].directives.is_empty());
+    }
+}
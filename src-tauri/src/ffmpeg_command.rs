@@ -0,0 +1,434 @@
+use std::fmt;
+
+// Typed pieces of an ffmpeg command line. Nothing here runs ffmpeg: a command is turned into
+// its argument list, which ffmpeg_operations spawns (or prints, in dry-run mode)
+
+// A filter such as `scale=1280:-1` or `ass=filename=subs.ass`. Argument values are escaped
+// when the filter is written out, so any path or text can be passed as is
+#[derive(Clone)]
+pub struct Filter {
+    name: String,
+    args: Vec<(Option<String>, String)>,
+}
+
+impl Filter {
+    pub fn new(name: &str) -> Self {
+        Filter { name: name.to_string(), args: Vec::new() }
+    }
+
+    // Positional argument, as in `scale=1280:-1`
+    pub fn arg(mut self, value: impl ToString) -> Self {
+        self.args.push((None, value.to_string()));
+        self
+    }
+
+    // Named argument, as in `fade=t=in`
+    pub fn option(mut self, key: &str, value: impl ToString) -> Self {
+        self.args.push((Some(key.to_string()), value.to_string()));
+        self
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (i, (key, value)) in self.args.iter().enumerate() {
+            f.write_str(if i == 0 { "=" } else { ":" })?;
+            if let Some(key) = key {
+                write!(f, "{}=", key)?;
+            }
+            f.write_str(&escape_graph(&escape_value(value)))?;
+        }
+        Ok(())
+    }
+}
+
+// Filters applied one after the other, reading the `inputs` pads and writing the `outputs` pads.
+// Unlabelled ends connect to the command's inputs and outputs
+#[derive(Clone, Default)]
+pub struct FilterChain {
+    inputs: Vec<String>,
+    filters: Vec<Filter>,
+    outputs: Vec<String>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        FilterChain::default()
+    }
+
+    // A pad label such as `0:v` or `scaled`, without brackets
+    pub fn input(mut self, label: &str) -> Self {
+        self.inputs.push(label.to_string());
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    pub fn filters(mut self, filters: impl IntoIterator<Item = Filter>) -> Self {
+        self.filters.extend(filters);
+        self
+    }
+
+    pub fn output(mut self, label: &str) -> Self {
+        self.outputs.push(label.to_string());
+        self
+    }
+}
+
+impl fmt::Display for FilterChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for label in &self.inputs {
+            write!(f, "[{}]", label)?;
+        }
+        let filters: Vec<String> = self.filters.iter().map(|filter| filter.to_string()).collect();
+        f.write_str(&filters.join(","))?;
+        for label in &self.outputs {
+            write!(f, "[{}]", label)?;
+        }
+        Ok(())
+    }
+}
+
+// The `-filter_complex` graph
+#[derive(Clone, Default)]
+pub struct Filtergraph {
+    chains: Vec<FilterChain>,
+}
+
+impl Filtergraph {
+    pub fn new() -> Self {
+        Filtergraph::default()
+    }
+
+    pub fn chain(mut self, chain: FilterChain) -> Self {
+        self.chains.push(chain);
+        self
+    }
+
+    pub fn push(&mut self, chain: FilterChain) {
+        self.chains.push(chain);
+    }
+}
+
+impl fmt::Display for Filtergraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chains: Vec<String> = self.chains.iter().map(|chain| chain.to_string()).collect();
+        f.write_str(&chains.join("; "))
+    }
+}
+
+// An input file with the options that apply to it
+#[derive(Clone)]
+pub struct Input {
+    options: Vec<String>,
+    source: String,
+}
+
+impl Input {
+    pub fn file(path: &str) -> Self {
+        Input { options: Vec::new(), source: path.to_string() }
+    }
+
+    // A still image repeated for as long as it's read
+    pub fn looped_image(path: &str) -> Self {
+        Input::file(path).option("-loop", "1")
+    }
+
+    // A generated source such as `color`, read through the lavfi device
+    pub fn lavfi(source: Filter) -> Self {
+        Input { options: vec!["-f".to_string(), "lavfi".to_string()], source: source.to_string() }
+    }
+
+    // Concat demuxer list
+    pub fn concat_list(path: &str) -> Self {
+        Input::file(path).option("-f", "concat").option("-safe", "0")
+    }
+
+    pub fn duration(self, seconds: f64) -> Self {
+        self.option("-t", format!("{:.3}", seconds))
+    }
+
+    pub fn option(mut self, key: &str, value: impl ToString) -> Self {
+        self.options.push(key.to_string());
+        self.options.push(value.to_string());
+        self
+    }
+}
+
+// An output file with its stream mapping and codec options
+#[derive(Clone)]
+pub struct OutputFile {
+    options: Vec<String>,
+    path: String,
+}
+
+impl OutputFile {
+    pub fn new(path: &str) -> Self {
+        OutputFile { options: Vec::new(), path: path.to_string() }
+    }
+
+    // An input stream such as `1:a:0` or a filtergraph label such as `[out]`
+    pub fn map(self, stream: &str) -> Self {
+        self.option("-map", stream)
+    }
+
+    pub fn duration(self, seconds: f64) -> Self {
+        self.option("-t", format!("{:.3}", seconds))
+    }
+
+    pub fn video_codec(self, codec: &str) -> Self {
+        self.option("-c:v", codec)
+    }
+
+    pub fn audio_codec(self, codec: &str) -> Self {
+        self.option("-c:a", codec)
+    }
+
+    // Stream-copy every stream
+    pub fn copy(self) -> Self {
+        self.option("-c", "copy")
+    }
+
+    // A simple filtergraph for the audio stream, such as `loudnorm`
+    pub fn audio_filter(self, filter: Filter) -> Self {
+        self.option("-af", filter)
+    }
+
+    pub fn video_bitrate(self, bitrate: &str) -> Self {
+        self.option("-b:v", bitrate)
+    }
+
+    pub fn preset(self, preset: &str) -> Self {
+        self.option("-preset", preset)
+    }
+
+    pub fn option(mut self, key: &str, value: impl ToString) -> Self {
+        self.options.push(key.to_string());
+        self.options.push(value.to_string());
+        self
+    }
+
+    pub fn flag(mut self, flag: &str) -> Self {
+        self.options.push(flag.to_string());
+        self
+    }
+}
+
+// A whole ffmpeg invocation. Output files are always overwritten
+#[derive(Clone, Default)]
+pub struct FfmpegCommand {
+    global: Vec<String>,
+    inputs: Vec<Input>,
+    filtergraph: Option<Filtergraph>,
+    outputs: Vec<OutputFile>,
+}
+
+impl FfmpegCommand {
+    pub fn new() -> Self {
+        FfmpegCommand::default()
+    }
+
+    pub fn global(mut self, option: &str) -> Self {
+        self.global.push(option.to_string());
+        self
+    }
+
+    // Inputs are numbered in the order they are added, starting at 0
+    pub fn input(mut self, input: Input) -> Self {
+        self.inputs.push(input);
+        self
+    }
+
+    pub fn filtergraph(mut self, filtergraph: Filtergraph) -> Self {
+        self.filtergraph = Some(filtergraph);
+        self
+    }
+
+    pub fn output(mut self, output: OutputFile) -> Self {
+        self.outputs.push(output);
+        self
+    }
+
+    // The arguments after `ffmpeg`, exactly as they are passed to the process
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec!["-y".to_string()];
+        args.extend(self.global.iter().cloned());
+        for input in &self.inputs {
+            args.extend(input.options.iter().cloned());
+            args.push("-i".to_string());
+            args.push(input.source.clone());
+        }
+        if let Some(filtergraph) = &self.filtergraph {
+            args.push("-filter_complex".to_string());
+            args.push(filtergraph.to_string());
+        }
+        for output in &self.outputs {
+            args.extend(output.options.iter().cloned());
+            args.push(output.path.clone());
+        }
+        args
+    }
+
+    // The command as it would be typed in a POSIX shell
+    pub fn command_line(&self) -> String {
+        let mut words = vec!["ffmpeg".to_string()];
+        words.extend(self.args().iter().map(|arg| shell_quote(arg)));
+        words.join(" ")
+    }
+}
+
+// First level of filter escaping: a value inside a filter's argument list, where `:` separates
+// arguments and `'` and `\` quote
+fn escape_value(value: &str) -> String {
+    escape(value, &['\\', '\'', ':'])
+}
+
+// A `key=value:key=value` list for an option such as -x265-params, which ffmpeg splits with the
+// same quoting as a filter's arguments
+pub fn key_value_list(pairs: &[(&str, String)]) -> String {
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, escape_value(value)))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// Second level: the filter inside the filtergraph, where `,` and `;` separate filters and
+// chains and brackets delimit labels
+fn escape_graph(text: &str) -> String {
+    escape(text, &['\\', '\'', '[', ']', ',', ';'])
+}
+
+fn escape(text: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=+,@%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_filter_values() {
+        let cases = [
+            ("plain.ass", "plain.ass"),
+            ("it's", "it\\'s"),
+            ("C:/subs.ass", "C\\:/subs.ass"),
+            ("a\\b", "a\\\\b"),
+            ("[in]", "[in]"),
+            ("a,b;c", "a,b;c"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(escape_value(value), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn escapes_filtergraph_text() {
+        let cases = [
+            ("plain", "plain"),
+            ("it's", "it\\'s"),
+            ("a:b", "a:b"),
+            ("a\\b", "a\\\\b"),
+            ("[in]", "\\[in\\]"),
+            ("a,b;c", "a\\,b\\;c"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(escape_graph(text), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn filters_escape_both_levels() {
+        let cases = [
+            ("subs.ass", "ass=filename=subs.ass"),
+            ("it's.ass", "ass=filename=it\\\\\\'s.ass"),
+            ("C:/subs.ass", "ass=filename=C\\\\:/subs.ass"),
+            ("a\\b.ass", "ass=filename=a\\\\\\\\b.ass"),
+            ("[a].ass", "ass=filename=\\[a\\].ass"),
+            ("a,b;c.ass", "ass=filename=a\\,b\\;c.ass"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(Filter::new("ass").option("filename", path).to_string(), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn key_value_lists_escape_values() {
+        let cases = [
+            ("x265.log", "pass=1:stats=x265.log"),
+            ("C:/job/x265.log", "pass=1:stats=C\\:/job/x265.log"),
+            ("it's.log", "pass=1:stats=it\\'s.log"),
+            ("a\\b.log", "pass=1:stats=a\\\\b.log"),
+            ("[a],b;c.log", "pass=1:stats=[a],b;c.log"),
+        ];
+        for (passlog, expected) in cases {
+            assert_eq!(key_value_list(&[("pass", "1".to_string()), ("stats", passlog.to_string())]), expected);
+        }
+    }
+
+    #[test]
+    fn args_keep_values_whole() {
+        let command = FfmpegCommand::new()
+            .global("-nostats")
+            .input(Input::looped_image("it's [1].png"))
+            .filtergraph(Filtergraph::new().chain(
+                FilterChain::new().input("0:v").filter(Filter::new("ass").option("filename", "a:b,c;d.ass")).output("out"),
+            ))
+            .output(OutputFile::new("out; rm.mp4").map("[out]"));
+        assert_eq!(
+            command.args(),
+            [
+                "-y",
+                "-nostats",
+                "-loop",
+                "1",
+                "-i",
+                "it's [1].png",
+                "-filter_complex",
+                "[0:v]ass=filename=a\\\\:b\\,c\\;d.ass[out]",
+                "-map",
+                "[out]",
+                "out; rm.mp4",
+            ]
+        );
+    }
+
+    #[test]
+    fn command_lines_quote_for_the_shell() {
+        let cases = [
+            ("out.mp4", "ffmpeg -y -i in.mp4 out.mp4"),
+            ("it's.mp4", "ffmpeg -y -i in.mp4 'it'\\''s.mp4'"),
+            ("a:b.mp4", "ffmpeg -y -i in.mp4 a:b.mp4"),
+            ("a\\b.mp4", "ffmpeg -y -i in.mp4 'a\\b.mp4'"),
+            ("[out].mp4", "ffmpeg -y -i in.mp4 '[out].mp4'"),
+            ("a,b;c.mp4", "ffmpeg -y -i in.mp4 'a,b;c.mp4'"),
+        ];
+        for (path, expected) in cases {
+            let command = FfmpegCommand::new().input(Input::file("in.mp4")).output(OutputFile::new(path));
+            assert_eq!(command.command_line(), expected, "{}", path);
+        }
+    }
+}
@@ -1,51 +1,218 @@
 use std::error::Error;
+use std::process::{Output, Stdio};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
 use tokio::process::Command;
+use crate::ffmpeg_command::{FfmpegCommand, Filter, FilterChain, Filtergraph, Input, OutputFile};
 use crate::image_resolution::ImageResolution;
+use crate::log_utils;
+use crate::note_settings::Transition;
+use crate::platform::Loudness;
+use crate::render_profile::RenderProfile;
 
+// One block of ffmpeg's `-progress` output: how much of the output has been written, in
+// seconds, and how fast the encoder is going
+#[derive(Clone, Copy, Default)]
+pub struct FfmpegProgress {
+    pub out_time: f64,
+    pub fps: f64,
+    pub speed: f64,
+}
+
+// Called with every progress block of an ffmpeg run
+pub type OnProgress<'a> = &'a (dyn Fn(FfmpegProgress) + Send + Sync);
+
+// Set FFMPEG_DRY_RUN=true to print every ffmpeg command instead of running it. Only the
+// encoding is skipped: audio and images are still fetched and the job's intermediate files
+// (subtitles, chapters, file lists) are still written, so the printed commands point at real inputs
+pub fn dry_run() -> bool {
+    std::env::var("FFMPEG_DRY_RUN").map_or(false, |value| value == "true" || value == "1")
+}
+
+// Run `ffmpeg` with progress reported on stdout, passing each progress block to `on_progress`.
+// Returns what `Command::output` would, minus the progress lines. The process is killed if
+// the render is abandoned, so a failed or cancelled run leaves no ffmpeg behind
+async fn run_with_progress(
+    ffmpeg: FfmpegCommand,
+    on_progress: OnProgress<'_>,
+) -> Result<Output, Box<dyn Error + Send + Sync>> {
+    let ffmpeg = ffmpeg.global("-progress").global("pipe:1").global("-nostats");
+    if dry_run() {
+        log_utils::print_pretty_log(&ffmpeg.command_line(), "blue");
+        return Ok(Output { status: Default::default(), stdout: Vec::new(), stderr: Vec::new() });
+    }
+
+    let mut child = Command::new("ffmpeg")
+        .kill_on_drop(true)
+        .args(ffmpeg.args())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().ok_or("ffmpeg stdout was not captured")?;
+    let mut stderr = child.stderr.take().ok_or("ffmpeg stderr was not captured")?;
+
+    // stderr is drained alongside stdout so a chatty ffmpeg can't fill its pipe and stall
+    let read_stderr = async {
+        let mut buffer = Vec::new();
+        stderr.read_to_end(&mut buffer).await.map(|_| buffer)
+    };
+    let read_progress = async {
+        let mut lines = BufReader::new(stdout).lines();
+        let mut progress = FfmpegProgress::default();
+        while let Some(line) = lines.next_line().await? {
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            // Values are N/A until the first frame is out, those keep the previous reading
+            match key {
+                // Despite its name out_time_ms is in microseconds too
+                "out_time_us" | "out_time_ms" => {
+                    if let Ok(micros) = value.parse::<f64>() {
+                        progress.out_time = micros / 1_000_000.0;
+                    }
+                }
+                "fps" => progress.fps = value.parse().unwrap_or(progress.fps),
+                "speed" => progress.speed = value.trim_end_matches('x').parse().unwrap_or(progress.speed),
+                // Ends every block, with `end` after the last one
+                "progress" => on_progress(progress),
+                _ => {}
+            }
+        }
+        Ok::<(), std::io::Error>(())
+    };
+
+    let (stderr, progress) = tokio::join!(read_stderr, read_progress);
+    progress?;
+    let status = child.wait().await?;
+    Ok(Output { status, stdout: Vec::new(), stderr: stderr? })
+}
+
+// Bitrate of the soundtrack in the final video, in bits per second
+pub const AUDIO_BITRATE: u64 = 128_000;
+
+// Margin between a QR code and the corner of the frame
+pub const QR_CODE_MARGIN: u32 = 20;
+
+// The plain background every video is drawn on, at the profile's size and frame rate
+fn background_source(background: &str, profile: &RenderProfile) -> Filter {
+    Filter::new("color")
+        .option("color", background)
+        .option("size", profile.size())
+        .option("rate", profile.fps)
+}
+
+// Burn in the subtitles of `ass_file_name`, with the fonts the note uses
+pub fn subtitles_filter(ass_file_name: &str, fonts_dir: &str) -> Filter {
+    Filter::new("ass").option("filename", ass_file_name).option("fontsdir", fonts_dir)
+}
+
+// Distance of a QR code from the right and top edges of the frame, clear of the safe zone
+pub fn qr_code_margins(profile: &RenderProfile) -> (u32, u32) {
+    let insets = profile.insets();
+    (insets.right.max(QR_CODE_MARGIN), insets.top.max(QR_CODE_MARGIN))
+}
+
+// A QR code in the top right corner
+pub fn qr_code_overlay_filter(profile: &RenderProfile) -> Filter {
+    let (right, top) = qr_code_margins(profile);
+    Filter::new("overlay").arg(format!("W-w-{}", right)).arg(top)
+}
+
+// An image scaled to `resolution`, centred horizontally in the upper part of the safe area
+// (see RenderProfile::image_position)
+pub fn image_scale_filter(resolution: ImageResolution, profile: &RenderProfile) -> Filter {
+    Filter::new("scale").arg(profile.image_width(resolution)).arg(-1)
+}
+
+pub fn image_overlay_filter(profile: &RenderProfile) -> Filter {
+    let insets = profile.insets();
+    if insets.is_zero() {
+        return Filter::new("overlay").arg("(W-w)/2").arg("(H-h)/4");
+    }
+    Filter::new("overlay")
+        .arg(format!("{}+(W-{}-w)/2", insets.left, insets.left + insets.right))
+        .arg(format!("{}+(H-{}-h)/4", insets.top, insets.top + insets.bottom))
+}
+
+// Every rendered video is encoded as the profile says
+fn encoded_output(output_file: &str, duration: f64, profile: &RenderProfile) -> OutputFile {
+    profile.encode(OutputFile::new(output_file).duration(duration))
+}
+
+// `qr_code` is an optional image drawn in the top right corner, under the subtitles
+#[allow(clippy::too_many_arguments)]
 pub async fn execute_ffmpeg_command(
     ass_file_name: &str,
-    index: usize,
-) -> Result<std::process::Output, Box<dyn Error + Send + Sync>> {
-    let command_output = Command::new("ffmpeg")
-        .args(&[
-            "-y",
-            "-f",
-            "lavfi",
-            "-i",
-            "color=color=black:size=1280x720",
-            "-vf",
-            &format!("ass={}:fontsdir=./", ass_file_name),
-            "-t",
-            "5",
-            "-b:v",
-            "5M",
-            "-preset",
-            "slow",
-            "-y",
-            &format!("output{}.mp4", index),
-        ])
-        .output()
-        .await?;
-
-    Ok(command_output)
-}
-
-pub async fn concatenate_videos() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let command_output = Command::new("ffmpeg")
-        .args(&[
-            "-f",
-            "concat",
-            "-safe",
-            "0",
-            "-i",
-            "file_list.txt",
-            "-c",
-            "copy",
-            "-y",
-            "output.mp4",
-        ])
-        .output()
-        .await?;
+    output_file: &str,
+    duration: f64,
+    background: &str,
+    fonts_dir: &str,
+    transition: Transition,
+    qr_code: Option<&str>,
+    profile: &RenderProfile,
+    on_progress: OnProgress<'_>,
+) -> Result<Output, Box<dyn Error + Send + Sync>> {
+    let mut command = FfmpegCommand::new().input(Input::lavfi(background_source(background, profile)));
+    let mut chain = FilterChain::new().input("0:v");
+    if let Some(qr_code) = qr_code {
+        command = command.input(Input::looped_image(qr_code));
+        chain = chain.input("1:v").filter(qr_code_overlay_filter(profile));
+    }
+    let chain = chain
+        .filter(subtitles_filter(ass_file_name, fonts_dir))
+        .filters(transition.filters(duration));
+
+    let command = command
+        .filtergraph(Filtergraph::new().chain(chain))
+        .output(encoded_output(output_file, duration, profile));
+
+    run_with_progress(command, on_progress).await
+}
+
+// Render the whole video in one pass: the background for `duration` seconds, each overlay
+// image looped for its own duration, combined by `filtergraph` (see timeline::filtergraph)
+pub async fn render_timeline(
+    filtergraph: Filtergraph,
+    overlay_inputs: &[(String, f64)],
+    duration: f64,
+    background: &str,
+    output_file: &str,
+    profile: &RenderProfile,
+    on_progress: OnProgress<'_>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let background_input = background_source(background, profile).option("duration", format!("{:.3}", duration));
+
+    let mut command = FfmpegCommand::new().input(Input::lavfi(background_input));
+    for (path, overlay_duration) in overlay_inputs {
+        command = command.input(Input::looped_image(path).duration(*overlay_duration));
+    }
+    let command = command
+        .filtergraph(filtergraph)
+        .output(encoded_output(output_file, duration, profile));
+    let command_output = run_with_progress(command, on_progress).await?;
+
+    if command_output.status.success() {
+        println!("Timeline rendered successfully!");
+        Ok(())
+    } else {
+        eprintln!(
+            "Error rendering timeline: {}",
+            String::from_utf8_lossy(&command_output.stderr)
+        );
+        Err("Failed to render timeline".into())
+    }
+}
+
+// `file_list` is a concat demuxer list; relative entries are resolved next to it
+pub async fn concatenate_videos(
+    file_list: &str,
+    output_file: &str,
+    on_progress: OnProgress<'_>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let command = FfmpegCommand::new()
+        .input(Input::concat_list(file_list))
+        .output(OutputFile::new(output_file).copy());
+    let command_output = run_with_progress(command, on_progress).await?;
 
     if command_output.status.success() {
         println!("Videos concatenated successfully!");
@@ -59,29 +226,40 @@ pub async fn concatenate_videos() -> Result<(), Box<dyn Error + Send + Sync>> {
     }
 }
 
-pub async fn merge_audio_with_video() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let command_output = Command::new("ffmpeg")
-        .args(&[
-            "-y", // Allow overwrite
-            "-i",
-            "output.mp4",
-            "-i",
-            "./temp_files/audio.mp3",
-            "-c:v",
-            "copy",
-            "-c:a",
-            "aac",
-            "-strict",
-            "experimental",
-            "-map",
-            "0:v:0",
-            "-map",
-            "1:a:0",
-            "-shortest",
-            "final_output.mp4",
-        ])
-        .output()
-        .await?;
+// `video_file` is the rendered, silent video, already at its final size, so its stream is copied.
+// `chapters_file` is an optional FFMETADATA file with chapter markers for the final video.
+// Without `audio_file` the video stays silent. `loudness` normalises the audio to a
+// platform's target
+pub async fn merge_audio_with_video(
+    video_file: &str,
+    chapters_file: Option<&str>,
+    audio_file: Option<&str>,
+    loudness: Option<Loudness>,
+    output_file: &str,
+    on_progress: OnProgress<'_>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut command = FfmpegCommand::new().input(Input::file(video_file));
+    let mut output = OutputFile::new(output_file).map("0:v:0").video_codec("copy");
+    let mut next_input = 1;
+    if let Some(audio_file) = audio_file {
+        command = command.input(Input::file(audio_file));
+        output = output
+            .map(&format!("{}:a:0", next_input))
+            .audio_codec("aac")
+            .option("-b:a", format!("{}k", AUDIO_BITRATE / 1000))
+            .option("-strict", "experimental")
+            .flag("-shortest");
+        if let Some(loudness) = loudness {
+            output = output.audio_filter(loudness.filter());
+        }
+        next_input += 1;
+    }
+    if let Some(chapters_file) = chapters_file {
+        command = command.input(Input::file(chapters_file));
+        output = output.option("-map_chapters", next_input);
+    }
+
+    let command_output = run_with_progress(command.output(output), on_progress).await?;
 
     if command_output.status.success() {
         println!("Audio merged with video successfully!");
@@ -95,48 +273,81 @@ pub async fn merge_audio_with_video() -> Result<(), Box<dyn Error + Send + Sync>
     }
 }
 
+// Re-encode the finished video at an average of `video_bitrate` bits per second, in two passes
+// so the bitrate goes where the video needs it. The first pass only analyses the video, the
+// second writes `output_file` with the audio and chapters copied. `on_progress` is told which
+// pass (0 or 1) each update is from
+pub async fn encode_two_pass(
+    input_file: &str,
+    output_file: &str,
+    video_bitrate: u64,
+    profile: &RenderProfile,
+    passlog: &str,
+    on_progress: &(dyn Fn(usize, FfmpegProgress) + Send + Sync),
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let null_output = if cfg!(windows) { "NUL" } else { "/dev/null" };
+    let first_pass = FfmpegCommand::new().input(Input::file(input_file)).output(
+        profile
+            .encode_pass(OutputFile::new(null_output).map("0:v:0"), video_bitrate, 1, passlog)
+            .flag("-an")
+            .option("-f", "null"),
+    );
+    let second_pass = FfmpegCommand::new().input(Input::file(input_file)).output(
+        profile
+            .encode_pass(OutputFile::new(output_file).map("0:v:0").map("0:a?"), video_bitrate, 2, passlog)
+            .audio_codec("copy"),
+    );
+
+    for (pass, command) in [first_pass, second_pass].into_iter().enumerate() {
+        let command_output = run_with_progress(command, &|update| on_progress(pass, update)).await?;
+        if !command_output.status.success() {
+            eprintln!(
+                "Error in pass {} of the two-pass encode: {}",
+                pass + 1,
+                String::from_utf8_lossy(&command_output.stderr)
+            );
+            return Err(format!("Failed to encode the video (pass {} of 2)", pass + 1).into());
+        }
+    }
+    println!("Video encoded to the target bitrate successfully!");
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn generate_video_with_text_and_image(
     ass_file_name: &str,
     image_file_path: &str,
-    index: usize,
+    output_file: &str,
     resolution: ImageResolution,
-) -> Result<std::process::Output, Box<dyn Error + Send + Sync>> {
-    let scale = match resolution {
-        ImageResolution::Low => "320:-1",
-        ImageResolution::Medium => "640:-1",
-        ImageResolution::High => "1280:-1",
-        ImageResolution::Full => "1920:-1",
-    };
+    duration: f64,
+    background: &str,
+    fonts_dir: &str,
+    transition: Transition,
+    qr_code: Option<&str>,
+    profile: &RenderProfile,
+    on_progress: OnProgress<'_>,
+) -> Result<Output, Box<dyn Error + Send + Sync>> {
+    let mut command = FfmpegCommand::new()
+        .input(Input::looped_image(image_file_path))
+        .input(Input::lavfi(background_source(background, profile)));
 
-    let command_output = Command::new("ffmpeg")
-        .args(&[
-            "-y",
-            "-loop",
-            "1",
-            "-i",
-            image_file_path,
-            "-f",
-            "lavfi",
-            "-i",
-            "color=color=black:size=1280x720",
-            "-filter_complex",
-            &format!(
-                "[0:v]scale={} [scaled]; [1:v][scaled]overlay=(W-w)/2:(H-h)/4,ass={}:fontsdir=./",
-                scale,
-                ass_file_name
-            ),
-            "-t",
-            "5",
-            "-b:v",
-            "5M",
-            "-preset",
-            "slow",
-            "-y",
-            &format!("output{}.mp4", index),
-        ])
-        .output()
-        .await?;
-
-    Ok(command_output)
-}
+    let mut filtergraph = Filtergraph::new()
+        .chain(FilterChain::new().input("0:v").filter(image_scale_filter(resolution, profile)).output("scaled"));
+    let mut chain = FilterChain::new().input("1:v").input("scaled").filter(image_overlay_filter(profile));
+    if let Some(qr_code) = qr_code {
+        command = command.input(Input::looped_image(qr_code));
+        filtergraph.push(chain.output("framed"));
+        chain = FilterChain::new().input("framed").input("2:v").filter(qr_code_overlay_filter(profile));
+    }
+    filtergraph.push(
+        chain
+            .filter(subtitles_filter(ass_file_name, fonts_dir))
+            .filters(transition.filters(duration)),
+    );
+
+    let command = command
+        .filtergraph(filtergraph)
+        .output(encoded_output(output_file, duration, profile));
 
+    run_with_progress(command, on_progress).await
+}
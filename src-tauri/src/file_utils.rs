@@ -1,6 +1,5 @@
 use std::fs;
 use serde_json::{json, Map, Value};
-use tauri::Window;
 use regex::Regex;
 
 pub fn get_all_files_frontmatter() -> Result<String, String> {
@@ -46,7 +45,6 @@ pub fn get_all_files_frontmatter() -> Result<String, String> {
 
 fn extract_frontmatter_and_insert_json(file: &str, filename: &str, path: &std::path::Path) -> Result<serde_json::Value, serde_json::Error> {
     let start_delimiter = "---";
-    let end_delimiter = "---";
     let start_index = file.find(start_delimiter).unwrap() + start_delimiter.len();
     let end_index = file[start_index..].find(start_delimiter).map(|i| start_index + i).unwrap_or_else(|| file.len());
     let frontmatter_str = &file[start_index..end_index].trim();
@@ -78,13 +76,92 @@ fn extract_frontmatter_and_insert_json(file: &str, filename: &str, path: &std::p
 pub fn extract_frontmatter(path: &str) -> Result<String, String> {
     let file = fs::read_to_string(path).map_err(|e| e.to_string())?;
     let start_delimiter = "---";
-    let end_delimiter = "---";
-    let start_index = file.find(start_delimiter).unwrap() + start_delimiter.len();
+    // A note without frontmatter has none to read, rather than being an error
+    let start_index = match file.find(start_delimiter) {
+        Some(i) => i + start_delimiter.len(),
+        None => return Ok(String::new()),
+    };
     let end_index = file[start_index..].find(start_delimiter).map(|i| start_index + i).unwrap_or_else(|| file.len());
     let frontmatter_str = &file[start_index..end_index].trim();
     Ok(frontmatter_str.to_string())
 }
 
+// Look up a single top-level key in a frontmatter string (as returned by extract_frontmatter)
+// and parse its value the same way as the frontmatter JSON sent to the frontend
+pub fn frontmatter_value(frontmatter: &str, key: &str) -> Option<Value> {
+    for line in frontmatter.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            continue;
+        }
+        if let Some((line_key, value)) = line.split_once(':') {
+            if line_key.trim() == key {
+                let value = value.trim().trim_matches('"').trim_matches('\'');
+                return Some(parse_value(value));
+            }
+        }
+    }
+    None
+}
+
+// The indented `key: value` lines of a top-level block such as
+// md2vid:
+//   theme: dark
+// in file order. None when the block is missing
+pub fn frontmatter_section(frontmatter: &str, key: &str) -> Option<Vec<(String, Value)>> {
+    let mut lines = frontmatter.lines();
+    lines.find(|line| {
+        !line.starts_with(' ')
+            && !line.starts_with('\t')
+            && line.split_once(':').map_or(false, |(line_key, value)| line_key.trim() == key && value.trim().is_empty())
+    })?;
+
+    let mut entries = Vec::new();
+    for line in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if !line.starts_with(' ') && !line.starts_with('\t') {
+            break;
+        }
+        let (entry_key, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.trim().trim_matches('"').trim_matches('\'');
+        entries.push((entry_key.trim().to_string(), parse_value(value)));
+    }
+    Some(entries)
+}
+
+// A list value, written inline (`key: [a, b]`) or as an indented block of `- item` lines.
+// A single value counts as a list of one. Empty when the key is missing
+pub fn frontmatter_list(frontmatter: &str, key: &str) -> Vec<String> {
+    let mut lines = frontmatter.lines();
+    let value = match lines.find_map(|line| {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            return None;
+        }
+        line.split_once(':')
+            .filter(|(line_key, _)| line_key.trim() == key)
+            .map(|(_, value)| value.trim().to_string())
+    }) {
+        Some(value) => value,
+        None => return Vec::new(),
+    };
+
+    let unquote = |item: &str| item.trim().trim_matches('"').trim_matches('\'').to_string();
+    if let Some(inline) = value.strip_prefix('[').and_then(|value| value.strip_suffix(']')) {
+        return inline.split(',').map(unquote).filter(|item| !item.is_empty()).collect();
+    }
+    if !value.is_empty() {
+        return vec![unquote(&value)];
+    }
+
+    lines
+        .take_while(|line| line.starts_with(' ') || line.starts_with('\t') || line.starts_with('-') || line.trim().is_empty())
+        .filter_map(|line| line.trim().strip_prefix('-'))
+        .map(unquote)
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
 fn parse_value(value: &str) -> Value {
     // Try to parse as a boolean
     if let Ok(boolean) = value.parse::<bool>() {
@@ -105,8 +182,6 @@ fn parse_value(value: &str) -> Value {
 // only store and print the text AFTER the dataviewjs code (ie. the text after the last set of triple backticks)
 // if there is no dataviewjs code in the file, just print the entire file content as the text content
 pub fn read_file_and_extract_frontmatter(path: &str) -> Result<String, String> {
-    let file = fs::read_to_string(path).map_err(|e| e.to_string())?;
-    let filename = path.split('/').last().unwrap();
     let frontmatter = extract_frontmatter(path).map_err(|e| e.to_string())?;
     let text_content = extract_text_content(path).map_err(|e| e.to_string())?;
     println!("===============================");
@@ -141,7 +216,7 @@ pub fn extract_youtube_url_from_text_content(text_content: &str) -> Result<Strin
 
             // Extract video ID from iframe URL and construct the full YouTube URL
             if let Some(youtube_url) = youtube_url.split('?').next() {
-                if let Some(video_id) = youtube_url.split('/').last() {
+                if let Some(video_id) = youtube_url.split('/').next_back() {
                     let full_youtube_url = format!("https://www.youtube.com/watch?v={}", video_id);
                     println!("Full YouTube URL: {}", full_youtube_url);
                     return Ok(full_youtube_url);
@@ -0,0 +1,394 @@
+use rusttype::{Font, Scale};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::text_processing;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Arabic,
+    Hebrew,
+    Devanagari,
+    Thai,
+    Cjk,
+    Hangul,
+    Emoji,
+    Other,
+}
+
+impl Script {
+    pub fn of(c: char) -> Script {
+        match c as u32 {
+            // Zero width joiner, inside the punctuation block, glues emoji sequences together
+            0x200D => Script::Emoji,
+            0x0000..=0x024F | 0x1E00..=0x1EFF | 0x2000..=0x206F => Script::Latin,
+            0x0370..=0x03FF | 0x1F00..=0x1FFF => Script::Greek,
+            0x0400..=0x052F => Script::Cyrillic,
+            0x0590..=0x05FF | 0xFB1D..=0xFB4F => Script::Hebrew,
+            0x0600..=0x06FF | 0x0750..=0x077F | 0xFB50..=0xFDFF | 0xFE70..=0xFEFF => Script::Arabic,
+            0x0900..=0x097F => Script::Devanagari,
+            0x0E00..=0x0E7F => Script::Thai,
+            0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => Script::Hangul,
+            0x2E80..=0x2FFF | 0x3000..=0x30FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0xFF00..=0xFFEF => {
+                Script::Cjk
+            }
+            0x2600..=0x27BF | 0x1F000..=0x1FAFF | 0xFE0F => Script::Emoji,
+            _ => Script::Other,
+        }
+    }
+
+    // Families tried first when looking for a fallback for this script
+    fn preferred_families(&self) -> &'static [&'static str] {
+        match self {
+            Script::Cjk => &["Noto Sans CJK", "Source Han Sans", "PingFang", "Hiragino", "Microsoft YaHei", "MS Gothic"],
+            Script::Hangul => &["Noto Sans CJK", "Noto Sans KR", "Apple SD Gothic", "Malgun Gothic"],
+            Script::Arabic => &["Noto Sans Arabic", "Noto Naskh Arabic", "Geeza", "Arial"],
+            Script::Hebrew => &["Noto Sans Hebrew", "Arial Hebrew", "Arial"],
+            Script::Devanagari => &["Noto Sans Devanagari", "Kohinoor Devanagari", "Mangal"],
+            Script::Thai => &["Noto Sans Thai", "Thonburi", "Tahoma"],
+            Script::Emoji => &["Noto Color Emoji", "Apple Color Emoji", "Segoe UI Emoji", "Twemoji"],
+            _ => &["Noto Sans", "DejaVu Sans", "Arial", "Helvetica"],
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FontFace {
+    pub family: String,
+    pub path: PathBuf,
+}
+
+// A font chosen for a note, parsed once for everything that measures or draws with it
+#[derive(Clone)]
+pub struct PlannedFont {
+    pub face: FontFace,
+    pub font: Font<'static>,
+    // What an ASS font size is multiplied by for the rusttype scale libass draws it at
+    pub size_factor: f32,
+}
+
+impl PlannedFont {
+    pub fn load(face: &FontFace) -> Option<Self> {
+        let (font, size_factor) = load_font(&face.path)?;
+        Some(PlannedFont { face: face.clone(), font, size_factor })
+    }
+
+    // The rusttype scale of text set at `font_size` in ASS
+    pub fn scale(&self, font_size: f32) -> Scale {
+        Scale::uniform(font_size * self.size_factor)
+    }
+}
+
+// The fonts chosen to render a note: the main font, one fallback per script the main font
+// can't draw, and the characters nothing could draw
+pub struct FontPlan {
+    pub primary: PlannedFont,
+    pub fallbacks: Vec<(Script, PlannedFont)>,
+    pub missing: Vec<char>,
+}
+
+impl FontPlan {
+    // The primary font followed by the fallbacks
+    pub fn fonts(&self) -> impl Iterator<Item = &PlannedFont> {
+        std::iter::once(&self.primary).chain(self.fallbacks.iter().map(|(_, font)| font))
+    }
+}
+
+// Every font found on the system or bundled with the app, scanned once per run
+static DISCOVERED_FONTS: Mutex<Option<Vec<FontFace>>> = Mutex::new(None);
+
+// Fonts parsed so far with their size factors, by path, so fallback searches don't read the same
+// files again. A parsed font shares its data between clones. Files that failed to parse are
+// remembered as None
+type LoadedFont = (Font<'static>, f32);
+static LOADED_FONTS: Mutex<Option<HashMap<PathBuf, Option<LoadedFont>>>> = Mutex::new(None);
+
+fn bundled_fonts_dir() -> PathBuf {
+    PathBuf::from(std::env::var("FONTS_DIR_PATH").unwrap_or_else(|_| "./assets".to_string()))
+}
+
+fn system_fonts_dirs() -> Vec<PathBuf> {
+    let home = std::env::var("HOME").unwrap_or_default();
+    if cfg!(target_os = "windows") {
+        let windir = std::env::var("WINDIR").unwrap_or_else(|_| "C:\\Windows".to_string());
+        let local = std::env::var("LOCALAPPDATA").unwrap_or_default();
+        vec![
+            Path::new(&windir).join("Fonts"),
+            Path::new(&local).join("Microsoft\\Windows\\Fonts"),
+        ]
+    } else if cfg!(target_os = "macos") {
+        vec![
+            PathBuf::from("/System/Library/Fonts"),
+            PathBuf::from("/Library/Fonts"),
+            Path::new(&home).join("Library/Fonts"),
+        ]
+    } else {
+        vec![
+            PathBuf::from("/usr/share/fonts"),
+            PathBuf::from("/usr/local/share/fonts"),
+            Path::new(&home).join(".fonts"),
+            Path::new(&home).join(".local/share/fonts"),
+        ]
+    }
+}
+
+// Bundled fonts come first so they win over system fonts with the same family
+pub fn discover_fonts() -> Vec<FontFace> {
+    let mut cache = DISCOVERED_FONTS.lock().unwrap();
+    if let Some(fonts) = cache.as_ref() {
+        return fonts.clone();
+    }
+
+    let mut fonts = Vec::new();
+    scan_fonts_dir(&bundled_fonts_dir(), &mut fonts);
+    for dir in system_fonts_dirs() {
+        scan_fonts_dir(&dir, &mut fonts);
+    }
+
+    *cache = Some(fonts.clone());
+    fonts
+}
+
+fn scan_fonts_dir(dir: &Path, fonts: &mut Vec<FontFace>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            scan_fonts_dir(&path, fonts);
+            continue;
+        }
+
+        let is_font = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .map_or(false, |ext| ext == "ttf" || ext == "otf" || ext == "ttc");
+        if !is_font {
+            continue;
+        }
+
+        if let Some(family) = fs::read(&path).ok().and_then(|data| family_name(&data)) {
+            fonts.push(FontFace { family, path });
+        }
+    }
+}
+
+pub fn load_font(path: &Path) -> Option<LoadedFont> {
+    let mut cache = LOADED_FONTS.lock().unwrap();
+    let cache = cache.get_or_insert_with(HashMap::new);
+    cache
+        .entry(path.to_path_buf())
+        .or_insert_with(|| {
+            let data = fs::read(path).ok()?;
+            let windows_height = windows_height(&data);
+            let font = Font::try_from_vec_and_index(data, 0)?;
+            let size_factor = size_factor(&font, windows_height);
+            Some((font, size_factor))
+        })
+        .clone()
+}
+
+// libass sizes text like VSFilter: the font's OS/2 winAscent + winDescent span is the ASS font
+// size. rusttype scales the ascent - descent span instead, so sizes are converted by their ratio
+fn size_factor(font: &Font, windows_height: Option<u32>) -> f32 {
+    let v_metrics = font.v_metrics_unscaled();
+    let height = v_metrics.ascent - v_metrics.descent;
+    match windows_height {
+        Some(windows_height) if windows_height > 0 && height > 0.0 => height / windows_height as f32,
+        _ => 1.0,
+    }
+}
+
+fn covers(font: &Font, c: char) -> bool {
+    c.is_whitespace() || c.is_control() || font.glyph(c).id().0 != 0
+}
+
+// Choose the fonts for a piece of text. The requested family is used when it can be found,
+// otherwise the first bundled font takes its place
+pub fn plan_fonts(text: &str, requested_family: &str) -> Option<FontPlan> {
+    let fonts = discover_fonts();
+    let face = fonts
+        .iter()
+        .find(|face| face.family.eq_ignore_ascii_case(requested_family))
+        .or_else(|| fonts.first())?
+        .clone();
+    let primary = PlannedFont::load(&face)?;
+
+    let mut uncovered: Vec<char> = text_processing::strip_ass_tags(text)
+        .chars()
+        .filter(|c| !covers(&primary.font, *c))
+        .collect();
+    uncovered.sort();
+    uncovered.dedup();
+
+    let mut scripts: Vec<Script> = Vec::new();
+    for script in uncovered.iter().map(|c| Script::of(*c)) {
+        if !scripts.contains(&script) {
+            scripts.push(script);
+        }
+    }
+
+    let mut fallbacks = Vec::new();
+    let mut missing = Vec::new();
+    for script in scripts {
+        let chars: Vec<char> = uncovered.iter().copied().filter(|c| Script::of(*c) == script).collect();
+        match find_fallback(&fonts, script, &chars) {
+            Some(fallback) => {
+                missing.extend(chars.iter().filter(|c| !covers(&fallback.font, **c)));
+                fallbacks.push((script, fallback));
+            }
+            None => missing.extend(chars),
+        }
+    }
+
+    Some(FontPlan { primary, fallbacks, missing })
+}
+
+// The font covering the most of `chars`, trying the script's preferred families first
+fn find_fallback(fonts: &[FontFace], script: Script, chars: &[char]) -> Option<PlannedFont> {
+    let preferred = script.preferred_families();
+    let mut candidates: Vec<&FontFace> = fonts.iter().collect();
+    candidates.sort_by_key(|face| {
+        preferred
+            .iter()
+            .position(|family| face.family.starts_with(family))
+            .unwrap_or(preferred.len())
+    });
+
+    let mut best: Option<(PlannedFont, usize)> = None;
+    for face in candidates {
+        let planned = match PlannedFont::load(face) {
+            Some(planned) => planned,
+            None => continue,
+        };
+        let covered = chars.iter().filter(|c| covers(&planned.font, **c)).count();
+        if covered == chars.len() {
+            return Some(planned);
+        }
+        if covered > 0 && best.as_ref().map_or(true, |(_, most)| covered > *most) {
+            best = Some((planned, covered));
+        }
+    }
+    best.map(|(fallback, _)| fallback)
+}
+
+// Switch to the fallback family (\fn) around characters the primary font can't draw
+pub fn apply_fallbacks(text: &str, plan: &FontPlan) -> String {
+    if plan.fallbacks.is_empty() {
+        return text.to_string();
+    }
+
+    let mut result = String::new();
+    let mut current: Option<&str> = None;
+    let mut in_tag = false;
+
+    for c in text.chars() {
+        if c == '{' {
+            in_tag = true;
+        }
+        if !in_tag {
+            let family = if covers(&plan.primary.font, c) {
+                None
+            } else {
+                plan.fallbacks
+                    .iter()
+                    .find(|(script, _)| *script == Script::of(c))
+                    .map(|(_, fallback)| fallback.face.family.as_str())
+            };
+            if family != current {
+                result.push_str(&format!("{{\\fn{}}}", family.unwrap_or("")));
+                current = family;
+            }
+        }
+        if c == '}' {
+            in_tag = false;
+        }
+        result.push(c);
+    }
+    if current.is_some() {
+        result.push_str("{\\fn}");
+    }
+    result
+}
+
+// Copy the planned fonts into `dir` so libass can be pointed at exactly these files
+pub fn prepare_fonts_dir(plan: &FontPlan, dir: &str) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for planned in plan.fonts() {
+        if let Some(file_name) = planned.face.path.file_name() {
+            fs::copy(&planned.face.path, Path::new(dir).join(file_name))?;
+        }
+    }
+    Ok(())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+// Offset of the table tagged `tag`. For collections (.ttc) the first font is used
+fn find_table(data: &[u8], tag: &[u8; 4]) -> Option<usize> {
+    let base = if data.get(0..4) == Some(b"ttcf") { read_u32(data, 12)? as usize } else { 0 };
+    let num_tables = read_u16(data, base + 4)? as usize;
+
+    for i in 0..num_tables {
+        let record = base + 12 + i * 16;
+        if data.get(record..record + 4) == Some(tag) {
+            return read_u32(data, record + 8).map(|table| table as usize);
+        }
+    }
+    None
+}
+
+// Family name (name ID 1) from the font's `name` table, which is what libass matches against
+fn family_name(data: &[u8]) -> Option<String> {
+    parse_name_table(data, find_table(data, b"name")?)
+}
+
+// usWinAscent + usWinDescent from the font's `OS/2` table
+fn windows_height(data: &[u8]) -> Option<u32> {
+    let table = find_table(data, b"OS/2")?;
+    Some(read_u16(data, table + 74)? as u32 + read_u16(data, table + 76)? as u32)
+}
+
+fn parse_name_table(data: &[u8], table: usize) -> Option<String> {
+    let count = read_u16(data, table + 2)? as usize;
+    let strings = table + read_u16(data, table + 4)? as usize;
+    let mut mac_name = None;
+
+    for i in 0..count {
+        let record = table + 6 + i * 12;
+        let platform = read_u16(data, record)?;
+        let name_id = read_u16(data, record + 6)?;
+        if name_id != 1 {
+            continue;
+        }
+
+        let length = read_u16(data, record + 8)? as usize;
+        let offset = strings + read_u16(data, record + 10)? as usize;
+        let bytes = data.get(offset..offset + length)?;
+
+        match platform {
+            // Unicode and Windows names are UTF-16BE
+            0 | 3 => {
+                let units: Vec<u16> = bytes.chunks(2).filter(|c| c.len() == 2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+                return String::from_utf16(&units).ok();
+            }
+            1 => mac_name = Some(bytes.iter().map(|b| *b as char).collect()),
+            _ => {}
+        }
+    }
+    mac_name
+}
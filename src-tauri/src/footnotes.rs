@@ -0,0 +1,75 @@
+use regex::Regex;
+use std::collections::HashMap;
+
+// Remove footnote definitions (`[^1]: text`) from the note body and return them by label.
+// Indented lines directly after a definition are treated as its continuation
+pub fn extract_definitions(text: &str) -> (String, HashMap<String, String>) {
+    let re = Regex::new(r"^\[\^([^\]]+)\]:\s*(.*)$").unwrap();
+    let mut body = Vec::new();
+    let mut definitions = HashMap::new();
+    let mut current: Option<String> = None;
+
+    for line in text.lines() {
+        if let Some(caps) = re.captures(line) {
+            let label = caps[1].to_string();
+            definitions.insert(label.clone(), caps[2].trim().to_string());
+            current = Some(label);
+            continue;
+        }
+
+        if let Some(label) = &current {
+            if line.starts_with("    ") || line.starts_with('\t') {
+                if let Some(definition) = definitions.get_mut(label) {
+                    definition.push(' ');
+                    definition.push_str(line.trim());
+                }
+                continue;
+            }
+        }
+
+        current = None;
+        body.push(line);
+    }
+
+    (body.join("\n").trim_end().to_string(), definitions)
+}
+
+// Inline footnotes (`^[{text}]`, `^[text]`) and references to definitions (`[^1]`)
+const FOOTNOTE_PATTERN: &str = r"\^\[\{(.*?)\}\]|\^\[([^\]]+)\]|\[\^([^\]]+)\]";
+
+// Replace footnote references in a segment with small numbered markers.
+// Handles inline footnotes (`^[text]` and `^[{text}]`) and references to definitions (`[^1]`).
+// `counter` numbers the footnotes across the whole note
+pub fn extract_footnotes(
+    sentence: &str,
+    definitions: &HashMap<String, String>,
+    counter: &mut usize,
+) -> (String, Vec<String>) {
+    let re = Regex::new(FOOTNOTE_PATTERN).unwrap();
+    let mut notes = Vec::new();
+
+    let result = re.replace_all(sentence, |caps: &regex::Captures| {
+        let note = if let Some(inline) = caps.get(1).or_else(|| caps.get(2)) {
+            Some(inline.as_str().trim().to_string())
+        } else {
+            definitions.get(&caps[3]).cloned()
+        };
+
+        match note {
+            Some(note) => {
+                *counter += 1;
+                notes.push(format!("{} {}", counter, note));
+                format!("{{\\fscx60\\fscy60}}{}{{\\fscx100\\fscy100}}", counter)
+            }
+            // A reference without a definition is left out of the video
+            None => String::new(),
+        }
+    });
+
+    (result.into_owned(), notes)
+}
+
+// The segment without its footnotes, for plain text such as chapter titles
+pub fn remove_footnotes(sentence: &str) -> String {
+    Regex::new(FOOTNOTE_PATTERN).unwrap().replace_all(sentence, "").into_owned()
+}
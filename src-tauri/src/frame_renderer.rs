@@ -0,0 +1,1006 @@
+use image::imageops::{self, FilterType};
+use image::{Rgb, RgbImage, RgbaImage};
+use ndarray::Array3;
+use rayon::prelude::*;
+use regex::Regex;
+use rusttype::{point, Font, PositionedGlyph, Scale};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use video_rs::encode::{Encoder, Settings};
+use video_rs::frame::PixelFormat;
+use video_rs::time::Time;
+use video_rs::Options;
+
+use crate::ffmpeg_operations;
+use crate::fonts::FontPlan;
+use crate::image_resolution::ImageResolution;
+use crate::note_settings::Transition;
+use crate::render_profile::{Codec, Quality, RenderProfile};
+use crate::theme;
+use crate::timeline::{self, Clip};
+
+// In-process renderer: rasterizes each clip's ASS events with rusttype, composites them over
+// the background and images, and encodes the frames with video-rs. It draws the subset of ASS
+// the text_processing generators produce for plain text, so its output matches the libass
+// renderers within a few pixels of glyph placement; notes that need more go through libass
+
+// Slant of synthesized italics, as libass does for fonts without an italic face
+const ITALIC_SLANT: f32 = 0.2;
+
+// Override tags that can't be drawn natively, with the feature to report for each
+const UNSUPPORTED_TAGS: [(&str, &str); 8] = [
+    ("\\move", "slide effects"),
+    ("\\t(", "animated effects"),
+    ("\\blur", "blur"),
+    ("\\k", "karaoke"),
+    ("\\p1", "drawings"),
+    ("\\clip", "clipping"),
+    ("\\fr", "rotation"),
+    ("\\fade(", "complex fades"),
+];
+
+// Features used by the clips that the native renderer can't draw, each listed once. Frames are
+// encoded with video-rs' H.264 preset, so other codecs and pixel formats are reported too
+pub fn unsupported_features(clips: &[Clip], profile: &RenderProfile) -> Vec<&'static str> {
+    let override_block = Regex::new(r"\{[^}]*\}").unwrap();
+    let mut features = Vec::new();
+    if profile.codec != Codec::H264 {
+        features.push("codecs other than H.264");
+    }
+    if profile.pixel_format != "yuv420p" {
+        features.push("pixel formats other than yuv420p");
+    }
+    for clip in clips {
+        for event in clip.ass_content.lines().filter_map(|line| line.trim().strip_prefix("Dialogue:")) {
+            for block in override_block.find_iter(event) {
+                for (tag, feature) in UNSUPPORTED_TAGS.iter() {
+                    if block.as_str().contains(tag) && !features.contains(feature) {
+                        features.push(*feature);
+                    }
+                }
+            }
+        }
+    }
+    features
+}
+
+// The planned fonts, loaded once and looked up by family like libass does for \fn
+pub struct FontSet {
+    fonts: Vec<(String, Font<'static>)>,
+}
+
+impl FontSet {
+    pub fn from_plan(plan: &FontPlan) -> Self {
+        let fonts = plan.fonts().map(|planned| (planned.face.family.clone(), planned.font.clone())).collect();
+        FontSet { fonts }
+    }
+
+    // Unknown families fall back to the primary font
+    fn get(&self, family: &str) -> &Font<'static> {
+        self.fonts
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(family))
+            .map(|(_, font)| font)
+            .unwrap_or(&self.fonts[0].1)
+    }
+}
+
+// An ASS colour and its transparency (0 is opaque, 255 invisible)
+#[derive(Clone, Copy)]
+struct Colour {
+    rgb: [u8; 3],
+    alpha: u8,
+}
+
+// Parse &HAABBGGRR, &HBBGGRR& or &HAA&
+fn parse_colour(text: &str) -> Option<Colour> {
+    let hex = text.trim().trim_start_matches("&H").trim_start_matches("&h").trim_end_matches('&');
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(Colour {
+        rgb: [(value & 0xFF) as u8, ((value >> 8) & 0xFF) as u8, ((value >> 16) & 0xFF) as u8],
+        alpha: ((value >> 24) & 0xFF) as u8,
+    })
+}
+
+struct Style {
+    name: String,
+    run: RunStyle,
+    alignment: u8,
+    margins: (f32, f32, f32),
+}
+
+// Fields of a Style line in the Format order written by text_processing. Sizes are multiplied
+// by `scale` to go from canvas units to frame pixels
+fn parse_style(line: &str, scale: f32) -> Option<Style> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    if fields.len() < 22 {
+        return None;
+    }
+    let number = |i: usize| fields[i].parse::<f32>().ok();
+    let primary = parse_colour(fields[3])?;
+    let outline = parse_colour(fields[5])?;
+    let shadow = parse_colour(fields[6])?;
+    Some(Style {
+        name: fields[0].to_string(),
+        run: RunStyle {
+            family: fields[1].to_string(),
+            font_size: number(2)? * scale,
+            scale: (number(11)? / 100.0, number(12)? / 100.0),
+            colour: primary.rgb,
+            outline_colour: outline.rgb,
+            shadow_colour: shadow.rgb,
+            alpha: [primary.alpha, outline.alpha, shadow.alpha],
+            bold: fields[7] != "0",
+            italic: fields[8] != "0",
+            underline: fields[9] != "0",
+            strike: fields[10] != "0",
+            outline: number(16)? * scale,
+            shadow: number(17)? * scale,
+        },
+        alignment: fields[18].parse().ok()?,
+        margins: (number(19)? * scale, number(20)? * scale, number(21)? * scale),
+    })
+}
+
+struct Event {
+    layer: i32,
+    start: f64,
+    end: f64,
+    style: usize,
+    margins: (f32, f32, f32),
+    text: String,
+}
+
+fn parse_event(line: &str, styles: &[Style], scale: f32) -> Option<Event> {
+    let fields: Vec<&str> = line.splitn(10, ',').collect();
+    if fields.len() < 10 {
+        return None;
+    }
+    let style = styles.iter().position(|style| style.name == fields[3].trim()).unwrap_or(0);
+    let margin = |i: usize, default: f32| match fields[i].trim().parse::<f32>() {
+        Ok(value) if value > 0.0 => value * scale,
+        _ => default,
+    };
+    let defaults = styles.get(style)?.margins;
+    Some(Event {
+        layer: fields[0].trim().parse().unwrap_or(0),
+        start: timeline::parse_ass_time(fields[1])?,
+        end: timeline::parse_ass_time(fields[2])?,
+        style,
+        margins: (margin(5, defaults.0), margin(6, defaults.1), margin(7, defaults.2)),
+        text: fields[9].to_string(),
+    })
+}
+
+// Styles and events of a script drawn on a frame `frame_height` pixels high. Like libass, the
+// script's PlayResY canvas is scaled to the frame
+fn parse_script(ass_content: &str, frame_height: u32) -> (Vec<Style>, Vec<Event>, f32) {
+    let play_res_y = ass_content
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("PlayResY:"))
+        .and_then(|value| value.trim().parse::<f32>().ok())
+        .unwrap_or(frame_height as f32);
+    let scale = frame_height as f32 / play_res_y;
+
+    let mut styles = Vec::new();
+    let mut events = Vec::new();
+    for line in ass_content.lines().map(str::trim) {
+        if let Some(style) = line.strip_prefix("Style:").and_then(|line| parse_style(line, scale)) {
+            styles.push(style);
+        } else if let Some(event) = line.strip_prefix("Dialogue:").and_then(|line| parse_event(line, &styles, scale)) {
+            events.push(event);
+        }
+    }
+    (styles, events, scale)
+}
+
+// Everything that can change between runs of text inside one event
+#[derive(Clone)]
+struct RunStyle {
+    family: String,
+    font_size: f32,
+    scale: (f32, f32),
+    colour: [u8; 3],
+    outline_colour: [u8; 3],
+    shadow_colour: [u8; 3],
+    // Transparency of the fill, outline and shadow
+    alpha: [u8; 3],
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strike: bool,
+    outline: f32,
+    shadow: f32,
+}
+
+struct Run {
+    text: String,
+    style: RunStyle,
+}
+
+// Tags that apply to the whole event, wherever they appear
+#[derive(Default)]
+struct EventOverrides {
+    alignment: Option<u8>,
+    position: Option<(f32, f32)>,
+    fade: Option<(f64, f64)>,
+    no_wrap: bool,
+}
+
+fn tag_arguments(tag: &str) -> Vec<f32> {
+    tag.trim_end_matches(')')
+        .split_once('(')
+        .map(|(_, args)| args.split(',').filter_map(|arg| arg.trim().parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+fn flag(value: &str, default: bool) -> bool {
+    match value {
+        "" => default,
+        value => value != "0",
+    }
+}
+
+// Positions and sizes in tags are in canvas units and multiplied by `scale`
+fn apply_tags(block: &str, run: &mut RunStyle, style: &RunStyle, overrides: &mut EventOverrides, scale: f32) {
+    for tag in block.split('\\').map(str::trim).filter(|tag| !tag.is_empty()) {
+        if tag.starts_with("fad(") {
+            if let [fade_in, fade_out] = tag_arguments(tag)[..] {
+                overrides.fade = Some((fade_in as f64 / 1000.0, fade_out as f64 / 1000.0));
+            }
+        } else if tag.starts_with("pos(") {
+            if let [x, y] = tag_arguments(tag)[..] {
+                overrides.position = Some((x * scale, y * scale));
+            }
+        } else if let Some(value) = tag.strip_prefix("an") {
+            overrides.alignment = value.parse().ok();
+        } else if let Some(value) = tag.strip_prefix("alpha") {
+            if let Some(colour) = parse_colour(value) {
+                run.alpha = [colour.rgb[0]; 3];
+            }
+        } else if let Some(value) = tag.strip_prefix("bord") {
+            run.outline = value.parse::<f32>().map(|outline| outline * scale).unwrap_or(style.outline);
+        } else if let Some(value) = tag.strip_prefix("shad") {
+            run.shadow = value.parse::<f32>().map(|shadow| shadow * scale).unwrap_or(style.shadow);
+        } else if let Some(value) = tag.strip_prefix("fscx") {
+            run.scale.0 = value.parse::<f32>().map(|x| x / 100.0).unwrap_or(style.scale.0);
+        } else if let Some(value) = tag.strip_prefix("fscy") {
+            run.scale.1 = value.parse::<f32>().map(|y| y / 100.0).unwrap_or(style.scale.1);
+        } else if tag.starts_with("fsp") || tag.starts_with("blur") || tag.starts_with("be") {
+            // Spacing and blur are not drawn
+        } else if let Some(value) = tag.strip_prefix("fs") {
+            run.font_size = value.parse::<f32>().map(|size| size * scale).unwrap_or(style.font_size);
+        } else if let Some(value) = tag.strip_prefix("fn") {
+            run.family = if value.is_empty() { style.family.clone() } else { value.to_string() };
+        } else if let Some(value) = tag.strip_prefix("1c").or_else(|| tag.strip_prefix('c')) {
+            run.colour = parse_colour(value).map_or(style.colour, |colour| colour.rgb);
+        } else if let Some(value) = tag.strip_prefix("3c") {
+            run.outline_colour = parse_colour(value).map_or(style.outline_colour, |colour| colour.rgb);
+        } else if let Some(value) = tag.strip_prefix("4c") {
+            run.shadow_colour = parse_colour(value).map_or(style.shadow_colour, |colour| colour.rgb);
+        } else if let Some(value) = tag.strip_prefix('q') {
+            overrides.no_wrap = value == "2";
+        } else if let Some(value) = tag.strip_prefix('b') {
+            run.bold = flag(value, style.bold);
+        } else if let Some(value) = tag.strip_prefix('i') {
+            run.italic = flag(value, style.italic);
+        } else if let Some(value) = tag.strip_prefix('u') {
+            run.underline = flag(value, style.underline);
+        } else if let Some(value) = tag.strip_prefix('s') {
+            run.strike = flag(value, style.strike);
+        } else if tag.starts_with('r') {
+            *run = style.clone();
+        }
+    }
+}
+
+// Split event text into lines of styled runs
+fn parse_text(text: &str, style: &RunStyle, scale: f32) -> (Vec<Vec<Run>>, EventOverrides) {
+    let mut overrides = EventOverrides::default();
+    let mut lines: Vec<Vec<Run>> = vec![Vec::new()];
+    let mut run_style = style.clone();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+
+    fn flush(lines: &mut [Vec<Run>], current: &mut String, style: &RunStyle) {
+        if !current.is_empty() {
+            lines.last_mut().unwrap().push(Run { text: std::mem::take(current), style: style.clone() });
+        }
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                let block: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                flush(&mut lines, &mut current, &run_style);
+                apply_tags(&block, &mut run_style, style, &mut overrides, scale);
+            }
+            '\\' => match chars.peek() {
+                Some('N') => {
+                    chars.next();
+                    flush(&mut lines, &mut current, &run_style);
+                    lines.push(Vec::new());
+                }
+                Some('n') => {
+                    chars.next();
+                    current.push(' ');
+                }
+                Some('h') => {
+                    chars.next();
+                    current.push('\u{a0}');
+                }
+                _ => current.push(c),
+            },
+            _ => current.push(c),
+        }
+    }
+    flush(&mut lines, &mut current, &run_style);
+    (lines, overrides)
+}
+
+fn run_scale(style: &RunStyle) -> Scale {
+    Scale { x: style.font_size * style.scale.0, y: style.font_size * style.scale.1 }
+}
+
+fn text_width(font: &Font, scale: Scale, text: &str) -> f32 {
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let glyph = font.glyph(c).scaled(scale);
+        if let Some(previous) = previous {
+            width += font.pair_kerning(scale, previous, glyph.id());
+        }
+        width += glyph.h_metrics().advance_width;
+        previous = Some(glyph.id());
+    }
+    width
+}
+
+fn line_width(line: &[Run], fonts: &FontSet) -> f32 {
+    line.iter()
+        .map(|run| text_width(fonts.get(&run.style.family), run_scale(&run.style), &run.text))
+        .sum()
+}
+
+// Greedy word wrap for lines wider than `max_width`, like libass does for events without \q2
+fn wrap_line(line: Vec<Run>, fonts: &FontSet, max_width: f32) -> Vec<Vec<Run>> {
+    if line_width(&line, fonts) <= max_width {
+        return vec![line];
+    }
+
+    let mut words: Vec<Run> = Vec::new();
+    for run in line {
+        for word in run.text.split_inclusive(' ') {
+            words.push(Run { text: word.to_string(), style: run.style.clone() });
+        }
+    }
+
+    let mut lines: Vec<Vec<Run>> = vec![Vec::new()];
+    let mut width = 0.0;
+    for word in words {
+        let word_width = text_width(fonts.get(&word.style.family), run_scale(&word.style), &word.text);
+        let trimmed_width =
+            text_width(fonts.get(&word.style.family), run_scale(&word.style), word.text.trim_end());
+        if width + trimmed_width > max_width && width > 0.0 {
+            lines.push(Vec::new());
+            width = 0.0;
+        }
+        width += word_width;
+        lines.last_mut().unwrap().push(word);
+    }
+    lines
+}
+
+// Coverage of a glyph (or a dilated copy of it) placed at (x, y) on the canvas
+struct Mask {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    data: Vec<f32>,
+}
+
+impl Mask {
+    fn get(&self, x: i32, y: i32) -> f32 {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            0.0
+        } else {
+            self.data[(y * self.width + x) as usize]
+        }
+    }
+}
+
+// Rasterize a glyph, slanting it for synthesized italics and thickening it for synthesized bold
+fn glyph_mask(glyph: &PositionedGlyph, baseline: f32, style: &RunStyle) -> Option<Mask> {
+    let bounds = glyph.pixel_bounding_box()?;
+    // Rows are shifted in proportion to their height above the baseline
+    let slant = if style.italic { ITALIC_SLANT } else { 0.0 };
+    let min_shift = ((baseline - bounds.max.y as f32) * slant).floor() as i32;
+    let max_shift = ((baseline - bounds.min.y as f32) * slant).ceil() as i32;
+    let embolden = if style.bold { (style.font_size / 40.0).ceil() as i32 } else { 0 };
+    let width = bounds.width() + (max_shift - min_shift) + embolden;
+    let height = bounds.height();
+    let mut data = vec![0.0f32; (width * height) as usize];
+
+    glyph.draw(|x, y, coverage| {
+        let shift = ((baseline - (bounds.min.y + y as i32) as f32) * slant).round() as i32;
+        for extra in 0..=embolden {
+            let px = x as i32 + shift - min_shift + extra;
+            if (0..width).contains(&px) {
+                let index = (y as i32 * width + px) as usize;
+                data[index] = data[index].max(coverage);
+            }
+        }
+    });
+
+    Some(Mask { x: bounds.min.x + min_shift, y: bounds.min.y, width, height, data })
+}
+
+// Grow a mask by `radius` pixels in every direction, for outlines
+fn dilate(mask: &Mask, radius: f32) -> Mask {
+    if radius <= 0.0 {
+        return Mask { x: mask.x, y: mask.y, width: mask.width, height: mask.height, data: mask.data.clone() };
+    }
+    let reach = radius.ceil() as i32;
+    let offsets: Vec<(i32, i32, f32)> = (-reach..=reach)
+        .flat_map(|dy| (-reach..=reach).map(move |dx| (dx, dy)))
+        .filter_map(|(dx, dy)| {
+            let weight = (radius + 0.5 - ((dx * dx + dy * dy) as f32).sqrt()).clamp(0.0, 1.0);
+            if weight > 0.0 { Some((dx, dy, weight)) } else { None }
+        })
+        .collect();
+
+    let width = mask.width + reach * 2;
+    let height = mask.height + reach * 2;
+    let mut data = vec![0.0f32; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let value = offsets
+                .iter()
+                .map(|(dx, dy, weight)| mask.get(x - reach + dx, y - reach + dy) * weight)
+                .fold(0.0, f32::max);
+            data[(y * width + x) as usize] = value;
+        }
+    }
+    Mask { x: mask.x - reach, y: mask.y - reach, width, height, data }
+}
+
+// Source-over blend of `colour` at `opacity` onto a non-premultiplied RGBA pixel
+fn blend_rgba(pixel: &mut image::Rgba<u8>, colour: [u8; 3], opacity: f32) {
+    if opacity <= 0.0 {
+        return;
+    }
+    let below = pixel[3] as f32 / 255.0;
+    let alpha = opacity + below * (1.0 - opacity);
+    for channel in 0..3 {
+        let value = (colour[channel] as f32 * opacity + pixel[channel] as f32 * below * (1.0 - opacity)) / alpha;
+        pixel[channel] = value.round() as u8;
+    }
+    pixel[3] = (alpha * 255.0).round() as u8;
+}
+
+fn opacity(alpha: u8) -> f32 {
+    1.0 - alpha as f32 / 255.0
+}
+
+// One event drawn into a transparent image at (x, y) on the canvas
+struct Layer {
+    x: i64,
+    y: i64,
+    image: RgbaImage,
+    layer: i32,
+    start: f64,
+    end: f64,
+    fade: Option<(f64, f64)>,
+}
+
+impl Layer {
+    // Opacity at `time` seconds into the clip, or None when not on screen
+    fn opacity_at(&self, time: f64) -> Option<f32> {
+        if time < self.start || time >= self.end {
+            return None;
+        }
+        let visible = match self.fade {
+            Some((fade_in, fade_out)) => {
+                let fading_in = if fade_in > 0.0 { (time - self.start) / fade_in } else { 1.0 };
+                let fading_out = if fade_out > 0.0 { (self.end - time) / fade_out } else { 1.0 };
+                fading_in.min(fading_out).clamp(0.0, 1.0)
+            }
+            None => 1.0,
+        };
+        Some(visible as f32)
+    }
+}
+
+struct PlacedGlyph<'a> {
+    glyph: PositionedGlyph<'a>,
+    baseline: f32,
+    style: RunStyle,
+}
+
+// Underline or strike-through from x0 to x1 at y
+struct Decoration {
+    x0: f32,
+    x1: f32,
+    y: f32,
+    thickness: f32,
+    style: RunStyle,
+}
+
+// `scale` is the script's canvas to frame scale, `width` and `height` the frame size
+fn layout_event(event: &Event, styles: &[Style], fonts: &FontSet, scale: f32, width: f32, height: f32) -> Option<Layer> {
+    let style = styles.get(event.style)?;
+    let (lines, overrides) = parse_text(&event.text, &style.run, scale);
+    let (margin_l, margin_r, margin_v) = event.margins;
+
+    let lines: Vec<Vec<Run>> = if overrides.no_wrap || overrides.position.is_some() {
+        lines
+    } else {
+        let max_width = width - margin_l - margin_r;
+        lines.into_iter().flat_map(|line| wrap_line(line, fonts, max_width)).collect()
+    };
+
+    // Line metrics: width, ascent and descent of the tallest run
+    let metrics: Vec<(f32, f32, f32)> = lines
+        .iter()
+        .map(|line| {
+            let (ascent, descent) = line
+                .iter()
+                .map(|run| {
+                    let v_metrics = fonts.get(&run.style.family).v_metrics(run_scale(&run.style));
+                    (v_metrics.ascent, -v_metrics.descent)
+                })
+                .fold((0.0f32, 0.0f32), |(a, d), (ra, rd)| (a.max(ra), d.max(rd)));
+            let (ascent, descent) = if line.is_empty() {
+                let v_metrics = fonts.get(&style.run.family).v_metrics(run_scale(&style.run));
+                (v_metrics.ascent, -v_metrics.descent)
+            } else {
+                (ascent, descent)
+            };
+            (line_width(line, fonts), ascent, descent)
+        })
+        .collect();
+    let block_height: f32 = metrics.iter().map(|(_, ascent, descent)| ascent + descent).sum();
+
+    // Anchor point and alignment, as libass positions a block of lines
+    let alignment = overrides.alignment.unwrap_or(style.alignment).clamp(1, 9);
+    let column = (alignment - 1) % 3;
+    let (anchor_x, anchor_y) = overrides.position.unwrap_or_else(|| {
+        let x = match column {
+            0 => margin_l,
+            1 => (margin_l + width - margin_r) / 2.0,
+            _ => width - margin_r,
+        };
+        let y = match alignment {
+            7..=9 => margin_v,
+            4..=6 => height / 2.0,
+            _ => height - margin_v,
+        };
+        (x, y)
+    });
+    let top = match alignment {
+        7..=9 => anchor_y,
+        4..=6 => anchor_y - block_height / 2.0,
+        _ => anchor_y - block_height,
+    };
+
+    let mut glyphs: Vec<PlacedGlyph> = Vec::new();
+    let mut decorations: Vec<Decoration> = Vec::new();
+    let mut line_top = top;
+    for (line, (line_width, ascent, descent)) in lines.iter().zip(&metrics) {
+        let baseline = line_top + ascent;
+        let mut caret = match column {
+            0 => anchor_x,
+            1 => anchor_x - line_width / 2.0,
+            _ => anchor_x - line_width,
+        };
+        for run in line {
+            let font = fonts.get(&run.style.family);
+            let scale = run_scale(&run.style);
+            let run_start = caret;
+            let mut previous = None;
+            for c in run.text.chars() {
+                let glyph = font.glyph(c).scaled(scale);
+                if let Some(previous) = previous {
+                    caret += font.pair_kerning(scale, previous, glyph.id());
+                }
+                previous = Some(glyph.id());
+                let advance = glyph.h_metrics().advance_width;
+                glyphs.push(PlacedGlyph {
+                    glyph: glyph.positioned(point(caret, baseline)),
+                    baseline,
+                    style: run.style.clone(),
+                });
+                caret += advance;
+            }
+            let thickness = (scale.y / 16.0).max(1.0);
+            if run.style.underline {
+                decorations.push(Decoration { x0: run_start, x1: caret, y: baseline + scale.y * 0.1, thickness, style: run.style.clone() });
+            }
+            if run.style.strike {
+                decorations.push(Decoration { x0: run_start, x1: caret, y: baseline - scale.y * 0.3, thickness, style: run.style.clone() });
+            }
+        }
+        line_top += ascent + descent;
+    }
+
+    // Masks for every glyph and decoration, with their outlines
+    let mut masks: Vec<(Mask, Mask, &RunStyle)> = Vec::new();
+    for placed in &glyphs {
+        if let Some(mask) = glyph_mask(&placed.glyph, placed.baseline, &placed.style) {
+            let outline = dilate(&mask, placed.style.outline);
+            masks.push((mask, outline, &placed.style));
+        }
+    }
+    for decoration in &decorations {
+        let x = decoration.x0.floor() as i32;
+        let y = (decoration.y - decoration.thickness / 2.0).floor() as i32;
+        let width = (decoration.x1 - decoration.x0).ceil().max(1.0) as i32;
+        let height = decoration.thickness.ceil() as i32;
+        let mask = Mask { x, y, width, height, data: vec![1.0; (width * height) as usize] };
+        let outline = dilate(&mask, decoration.style.outline);
+        masks.push((mask, outline, &decoration.style));
+    }
+    if masks.is_empty() {
+        return None;
+    }
+
+    // Bounds of everything drawn, shadows included
+    let shadow = |style: &RunStyle| style.shadow.round() as i32;
+    let min_x = masks.iter().map(|(_, outline, style)| outline.x.min(outline.x + shadow(style))).min()?;
+    let min_y = masks.iter().map(|(_, outline, style)| outline.y.min(outline.y + shadow(style))).min()?;
+    let max_x = masks.iter().map(|(_, outline, style)| outline.x + outline.width + shadow(style).max(0)).max()?;
+    let max_y = masks.iter().map(|(_, outline, style)| outline.y + outline.height + shadow(style).max(0)).max()?;
+    let mut image = RgbaImage::new((max_x - min_x) as u32, (max_y - min_y) as u32);
+
+    let mut paint = |mask: &Mask, offset: i32, colour: [u8; 3], alpha: u8| {
+        for y in 0..mask.height {
+            for x in 0..mask.width {
+                let coverage = mask.data[(y * mask.width + x) as usize];
+                if coverage > 0.0 {
+                    let px = (mask.x + x + offset - min_x) as u32;
+                    let py = (mask.y + y + offset - min_y) as u32;
+                    blend_rgba(image.get_pixel_mut(px, py), colour, coverage * opacity(alpha));
+                }
+            }
+        }
+    };
+
+    // Shadows under outlines under fills, as libass layers them
+    for (mask, outline, style) in &masks {
+        if style.shadow > 0.0 {
+            let body = if style.outline > 0.0 { outline } else { mask };
+            paint(body, shadow(style), style.shadow_colour, style.alpha[2]);
+        }
+    }
+    for (_, outline, style) in &masks {
+        if style.outline > 0.0 {
+            paint(outline, 0, style.outline_colour, style.alpha[1]);
+        }
+    }
+    for (mask, _, style) in &masks {
+        paint(mask, 0, style.colour, style.alpha[0]);
+    }
+
+    Some(Layer {
+        x: min_x as i64,
+        y: min_y as i64,
+        image,
+        layer: event.layer,
+        start: event.start,
+        end: event.end,
+        fade: overrides.fade,
+    })
+}
+
+// A clip ready to be drawn: its background with images, and its text layers in draw order
+struct Scene {
+    base: RgbImage,
+    layers: Vec<Layer>,
+}
+
+fn hex_rgb(hex: &str) -> [u8; 3] {
+    let (r, g, b) = theme::rgb_or_white(hex);
+    [r, g, b]
+}
+
+// Blend an image with transparency onto the frame at (x, y), clipping what falls outside
+fn overlay(frame: &mut RgbImage, image: &RgbaImage, x: i64, y: i64, opacity: f32) {
+    for (ix, iy, pixel) in image.enumerate_pixels() {
+        let fx = x + ix as i64;
+        let fy = y + iy as i64;
+        if fx < 0 || fy < 0 || fx >= frame.width() as i64 || fy >= frame.height() as i64 {
+            continue;
+        }
+        let amount = pixel[3] as f32 / 255.0 * opacity;
+        if amount <= 0.0 {
+            continue;
+        }
+        let target = frame.get_pixel_mut(fx as u32, fy as u32);
+        for channel in 0..3 {
+            target[channel] =
+                (pixel[channel] as f32 * amount + target[channel] as f32 * (1.0 - amount)).round() as u8;
+        }
+    }
+}
+
+fn build_scene(
+    clip: &Clip,
+    fonts: &FontSet,
+    background: [u8; 3],
+    profile: &RenderProfile,
+) -> Result<Scene, Box<dyn Error + Send + Sync>> {
+    let mut base = RgbImage::from_pixel(profile.width, profile.height, Rgb(background));
+
+    // Same placement as the ffmpeg overlay: scaled to the resolution's width, centred
+    // horizontally and a quarter of the way down the safe area
+    if let Some(path) = &clip.image {
+        let image = image::open(path)?.to_rgba8();
+        let width = profile.image_width(ImageResolution::Full);
+        let height = (image.height() as f64 * width as f64 / image.width().max(1) as f64).round() as u32;
+        let image = imageops::resize(&image, width, height.max(1), FilterType::Triangle);
+        let (x, y) = profile.image_position(image.width(), image.height());
+        overlay(&mut base, &image, x, y, 1.0);
+    }
+    if let Some(path) = &clip.qr_code {
+        let image = image::open(path)?.to_rgba8();
+        let (right, top) = ffmpeg_operations::qr_code_margins(profile);
+        let x = profile.width as i64 - image.width() as i64 - right as i64;
+        overlay(&mut base, &image, x, top as i64, 1.0);
+    }
+
+    let (styles, events, scale) = parse_script(&clip.ass_content, profile.height);
+    let (width, height) = (profile.width as f32, profile.height as f32);
+    let mut layers: Vec<Layer> = events
+        .par_iter()
+        .filter_map(|event| layout_event(event, &styles, fonts, scale, width, height))
+        .collect();
+    layers.sort_by_key(|layer| layer.layer);
+
+    Ok(Scene { base, layers })
+}
+
+// How much of the clip shows through its fade transition at `time` seconds in (1 is fully)
+fn transition_visibility(clip: &Clip, time: f64) -> f64 {
+    match clip.transition {
+        Transition::Fade => {
+            let fade = timeline::FADE_DURATION.min(clip.duration / 2.0);
+            (time / fade).min((clip.duration - time) / fade).clamp(0.0, 1.0)
+        }
+        Transition::Cut => 1.0,
+    }
+}
+
+// What a frame shows: the clip, its visible layers with their opacities and how far its
+// transition has faded in
+type FrameKey = (usize, Vec<(usize, u8)>, u8);
+
+// libx264 at the profile's quality, with the same speed preset as the ffmpeg encodes
+fn encoder_settings(profile: &RenderProfile) -> Settings {
+    let mut options = HashMap::new();
+    options.insert("preset".to_string(), "slow".to_string());
+    match &profile.quality {
+        Quality::Crf(crf) => options.insert("crf".to_string(), crf.to_string()),
+        Quality::Bitrate(bitrate) => options.insert("b".to_string(), bitrate.clone()),
+    };
+    Settings::preset_h264_custom(
+        profile.width as usize,
+        profile.height as usize,
+        PixelFormat::YUV420P,
+        Options::from(options),
+    )
+}
+
+// The clip's background with the `visible` layers at their opacities, faded towards the
+// background colour while less than fully `visibility`
+fn compose(scene: &Scene, visible: &[(usize, u8)], visibility: u8, background: [u8; 3]) -> RgbImage {
+    let mut image = scene.base.clone();
+    for (i, layer_opacity) in visible {
+        let layer = &scene.layers[*i];
+        overlay(&mut image, &layer.image, layer.x, layer.y, *layer_opacity as f32 / 255.0);
+    }
+    if visibility < 255 {
+        let amount = visibility as f32 / 255.0;
+        for pixel in image.pixels_mut() {
+            for channel in 0..3 {
+                pixel[channel] =
+                    (pixel[channel] as f32 * amount + background[channel] as f32 * (1.0 - amount)).round() as u8;
+            }
+        }
+    }
+    image
+}
+
+// Render the clips back to back into a silent `output_file`, at the profile's size and frame
+// rate. `progress` is called with the percentage of frames encoded
+pub fn render(
+    clips: &[Clip],
+    fonts: &FontSet,
+    profile: &RenderProfile,
+    background: &str,
+    output_file: &str,
+    progress: impl Fn(f64),
+    should_stop: impl Fn() -> bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    video_rs::init()?;
+    let mut encoder = Encoder::new(Path::new(output_file), encoder_settings(profile))?;
+    let fps = profile.fps as usize;
+    let frame_duration = Time::from_nth_of_a_second(fps);
+    let mut position = Time::zero();
+
+    let background = hex_rgb(background);
+    let total_duration: f64 = clips.iter().map(|clip| clip.duration).sum();
+    let total_frames = (total_duration * fps as f64).round() as usize;
+
+    let mut clip_index = 0;
+    let mut clip_start = 0.0;
+    let mut scene: Option<Scene> = None;
+    // Consecutive frames showing the same layers at the same opacity are encoded from one image
+    let mut previous_key: Option<FrameKey> = None;
+    let mut frame: Option<Array3<u8>> = None;
+    let mut reported = 0;
+
+    for frame_number in 0..total_frames {
+        if should_stop() {
+            return Err("Render cancelled".into());
+        }
+        let time = frame_number as f64 / fps as f64;
+        while clip_index + 1 < clips.len() && time >= clip_start + clips[clip_index].duration {
+            clip_start += clips[clip_index].duration;
+            clip_index += 1;
+            scene = None;
+        }
+        let clip = &clips[clip_index];
+        let local_time = time - clip_start;
+        if scene.is_none() {
+            scene = Some(build_scene(clip, fonts, background, profile)?);
+        }
+        let current = scene.as_ref().unwrap();
+
+        let visible: Vec<(usize, u8)> = current
+            .layers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, layer)| layer.opacity_at(local_time).map(|opacity| (i, (opacity * 255.0).round() as u8)))
+            .filter(|(_, opacity)| *opacity > 0)
+            .collect();
+        let visibility = (transition_visibility(clip, local_time) * 255.0).round() as u8;
+        let key = (clip_index, visible, visibility);
+
+        if previous_key.as_ref() != Some(&key) {
+            let image = compose(current, &key.1, visibility, background);
+            frame = Some(Array3::from_shape_vec((profile.height as usize, profile.width as usize, 3), image.into_raw())?);
+            previous_key = Some(key);
+        }
+
+        if let Some(frame) = &frame {
+            encoder.encode(frame, position)?;
+        }
+        position = position.aligned_with(frame_duration).add();
+
+        let percent = (frame_number + 1) * 100 / total_frames.max(1);
+        if percent > reported {
+            reported = percent;
+            progress(percent as f64);
+        }
+    }
+
+    encoder.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg_command::{FfmpegCommand, Filter, FilterChain, Filtergraph, Input, OutputFile};
+    use crate::text_processing::{self, TextEffect};
+    use crate::fonts;
+    use crate::theme::Theme;
+    use crate::typography;
+
+    // Mean difference per channel, out of 255, that glyph placement and antialiasing may add
+    const MAX_MEAN_DIFFERENCE: f64 = 2.0;
+
+    fn mean_difference(a: &RgbImage, b: &RgbImage) -> f64 {
+        let total: u64 = a
+            .as_raw()
+            .iter()
+            .zip(b.as_raw())
+            .map(|(x, y)| (*x as i32 - *y as i32).unsigned_abs() as u64)
+            .sum();
+        total as f64 / a.as_raw().len() as f64
+    }
+
+    // A wrapped sentence with bold and italic runs, drawn natively one second in
+    struct TestFrame {
+        theme: Theme,
+        plan: FontPlan,
+        profile: RenderProfile,
+        ass_content: String,
+        native: RgbImage,
+    }
+
+    fn native_frame() -> TestFrame {
+        let sentence = "This is **bold** and *italic* text that goes on long enough to wrap onto a second line";
+        let mut theme = Theme::default();
+        let plan = fonts::plan_fonts(sentence, &theme.font).expect("no font to render with");
+        theme.font = plan.primary.face.family.clone();
+        let profile = RenderProfile::preset("720p").unwrap();
+        let canvas = profile.canvas();
+
+        let text = typography::typeset(
+            &text_processing::process_sentence(sentence, &theme),
+            Some(&plan.primary),
+            theme.font_size,
+            theme.margin_l,
+            theme.margin_r,
+            canvas,
+        );
+        let ass_content =
+            text_processing::generate_ass_content_bottom(&text, 2.0, TextEffect::None, &theme, &[], canvas).unwrap();
+        let clip = Clip {
+            ass_content: ass_content.clone(),
+            duration: 2.0,
+            image: None,
+            qr_code: None,
+            transition: Transition::Cut,
+            chapter: None,
+        };
+
+        let background = hex_rgb(&theme.background);
+        let fonts = FontSet::from_plan(&plan);
+        let scene = build_scene(&clip, &fonts, background, &profile).unwrap();
+        let visible: Vec<(usize, u8)> = scene
+            .layers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, layer)| layer.opacity_at(1.0).map(|opacity| (i, (opacity * 255.0).round() as u8)))
+            .collect();
+        let native = compose(&scene, &visible, 255, background);
+        TestFrame { theme, plan, profile, ass_content, native }
+    }
+
+    #[test]
+    fn native_frame_draws_text() {
+        let frame = native_frame();
+        let blank = RgbImage::from_pixel(frame.profile.width, frame.profile.height, Rgb(hex_rgb(&frame.theme.background)));
+        assert!(mean_difference(&frame.native, &blank) > 0.5, "the native renderer drew no text");
+    }
+
+    // Draws the same clip through ffmpeg's libass filter and compares it with the native frame
+    #[test]
+    #[ignore = "needs ffmpeg built with libass, run with --ignored"]
+    fn native_frame_matches_libass() {
+        let TestFrame { theme, plan, profile, ass_content, native } = native_frame();
+        std::process::Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .expect("ffmpeg must be on the PATH to compare against libass");
+
+        let dir = std::env::temp_dir().join(format!("frame_renderer_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ass_file = dir.join("clip.ass");
+        let frame_file = dir.join("frame.png");
+        std::fs::write(&ass_file, &ass_content).unwrap();
+        let fonts_dir = plan.primary.face.path.parent().unwrap();
+        let source = Filter::new("color")
+            .option("color", &theme.background)
+            .option("size", profile.size())
+            .option("rate", profile.fps)
+            .option("duration", 2);
+        let command = FfmpegCommand::new()
+            .input(Input::lavfi(source))
+            .filtergraph(Filtergraph::new().chain(FilterChain::new().filter(
+                ffmpeg_operations::subtitles_filter(ass_file.to_str().unwrap(), fonts_dir.to_str().unwrap()),
+            )))
+            .output(OutputFile::new(frame_file.to_str().unwrap()).option("-ss", 1).option("-frames:v", 1));
+        let output = std::process::Command::new("ffmpeg").args(command.args()).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let libass = image::open(&frame_file).unwrap().to_rgb8();
+        std::fs::remove_dir_all(&dir).ok();
+
+        let difference = mean_difference(&native, &libass);
+        assert!(difference < MAX_MEAN_DIFFERENCE, "frames differ by {:.2} per channel", difference);
+    }
+}
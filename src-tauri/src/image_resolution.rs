@@ -1,6 +1,13 @@
+#[derive(Clone, Copy, PartialEq)]
 pub enum ImageResolution {
-    Low,
-    Medium,
-    High,
     Full,
-}
\ No newline at end of file
+}
+
+impl ImageResolution {
+    // Width the image is scaled to, in pixels
+    pub fn width(&self) -> u32 {
+        match self {
+            ImageResolution::Full => 1920,
+        }
+    }
+}
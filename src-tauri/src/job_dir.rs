@@ -0,0 +1,56 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::log_utils;
+
+// Jobs started by this process, so two jobs started in the same millisecond still differ
+static JOB_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+fn jobs_dir() -> PathBuf {
+    PathBuf::from(std::env::var("JOBS_DIR_PATH").unwrap_or_else(|_| "./temp_files/jobs".to_string()))
+}
+
+// The working directory of one render. Every intermediate file of the job is written here and
+// the directory is removed when the guard is dropped, which covers success, errors, panics and
+// the render future being cancelled. With `keep` the files are left for debugging
+pub struct JobDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl JobDir {
+    pub fn create(keep: bool) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let number = JOB_COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = jobs_dir().join(format!("job-{}-{}-{}", std::process::id(), millis, number));
+        fs::create_dir_all(&path)?;
+        Ok(JobDir { path, keep })
+    }
+
+    // Path of a file inside the job directory
+    pub fn file(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().to_string()
+    }
+}
+
+impl Drop for JobDir {
+    fn drop(&mut self) {
+        if self.keep {
+            log_utils::print_pretty_log(&format!("Keeping intermediates in {}", self.path.display()), "blue");
+        } else if let Err(e) = fs::remove_dir_all(&self.path) {
+            log_utils::print_pretty_log(&format!("Could not remove {}: {}", self.path.display(), e), "red");
+        }
+    }
+}
+
+// Move a finished file out of the job directory, copying when it's on another file system
+pub fn move_file(from: &str, to: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,364 @@
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::async_runtime::{self, JoinHandle};
+use tauri::Window;
+use tokio::sync::watch;
+
+use crate::log_utils;
+use crate::progress::ProgressReport;
+use crate::video_gen;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Paused,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+// What the frontend sees of a job, sent as the `job` event whenever it changes
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatus {
+    pub id: u64,
+    pub path: String,
+    pub state: JobState,
+    pub stage: String,
+    pub progress: f64,
+    pub error: Option<String>,
+    // Whether the render is at a point where a pause takes effect. A single ffmpeg pass can't
+    // be paused, so the job refuses to pause while one runs
+    pub pausable: bool,
+    // Everything the render warned about, such as unknown directives or missing fonts
+    pub warnings: Vec<String>,
+}
+
+// Payload of every job-scoped event, so listeners can tell concurrent jobs apart
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JobEvent<T: Serialize + Clone> {
+    job_id: u64,
+    payload: T,
+}
+
+struct Job {
+    status: JobStatus,
+    window: Window,
+    task: Option<JoinHandle<()>>,
+    pause: watch::Sender<bool>,
+    // Replaced for every run, so work left over from a cancelled run never sees a re-run
+    cancel: watch::Sender<bool>,
+}
+
+// Dropping a JoinHandle leaves its task running, so aborting a job's task aborts its render
+// through this
+struct RenderTask(JoinHandle<Result<(), String>>);
+
+impl Drop for RenderTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Renders run one at a time in the order they were enqueued
+#[derive(Clone, Default)]
+pub struct JobManager {
+    jobs: Arc<Mutex<Vec<Job>>>,
+}
+
+// What a running render gets from the queue: its id for job-scoped events, the window to send
+// them to, the pause switch it checks between segments and the cancel switch for work that
+// can't be aborted with its future
+#[derive(Clone)]
+pub struct JobHandle {
+    pub id: u64,
+    window: Window,
+    manager: JobManager,
+    paused: watch::Receiver<bool>,
+    cancelled: watch::Receiver<bool>,
+}
+
+impl JobHandle {
+    pub fn emit<T: Serialize + Clone>(&self, event: &str, payload: T) -> Result<(), tauri::Error> {
+        self.window.emit(event, JobEvent { job_id: self.id, payload })
+    }
+
+    pub fn emit_progress(&self, report: ProgressReport) -> Result<(), tauri::Error> {
+        self.manager.update(self.id, |status| status.progress = report.percent);
+        self.emit("progress", report)
+    }
+
+    pub fn emit_stage(&self, stage: &str) -> Result<(), tauri::Error> {
+        self.manager.update(self.id, |status| status.stage = stage.to_string());
+        self.emit("stage", stage)
+    }
+
+    // Kept on the job's status too, so warnings sent before the frontend listened aren't lost
+    pub fn emit_warning(&self, message: &str) -> Result<(), tauri::Error> {
+        self.manager.update(self.id, |status| {
+            if !status.warnings.iter().any(|warning| warning == message) {
+                status.warnings.push(message.to_string());
+            }
+        });
+        self.manager.announce(self.id);
+        self.emit("warning", message)
+    }
+
+    // Returns straight away unless the job is paused, then waits for it to be resumed
+    pub async fn wait_while_paused(&self) {
+        let mut paused = self.paused.clone();
+        while *paused.borrow_and_update() {
+            if paused.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Mark whether a pause takes effect from here on. Turning it off first waits out a pause
+    // already asked for, so the job is never reported paused while work it can't stop runs
+    pub async fn set_pausable(&self, pausable: bool) {
+        loop {
+            self.wait_while_paused().await;
+            if self.manager.set_pausable(self.id, pausable) {
+                return;
+            }
+        }
+    }
+
+    // For blocking work such as the native renderer, which keeps running when the job's future
+    // is aborted: waits while the job is paused, then says whether it has been cancelled
+    pub fn should_stop(&self) -> bool {
+        while *self.paused.borrow() && !*self.cancelled.borrow() {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        *self.cancelled.borrow()
+    }
+}
+
+impl JobManager {
+    pub fn enqueue(&self, path: &str, window: Window) -> u64 {
+        let id = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let id = jobs.iter().map(|job| job.status.id).max().unwrap_or(0) + 1;
+            let (pause, _) = watch::channel(false);
+            let (cancel, _) = watch::channel(false);
+            jobs.push(Job {
+                status: JobStatus {
+                    id,
+                    path: path.to_string(),
+                    state: JobState::Queued,
+                    stage: String::new(),
+                    progress: 0.0,
+                    error: None,
+                    pausable: false,
+                    warnings: Vec::new(),
+                },
+                window,
+                task: None,
+                pause,
+                cancel,
+            });
+            id
+        };
+        self.announce(id);
+        self.start_next();
+        id
+    }
+
+    pub fn list(&self) -> Vec<JobStatus> {
+        self.jobs.lock().unwrap().iter().map(|job| job.status.clone()).collect()
+    }
+
+    pub fn status(&self, id: u64) -> Option<JobStatus> {
+        self.jobs.lock().unwrap().iter().find(|job| job.status.id == id).map(|job| job.status.clone())
+    }
+
+    // Aborting the render drops its future, which kills its ffmpeg and yt-dlp processes and
+    // removes its working directory. Blocking work stops at its next check of the cancel switch
+    pub fn cancel(&self, id: u64) -> Result<(), String> {
+        self.change(id, |job| match job.status.state {
+            JobState::Queued | JobState::Running | JobState::Paused => {
+                job.cancel.send_replace(true);
+                if let Some(task) = job.task.take() {
+                    task.abort();
+                }
+                job.status.state = JobState::Cancelled;
+                Ok(())
+            }
+            _ => Err(format!("Job {} has already finished", id)),
+        })?;
+        self.start_next();
+        Ok(())
+    }
+
+    // The job stops before its next segment or frame
+    pub fn pause(&self, id: u64) -> Result<(), String> {
+        self.change(id, |job| match job.status.state {
+            JobState::Running if job.status.pausable => {
+                job.pause.send_replace(true);
+                job.status.state = JobState::Paused;
+                Ok(())
+            }
+            JobState::Running => Err(format!("Job {} is in a step that can't be paused", id)),
+            _ => Err(format!("Job {} is not running", id)),
+        })
+    }
+
+    pub fn resume(&self, id: u64) -> Result<(), String> {
+        self.change(id, |job| match job.status.state {
+            JobState::Paused => {
+                job.pause.send_replace(false);
+                job.status.state = JobState::Running;
+                Ok(())
+            }
+            _ => Err(format!("Job {} is not paused", id)),
+        })
+    }
+
+    // Put a failed or cancelled job back in the queue
+    pub fn rerun(&self, id: u64) -> Result<(), String> {
+        self.change(id, |job| match job.status.state {
+            JobState::Failed | JobState::Cancelled => {
+                job.pause.send_replace(false);
+                job.status.state = JobState::Queued;
+                job.status.stage = String::new();
+                job.status.progress = 0.0;
+                job.status.error = None;
+                job.status.pausable = false;
+                job.status.warnings.clear();
+                Ok(())
+            }
+            _ => Err(format!("Only failed or cancelled jobs can be re-run, job {} is not", id)),
+        })?;
+        self.start_next();
+        Ok(())
+    }
+
+    // Apply `change` to a job and tell the frontend about its new status
+    fn change(&self, id: u64, change: impl FnOnce(&mut Job) -> Result<(), String>) -> Result<(), String> {
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            let job = jobs
+                .iter_mut()
+                .find(|job| job.status.id == id)
+                .ok_or_else(|| format!("No job with id {}", id))?;
+            change(job)?;
+        }
+        self.announce(id);
+        Ok(())
+    }
+
+    // Fails while the job is paused when turning pausing off, so the caller can wait for it to
+    // be resumed first
+    fn set_pausable(&self, id: u64, pausable: bool) -> bool {
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            let job = match jobs.iter_mut().find(|job| job.status.id == id) {
+                Some(job) => job,
+                None => return true,
+            };
+            if !pausable && job.status.state == JobState::Paused {
+                return false;
+            }
+            if job.status.pausable == pausable {
+                return true;
+            }
+            job.status.pausable = pausable;
+        }
+        self.announce(id);
+        true
+    }
+
+    fn update(&self, id: u64, update: impl FnOnce(&mut JobStatus)) {
+        if let Some(job) = self.jobs.lock().unwrap().iter_mut().find(|job| job.status.id == id) {
+            update(&mut job.status);
+        }
+    }
+
+    fn announce(&self, id: u64) {
+        let jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.iter().find(|job| job.status.id == id) {
+            if let Err(e) = job.window.emit("job", job.status.clone()) {
+                log_utils::print_pretty_log(&format!("Could not send job status: {}", e), "red");
+            }
+        }
+    }
+
+    // Start the oldest queued job unless one is already running or paused
+    fn start_next(&self) {
+        let started = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let busy = jobs
+                .iter()
+                .any(|job| matches!(job.status.state, JobState::Running | JobState::Paused));
+            let next = jobs.iter_mut().find(|job| job.status.state == JobState::Queued);
+            match next {
+                Some(job) if !busy => {
+                    job.status.state = JobState::Running;
+                    job.status.pausable = false;
+                    let (cancel, cancelled) = watch::channel(false);
+                    job.cancel = cancel;
+                    let handle = JobHandle {
+                        id: job.status.id,
+                        window: job.window.clone(),
+                        manager: self.clone(),
+                        paused: job.pause.subscribe(),
+                        cancelled,
+                    };
+                    let path = job.status.path.clone();
+                    let manager = self.clone();
+                    job.task = Some(async_runtime::spawn(async move {
+                        let id = handle.id;
+                        // Rendered in a task of its own, so a panic fails the job instead of
+                        // leaving it running and the queue stuck behind it
+                        let mut render =
+                            RenderTask(async_runtime::spawn(async move { video_gen::render_note(handle, &path).await }));
+                        let result = match (&mut render.0).await {
+                            Ok(result) => result,
+                            Err(e) => Err(format!("The render stopped unexpectedly: {}", e)),
+                        };
+                        manager.finish(id, result);
+                    }));
+                    Some(job.status.id)
+                }
+                _ => None,
+            }
+        };
+        if let Some(id) = started {
+            self.announce(id);
+        }
+    }
+
+    fn finish(&self, id: u64, result: Result<(), String>) {
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            let job = match jobs.iter_mut().find(|job| job.status.id == id) {
+                Some(job) => job,
+                None => return,
+            };
+            // A job cancelled while it was finishing stays cancelled
+            if !matches!(job.status.state, JobState::Running | JobState::Paused) {
+                return;
+            }
+            job.task = None;
+            job.status.pausable = false;
+            match result {
+                Ok(()) => {
+                    job.status.state = JobState::Done;
+                    job.status.progress = 100.0;
+                }
+                Err(e) => {
+                    log_utils::print_pretty_log(&format!("Job {} failed: {}", id, e), "red");
+                    job.status.state = JobState::Failed;
+                    job.status.error = Some(e);
+                }
+            }
+        }
+        self.announce(id);
+        self.start_next();
+    }
+}
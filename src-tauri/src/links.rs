@@ -0,0 +1,168 @@
+use image::{GrayImage, Luma};
+use qrcode::{Color, QrCode};
+use regex::Regex;
+use std::error::Error;
+
+// A hosted link in the note: `[text](url)`, or a bare URL whose text is its domain
+#[derive(Clone)]
+pub struct Link {
+    pub text: String,
+    pub url: String,
+}
+
+fn markdown_link_regex() -> Regex {
+    // Not preceded by `!`, which would make it an image
+    Regex::new(r"(^|[^!])\[([^\]]+)\]\((https?://[^)\s]+)\)").unwrap()
+}
+
+fn bare_url_regex() -> Regex {
+    Regex::new(r"(^|\s)(https?://[^\s)>\]]+)").unwrap()
+}
+
+// Every hosted link in the text in the order they appear, each URL once
+pub fn extract_links(text: &str) -> Vec<Link> {
+    let mut links: Vec<(usize, Link)> = Vec::new();
+
+    for caps in markdown_link_regex().captures_iter(text) {
+        let position = caps.get(2).unwrap().start();
+        links.push((position, Link { text: caps[2].trim().to_string(), url: caps[3].to_string() }));
+    }
+    for caps in bare_url_regex().captures_iter(text) {
+        let url = caps[2].trim_end_matches(['.', ',', ';']).to_string();
+        links.push((caps.get(2).unwrap().start(), Link { text: display_url(&url), url }));
+    }
+
+    links.sort_by_key(|(position, _)| *position);
+    let mut unique: Vec<Link> = Vec::new();
+    for (_, link) in links {
+        if !unique.iter().any(|existing| existing.url == link.url) {
+            unique.push(link);
+        }
+    }
+    unique
+}
+
+// A URL shortened for the screen: no scheme or `www.`, no trailing slash, at most 48 characters
+pub fn display_url(url: &str) -> String {
+    let short = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("www.")
+        .trim_end_matches('/');
+    if short.chars().count() > 48 {
+        format!("{}…", short.chars().take(47).collect::<String>())
+    } else {
+        short.to_string()
+    }
+}
+
+// Replace markdown links and bare URLs with their text, styled by `style` (given the link text)
+pub fn style_links(text: &str, style: impl Fn(&str) -> String) -> String {
+    let result = markdown_link_regex().replace_all(text, |caps: &regex::Captures| {
+        format!("{}{}", &caps[1], style(caps[2].trim()))
+    });
+    bare_url_regex()
+        .replace_all(&result, |caps: &regex::Captures| {
+            let url = &caps[2];
+            let trimmed = url.trim_end_matches(['.', ',', ';']);
+            format!("{}{}{}", &caps[1], style(&display_url(trimmed)), &url[trimmed.len()..])
+        })
+        .into_owned()
+}
+
+// Pixels per QR module and the blank modules around the code that scanners need
+const QR_MODULE_SIZE: u32 = 4;
+const QR_QUIET_ZONE: u32 = 4;
+
+// Write a QR code for `url` as a black on white PNG
+pub fn write_qr_code(url: &str, path: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let code = QrCode::new(url.as_bytes())?;
+    let width = code.width() as u32;
+    let colors = code.to_colors();
+    let size = (width + QR_QUIET_ZONE * 2) * QR_MODULE_SIZE;
+
+    let image = GrayImage::from_fn(size, size, |x, y| {
+        let module_x = (x / QR_MODULE_SIZE) as i64 - QR_QUIET_ZONE as i64;
+        let module_y = (y / QR_MODULE_SIZE) as i64 - QR_QUIET_ZONE as i64;
+        let inside = (0..width as i64).contains(&module_x) && (0..width as i64).contains(&module_y);
+        if inside && colors[(module_y * width as i64 + module_x) as usize] == Color::Dark {
+            Luma([0])
+        } else {
+            Luma([255])
+        }
+    });
+    image.save(path)?;
+    Ok(())
+}
+
+// ASS text for the end card listing every link of the note
+pub fn sources_card_text(links: &[Link], link_colour: &str, text_colour: &str) -> String {
+    let mut lines = vec!["{\\b1}Sources{\\b0}".to_string()];
+    for link in links {
+        let url = display_url(&link.url);
+        if link.text == url {
+            lines.push(format!("{{\\c{}}}{}{{\\c{}}}", link_colour, url, text_colour));
+        } else {
+            lines.push(format!("{} – {{\\c{}}}{}{{\\c{}}}", link.text, link_colour, url, text_colour));
+        }
+    }
+    lines.join("\\N")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls(links: &[Link]) -> Vec<&str> {
+        links.iter().map(|link| link.url.as_str()).collect()
+    }
+
+    #[test]
+    fn extracts_links_in_order() {
+        let links = extract_links(
+            "See https://example.com/later. Read [the docs](https://docs.rs/regex) first, ![chart](https://example.com/chart.png)",
+        );
+        assert_eq!(urls(&links), ["https://example.com/later", "https://docs.rs/regex"]);
+        assert_eq!(links[0].text, "example.com/later");
+        assert_eq!(links[1].text, "the docs");
+    }
+
+    #[test]
+    fn keeps_each_url_once() {
+        let links = extract_links("[First](https://example.com) then https://example.com and [again](https://example.com)");
+        assert_eq!(urls(&links), ["https://example.com"]);
+        assert_eq!(links[0].text, "First");
+    }
+
+    #[test]
+    fn shortens_urls_for_the_screen() {
+        assert_eq!(display_url("https://www.example.com/"), "example.com");
+        assert_eq!(display_url("http://example.com/a"), "example.com/a");
+        let long = display_url(&format!("https://example.com/{}", "a".repeat(60)));
+        assert_eq!(long.chars().count(), 48);
+        assert!(long.ends_with('…'));
+    }
+
+    #[test]
+    fn styles_links_in_place() {
+        let styled = style_links("Read [the docs](https://docs.rs) or https://www.example.com/, then ![x](https://example.com/x.png)", |text| {
+            format!("<{}>", text)
+        });
+        assert_eq!(styled, "Read <the docs> or <example.com>, then ![x](https://example.com/x.png)");
+    }
+
+    #[test]
+    fn sources_card_lists_every_link() {
+        let links = extract_links("[The docs](https://docs.rs/regex) and https://example.com");
+        let card = sources_card_text(&links, "&H00FF00&", "&HFFFFFF&");
+        let lines: Vec<&str> = card.split("\\N").collect();
+        assert_eq!(
+            lines,
+            [
+                "{\\b1}Sources{\\b0}",
+                "The docs – {\\c&H00FF00&}docs.rs/regex{\\c&HFFFFFF&}",
+                "{\\c&H00FF00&}example.com{\\c&HFFFFFF&}",
+            ]
+        );
+    }
+}
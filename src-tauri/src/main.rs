@@ -1,6 +1,5 @@
 use dotenv::dotenv;
-use std::error::Error; // Import the Error trait
-use tauri::Window;
+use tauri::{State, Window};
 use std::process::Command;
 
 
@@ -11,7 +10,26 @@ mod video_gen;
 mod yt_downloader;
 mod text_processing;
 mod ffmpeg_operations;
+mod ffmpeg_command;
 mod image_resolution;
+mod timing;
+mod theme;
+mod footnotes;
+mod segmentation;
+mod typography;
+mod fonts;
+mod directives;
+mod note_settings;
+mod templates;
+mod redaction;
+mod links;
+mod timeline;
+mod frame_renderer;
+mod job_dir;
+mod jobs;
+mod progress;
+mod render_profile;
+mod platform;
 
 #[tokio::main]
 async fn main() {
@@ -31,7 +49,8 @@ async fn main() {
     // }
 
     tauri::Builder::default()
-    .invoke_handler(tauri::generate_handler![greet, get_all_files_frontmatter, create_video_with_ffmpeg, read_file_and_extract_frontmatter, open_in_obsidian])
+    .manage(jobs::JobManager::default())
+    .invoke_handler(tauri::generate_handler![greet, get_all_files_frontmatter, create_video_with_ffmpeg, list_jobs, cancel_job, pause_job, resume_job, rerun_job, read_file_and_extract_frontmatter, open_in_obsidian, list_themes, load_theme, save_theme])
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
 }
@@ -64,24 +83,38 @@ fn get_all_files_frontmatter() -> Result<String, String> {
 
 // take path and window
 
+// Queue a render of the note and return the new job's status, as a job that starts straight
+// away sends its first `job` event before the frontend knows its id. Progress arrives as
+// job-scoped events
 #[tauri::command]
-async fn create_video_with_ffmpeg(path: &str, window: Window) -> Result<(), String> {
-    // Extract frontmatter and text content from the file
-    let frontmatter = file_utils::extract_frontmatter(&path).map_err(|e| e.to_string())?;
-    let text_content = file_utils::extract_text_content(&path).map_err(|e| e.to_string())?;
-    
-    // Attempt to extract YouTube URL from the frontmatter
-    let youtube_url_from_frontmatter = file_utils::extract_youtube_url_from_text_content(&frontmatter).ok();
-    
-    // If no URL is found in the frontmatter, attempt to extract it from the text content
-    let youtube_url = youtube_url_from_frontmatter
-        .or_else(|| file_utils::extract_youtube_url_from_text_content(&text_content).ok())
-        .unwrap_or_else(|| "https://www.youtube.com/watch?v=H0j_xIm4fW0".to_string());
-    
-    // Create video with the extracted YouTube URL
-    video_gen::create_video_with_ffmpeg(window, &frontmatter, &text_content, &youtube_url, true, true)
-        .await
-        .map_err(|e| e.to_string())
+fn create_video_with_ffmpeg(path: &str, window: Window, jobs: State<'_, jobs::JobManager>) -> Result<jobs::JobStatus, String> {
+    let id = jobs.enqueue(path, window);
+    jobs.status(id).ok_or_else(|| format!("No job with id {}", id))
+}
+
+#[tauri::command]
+fn list_jobs(jobs: State<'_, jobs::JobManager>) -> Vec<jobs::JobStatus> {
+    jobs.list()
+}
+
+#[tauri::command]
+fn cancel_job(id: u64, jobs: State<'_, jobs::JobManager>) -> Result<(), String> {
+    jobs.cancel(id)
+}
+
+#[tauri::command]
+fn pause_job(id: u64, jobs: State<'_, jobs::JobManager>) -> Result<(), String> {
+    jobs.pause(id)
+}
+
+#[tauri::command]
+fn resume_job(id: u64, jobs: State<'_, jobs::JobManager>) -> Result<(), String> {
+    jobs.resume(id)
+}
+
+#[tauri::command]
+fn rerun_job(id: u64, jobs: State<'_, jobs::JobManager>) -> Result<(), String> {
+    jobs.rerun(id)
 }
 
 //read_file_and_extract_frontmatter
@@ -90,6 +123,21 @@ async fn read_file_and_extract_frontmatter(path: &str) -> Result<String, String>
     file_utils::read_file_and_extract_frontmatter(path).map_err(|e| e.to_string())
 }
 
+#[tauri::command]
+fn list_themes() -> Result<Vec<String>, String> {
+    theme::list_themes()
+}
+
+#[tauri::command]
+fn load_theme(name: &str) -> Result<theme::Theme, String> {
+    theme::load_theme(name)
+}
+
+#[tauri::command]
+fn save_theme(theme: theme::Theme) -> Result<(), String> {
+    theme::save_theme(&theme)
+}
+
 #[tauri::command]
 fn open_in_obsidian(vault: &str, filename: &str) -> Result<(), String> {
     open_in_obsidian_impl(vault, filename).map_err(|e| e.to_string())
@@ -104,7 +152,7 @@ fn open_in_obsidian_impl(vault: &str, filename: &str) -> std::io::Result<()> {
 
     if cfg!(target_os = "windows") {
         Command::new("cmd")
-            .args(&["/C", &format!("start {}", obsidian_uri)])
+            .args(["/C", &format!("start {}", obsidian_uri)])
             .output()?;
     } else if cfg!(target_os = "macos") {
         Command::new("open")
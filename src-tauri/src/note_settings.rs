@@ -0,0 +1,562 @@
+use serde_json::Value;
+use std::path::Path;
+
+use crate::ffmpeg_command::Filter;
+use crate::file_utils;
+use crate::platform::{self, Platform};
+use crate::redaction::RedactionMode;
+use crate::render_profile::{self, Codec, Quality, RenderProfile};
+use crate::theme;
+
+// How the note body is cut into segments
+#[derive(Clone, Copy, PartialEq)]
+pub enum Segmentation {
+    Sentence,
+    Paragraph,
+    Line,
+}
+
+// What happens between two segments
+#[derive(Clone, Copy, PartialEq)]
+pub enum Transition {
+    Cut,
+    Fade,
+}
+
+// Length of a fade in or out, in seconds
+const FADE_DURATION: f64 = 0.3;
+
+impl Transition {
+    // Extra video filters for a segment of the given duration, none for a cut
+    pub fn filters(&self, duration: f64) -> Vec<Filter> {
+        match self {
+            Transition::Cut => Vec::new(),
+            Transition::Fade => {
+                let fade = FADE_DURATION.min(duration / 2.0);
+                vec![
+                    Filter::new("fade")
+                        .option("t", "in")
+                        .option("st", 0)
+                        .option("d", format!("{:.2}", fade)),
+                    Filter::new("fade")
+                        .option("t", "out")
+                        .option("st", format!("{:.2}", duration - fade))
+                        .option("d", format!("{:.2}", fade)),
+                ]
+            }
+        }
+    }
+}
+
+// How the clips are turned into a video
+#[derive(Clone, Copy, PartialEq)]
+pub enum Renderer {
+    // One ASS file and one filtergraph, rendered by a single ffmpeg run
+    Timeline,
+    // One ffmpeg run per clip, stream-copied together afterwards
+    Segments,
+    // Frames drawn in process and encoded with video-rs, for notes without libass-only effects
+    Native,
+}
+
+// Where the soundtrack comes from
+#[derive(Clone, PartialEq)]
+pub enum AudioSource {
+    YouTube(String),
+    File(String),
+    Silent,
+}
+
+// Render settings from the note's `md2vid:` frontmatter block. Anything left out
+// falls back to the app defaults (and the older top-level keys)
+pub struct NoteSettings {
+    pub theme: Option<String>,
+    // Size, frame rate and encoding: a `profile` preset with `resolution`, `aspect`, `fps`,
+    // `codec`, `crf`, `bitrate` and `pixel_format` applied on top
+    pub profile: RenderProfile,
+    // Short-form platform the video is made for. Its preset replaces `profile` and adds a
+    // safe zone, a duration limit and a loudness target
+    pub platform: Option<Platform>,
+    pub segmentation: Segmentation,
+    pub duration: Option<f64>,
+    pub audio: Option<AudioSource>,
+    pub transition: Transition,
+    // File name of the final video, without extension
+    pub output: Option<String>,
+    // Largest size of the final video in bytes, met with a two-pass encode
+    pub target_size: Option<u64>,
+    pub redaction: Option<RedactionMode>,
+    // Show a QR code for the first link of a segment
+    pub qr_codes: bool,
+    // End the video with a card listing every link
+    pub sources: bool,
+    pub renderer: Renderer,
+    // Segments rendered at the same time by the segments renderer, one per CPU when unset
+    pub workers: Option<usize>,
+}
+
+impl Default for NoteSettings {
+    fn default() -> Self {
+        NoteSettings {
+            theme: None,
+            profile: RenderProfile::default(),
+            platform: None,
+            segmentation: Segmentation::Sentence,
+            duration: None,
+            audio: None,
+            transition: Transition::Cut,
+            output: None,
+            target_size: None,
+            redaction: None,
+            qr_codes: false,
+            sources: true,
+            renderer: Renderer::Timeline,
+            workers: None,
+        }
+    }
+}
+
+const KEYS: [&str; 21] = [
+    "theme",
+    "profile",
+    "platform",
+    "resolution",
+    "aspect",
+    "fps",
+    "codec",
+    "crf",
+    "bitrate",
+    "pixel_format",
+    "segmentation",
+    "duration",
+    "audio",
+    "transitions",
+    "output",
+    "target_size",
+    "redaction",
+    "qr_codes",
+    "sources",
+    "renderer",
+    "workers",
+];
+
+impl NoteSettings {
+    // Read and validate the `md2vid:` block. Every problem is reported, one per line
+    pub fn from_frontmatter(frontmatter: &str) -> Result<Self, String> {
+        let mut settings = NoteSettings::default();
+        let entries = match file_utils::frontmatter_section(frontmatter, "md2vid") {
+            Some(entries) => entries,
+            None => return Ok(settings),
+        };
+
+        let mut errors = Vec::new();
+        let mut profile = None;
+        let mut resolution = None;
+        let mut aspect = None;
+        let mut fps = None;
+        let mut codec = None;
+        let mut crf = None;
+        let mut bitrate = None;
+        let mut pixel_format = None;
+
+        for (key, value) in &entries {
+            let result = match key.as_str() {
+                "theme" => parse_theme(value).map(|name| settings.theme = Some(name)),
+                "profile" => parse_profile(value).map(|preset| profile = Some(preset)),
+                "platform" => parse_platform(value).map(|name| settings.platform = Some(name)),
+                "resolution" => parse_resolution(value).map(|size| resolution = Some(size)),
+                "aspect" => parse_aspect(value).map(|ratio| aspect = Some(ratio)),
+                "fps" => parse_fps(value).map(|rate| fps = Some(rate)),
+                "codec" => parse_codec(value).map(|name| codec = Some(name)),
+                "crf" => parse_crf(value).map(|value| crf = Some(value)),
+                "bitrate" => parse_bitrate(value).map(|value| bitrate = Some(value)),
+                "pixel_format" => parse_pixel_format(value).map(|format| pixel_format = Some(format)),
+                "segmentation" => parse_segmentation(value).map(|mode| settings.segmentation = mode),
+                "duration" => parse_duration(value).map(|seconds| settings.duration = Some(seconds)),
+                "audio" => parse_audio(value).map(|audio| settings.audio = Some(audio)),
+                "transitions" => parse_transition(value).map(|transition| settings.transition = transition),
+                "output" => parse_output(value).map(|name| settings.output = Some(name)),
+                "target_size" => parse_target_size(value).map(|bytes| settings.target_size = Some(bytes)),
+                "redaction" => parse_redaction(value).map(|mode| settings.redaction = Some(mode)),
+                "qr_codes" => parse_bool(value).map(|enabled| settings.qr_codes = enabled),
+                "sources" => parse_bool(value).map(|enabled| settings.sources = enabled),
+                "renderer" => parse_renderer(value).map(|renderer| settings.renderer = renderer),
+                "workers" => parse_workers(value).map(|workers| settings.workers = Some(workers)),
+                _ => Err(format!("unknown setting, expected one of {}", KEYS.join(", "))),
+            };
+            if let Err(e) = result {
+                errors.push(format!("md2vid.{}: {}", key, e));
+            }
+        }
+
+        let mut profile = match (profile, settings.platform) {
+            (Some(_), Some(_)) => {
+                errors.push("md2vid.platform: platform and profile can't be used together".to_string());
+                RenderProfile::default()
+            }
+            (Some(profile), None) => profile,
+            (None, Some(platform)) => platform.profile(),
+            (None, None) => RenderProfile::default(),
+        };
+        match output_size(resolution, aspect, &profile) {
+            Ok(Some((width, height))) => {
+                profile.width = width;
+                profile.height = height;
+            }
+            Ok(None) => {}
+            Err(e) => errors.push(format!("md2vid.resolution: {}", e)),
+        }
+        if let Some(fps) = fps {
+            profile.fps = fps;
+        }
+        if let Some(codec) = codec {
+            profile.codec = codec;
+        }
+        // The final bitrate is worked out from the target size
+        if settings.target_size.is_some() && bitrate.is_some() {
+            errors.push("md2vid.target_size: target_size and bitrate can't be used together".to_string());
+        }
+        match (crf, bitrate) {
+            (Some(_), Some(_)) => errors.push("md2vid.crf: crf and bitrate can't be used together".to_string()),
+            (Some(crf), None) => profile.quality = Quality::Crf(crf),
+            (None, Some(bitrate)) => profile.quality = Quality::Bitrate(bitrate),
+            (None, None) => {}
+        }
+        if let Some(pixel_format) = pixel_format {
+            profile.pixel_format = pixel_format;
+        }
+        settings.profile = profile;
+
+        if errors.is_empty() {
+            Ok(settings)
+        } else {
+            Err(format!("Invalid md2vid settings in frontmatter:\n{}", errors.join("\n")))
+        }
+    }
+}
+
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.trim().to_string(),
+        // Frontmatter numbers are parsed as floats, keep `1080` from becoming `1080.0`
+        Value::Number(number) => match number.as_f64() {
+            Some(n) if n.fract() == 0.0 => format!("{}", n as i64),
+            _ => number.to_string(),
+        },
+        other => other.to_string(),
+    }
+}
+
+fn parse_theme(value: &Value) -> Result<String, String> {
+    let name = as_text(value);
+    theme::load_theme(&name)?;
+    Ok(name)
+}
+
+// Either WIDTHxHEIGHT or a size such as 720p, 1080p or 4k, which sets the shorter side so
+// 1080p is 1920x1080 landscape and 1080x1920 vertical
+enum Resolution {
+    Exact(u32, u32),
+    ShortSide(u32),
+}
+
+fn parse_resolution(value: &Value) -> Result<Resolution, String> {
+    let text = as_text(value).to_lowercase();
+    let invalid = || format!("expected WIDTHxHEIGHT or one of 480p, 720p, 1080p, 1440p, 4k, got \"{}\"", text);
+
+    let resolution = match text.as_str() {
+        "4k" | "2160p" => Resolution::ShortSide(2160),
+        _ => match text.split_once('x') {
+            Some((width, height)) => {
+                let width = width.trim().parse::<u32>().map_err(|_| invalid())?;
+                let height = height.trim().parse::<u32>().map_err(|_| invalid())?;
+                Resolution::Exact(width, height)
+            }
+            None => Resolution::ShortSide(text.trim_end_matches('p').parse::<u32>().map_err(|_| invalid())?),
+        },
+    };
+
+    let (width, height) = match resolution {
+        Resolution::Exact(width, height) => (width, height),
+        Resolution::ShortSide(side) => (side, side),
+    };
+    if !(120..=4320).contains(&width) || !(120..=4320).contains(&height) {
+        return Err(format!("{} is outside the supported range (120 to 4320 pixels)", text));
+    }
+    if width % 2 != 0 || height % 2 != 0 {
+        return Err(format!("{} must use even dimensions", text));
+    }
+    Ok(resolution)
+}
+
+fn parse_aspect(value: &Value) -> Result<(u32, u32), String> {
+    let text = as_text(value);
+    let invalid = || format!("expected a ratio such as 16:9, 9:16, 1:1 or 4:5, got \"{}\"", text);
+    let (width, height) = text.split_once(':').ok_or_else(invalid)?;
+    let width = width.trim().parse::<u32>().map_err(|_| invalid())?;
+    let height = height.trim().parse::<u32>().map_err(|_| invalid())?;
+    if width == 0 || height == 0 {
+        return Err(invalid());
+    }
+    Ok((width, height))
+}
+
+// Combine resolution and aspect into the output size. A bare size with no aspect keeps the
+// profile's aspect, an aspect with no resolution keeps the profile's long edge
+fn output_size(
+    resolution: Option<Resolution>,
+    aspect: Option<(u32, u32)>,
+    profile: &RenderProfile,
+) -> Result<Option<(u32, u32)>, String> {
+    let width_for = |height: u32, (w, h): (u32, u32)| ((height as f64 * w as f64 / h as f64 / 2.0).round() as u32) * 2;
+
+    match (resolution, aspect) {
+        (None, None) => Ok(None),
+        (Some(Resolution::Exact(width, height)), None) => Ok(Some((width, height))),
+        (Some(Resolution::Exact(width, height)), Some((w, h))) => {
+            if width as u64 * h as u64 == height as u64 * w as u64 {
+                Ok(Some((width, height)))
+            } else {
+                Err(format!("{}x{} does not match the aspect ratio {}:{}", width, height, w, h))
+            }
+        }
+        (Some(Resolution::ShortSide(side)), aspect) => {
+            let (w, h) = aspect.unwrap_or((profile.width, profile.height));
+            if w > h {
+                Ok(Some((width_for(side, (w, h)), side)))
+            } else {
+                Ok(Some((side, width_for(side, (h, w)))))
+            }
+        }
+        (None, Some((w, h))) => {
+            let long = profile.width.max(profile.height);
+            if w > h {
+                Ok(Some((long, width_for(long, (h, w)))))
+            } else {
+                Ok(Some((width_for(long, (w, h)), long)))
+            }
+        }
+    }
+}
+
+fn parse_profile(value: &Value) -> Result<RenderProfile, String> {
+    let name = as_text(value);
+    RenderProfile::preset(&name)
+        .ok_or_else(|| format!("expected one of {}, got \"{}\"", render_profile::PRESETS.join(", "), name))
+}
+
+fn parse_platform(value: &Value) -> Result<Platform, String> {
+    let name = as_text(value);
+    Platform::from_name(&name)
+        .ok_or_else(|| format!("expected one of {}, got \"{}\"", platform::PLATFORMS.join(", "), name))
+}
+
+fn parse_fps(value: &Value) -> Result<u32, String> {
+    match as_text(value).parse::<u32>() {
+        Ok(fps) if (1..=120).contains(&fps) => Ok(fps),
+        _ => Err(format!("expected a whole number of frames per second from 1 to 120, got \"{}\"", as_text(value))),
+    }
+}
+
+fn parse_codec(value: &Value) -> Result<Codec, String> {
+    let text = as_text(value);
+    Codec::from_name(&text).ok_or_else(|| format!("expected h264, h265, vp9 or av1, got \"{}\"", text))
+}
+
+fn parse_crf(value: &Value) -> Result<u32, String> {
+    match as_text(value).parse::<u32>() {
+        Ok(crf) if crf <= 63 => Ok(crf),
+        _ => Err(format!("expected a whole number from 0 to 63, got \"{}\"", as_text(value))),
+    }
+}
+
+// A number of bits per second with an optional k or M suffix, such as 2500k or 8M
+fn parse_bitrate(value: &Value) -> Result<String, String> {
+    let text = as_text(value);
+    let number = text.trim_end_matches(['k', 'K', 'm', 'M']);
+    match number.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && number.len() + 1 >= text.len() => Ok(text.replace('m', "M").replace('K', "k")),
+        _ => Err(format!("expected a bitrate such as 2500k or 8M, got \"{}\"", text)),
+    }
+}
+
+fn parse_pixel_format(value: &Value) -> Result<String, String> {
+    const FORMATS: [&str; 6] = ["yuv420p", "yuv422p", "yuv444p", "yuv420p10le", "yuv422p10le", "yuv444p10le"];
+    let text = as_text(value).to_lowercase();
+    if FORMATS.contains(&text.as_str()) {
+        Ok(text)
+    } else {
+        Err(format!("expected one of {}, got \"{}\"", FORMATS.join(", "), text))
+    }
+}
+
+fn parse_segmentation(value: &Value) -> Result<Segmentation, String> {
+    match as_text(value).to_lowercase().as_str() {
+        "sentence" | "sentences" => Ok(Segmentation::Sentence),
+        "paragraph" | "paragraphs" => Ok(Segmentation::Paragraph),
+        "line" | "lines" => Ok(Segmentation::Line),
+        other => Err(format!("expected sentence, paragraph or line, got \"{}\"", other)),
+    }
+}
+
+fn parse_duration(value: &Value) -> Result<f64, String> {
+    let seconds = match value {
+        Value::Number(number) => number.as_f64(),
+        other => as_text(other).trim_end_matches('s').trim().parse::<f64>().ok(),
+    };
+    match seconds {
+        Some(seconds) if seconds > 0.0 => Ok(seconds),
+        _ => Err(format!("expected a number of seconds greater than 0, got \"{}\"", as_text(value))),
+    }
+}
+
+fn parse_audio(value: &Value) -> Result<AudioSource, String> {
+    let text = as_text(value);
+    if text.eq_ignore_ascii_case("none") || *value == Value::Bool(false) {
+        return Ok(AudioSource::Silent);
+    }
+    if text.starts_with("http://") || text.starts_with("https://") {
+        let is_youtube = text.contains("youtube.com/") || text.contains("youtu.be/");
+        return if is_youtube {
+            Ok(AudioSource::YouTube(text))
+        } else {
+            Err(format!("only YouTube links are supported, got \"{}\"", text))
+        };
+    }
+    if Path::new(&text).is_file() {
+        return Ok(AudioSource::File(text));
+    }
+    Err(format!("expected a YouTube link, an audio file path or none, \"{}\" is neither", text))
+}
+
+fn parse_transition(value: &Value) -> Result<Transition, String> {
+    match as_text(value).to_lowercase().as_str() {
+        "none" | "cut" | "false" => Ok(Transition::Cut),
+        "fade" => Ok(Transition::Fade),
+        other => Err(format!("expected none or fade, got \"{}\"", other)),
+    }
+}
+
+fn parse_output(value: &Value) -> Result<String, String> {
+    let name = as_text(value);
+    let name = name.strip_suffix(".mp4").unwrap_or(&name).to_string();
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ' ' || c == '.');
+    if valid {
+        Ok(name)
+    } else {
+        Err(format!(
+            "\"{}\" is not a valid file name, use letters, numbers, spaces, dots, dashes and underscores",
+            name
+        ))
+    }
+}
+
+// A size such as 25MB, 1.5 GB or 800kb, in decimal units. A bare number is in megabytes
+fn parse_target_size(value: &Value) -> Result<u64, String> {
+    let text = as_text(value).trim().to_lowercase();
+    let number_end = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+    let (number, unit) = text.split_at(number_end);
+    let unit_bytes = match unit.trim() {
+        "" | "mb" | "m" => 1e6,
+        "kb" | "k" => 1e3,
+        "gb" | "g" => 1e9,
+        _ => 0.0,
+    };
+    match number.parse::<f64>() {
+        Ok(size) if size > 0.0 && unit_bytes > 0.0 => Ok((size * unit_bytes) as u64),
+        _ => Err(format!("expected a size such as 25MB or 1.5GB, got \"{}\"", as_text(value))),
+    }
+}
+
+fn parse_redaction(value: &Value) -> Result<RedactionMode, String> {
+    let text = as_text(value);
+    RedactionMode::from_name(&text).ok_or_else(|| format!("expected blur, placeholder or remove, got \"{}\"", text))
+}
+
+fn parse_bool(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(enabled) => Ok(*enabled),
+        other => Err(format!("expected true or false, got \"{}\"", as_text(other))),
+    }
+}
+
+fn parse_renderer(value: &Value) -> Result<Renderer, String> {
+    match as_text(value).to_lowercase().as_str() {
+        "timeline" => Ok(Renderer::Timeline),
+        "segments" => Ok(Renderer::Segments),
+        "native" => Ok(Renderer::Native),
+        other => Err(format!("expected timeline, segments or native, got \"{}\"", other)),
+    }
+}
+
+fn parse_workers(value: &Value) -> Result<usize, String> {
+    match as_text(value).parse::<usize>() {
+        Ok(workers) if workers > 0 => Ok(workers),
+        _ => Err(format!("expected a whole number greater than 0, got \"{}\"", as_text(value))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(frontmatter: &str) -> (u32, u32) {
+        let profile = NoteSettings::from_frontmatter(frontmatter).unwrap().profile;
+        (profile.width, profile.height)
+    }
+
+    #[test]
+    fn bare_resolution_keeps_the_profile_aspect() {
+        let cases = [
+            ("md2vid:\n  resolution: 720p\n", (1280, 720)),
+            ("md2vid:\n  profile: vertical\n  resolution: 1080p\n", (1080, 1920)),
+            ("md2vid:\n  profile: vertical\n  resolution: 720p\n", (720, 1280)),
+            ("md2vid:\n  profile: square\n  resolution: 720p\n", (720, 720)),
+            ("md2vid:\n  platform: tiktok\n  resolution: 720p\n", (720, 1280)),
+            ("md2vid:\n  profile: vertical\n  resolution: 720p\n  aspect: 16:9\n", (1280, 720)),
+            ("md2vid:\n  resolution: 1080p\n  aspect: 4:5\n", (1080, 1350)),
+            ("md2vid:\n  profile: vertical\n  aspect: 1:1\n", (1920, 1920)),
+            ("md2vid:\n  profile: 1080p\n  aspect: 9:16\n", (1080, 1920)),
+            ("md2vid:\n  aspect: 4:3\n", (1280, 960)),
+        ];
+        for (frontmatter, expected) in cases {
+            assert_eq!(size(frontmatter), expected, "{}", frontmatter);
+        }
+    }
+
+    fn errors(frontmatter: &str) -> Vec<String> {
+        let error = NoteSettings::from_frontmatter(frontmatter).err().expect("settings should be rejected");
+        let mut lines = error.lines();
+        assert_eq!(lines.next(), Some("Invalid md2vid settings in frontmatter:"));
+        lines.map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn reports_every_invalid_setting() {
+        let errors = errors("md2vid:\n  fps: 500\n  codec: divx\n  duration: -2\n  colour: blue\n  workers: 3\n");
+        let keys: Vec<&str> = errors.iter().map(|line| line.split(':').next().unwrap()).collect();
+        assert_eq!(keys, ["md2vid.fps", "md2vid.codec", "md2vid.duration", "md2vid.colour"]);
+        assert!(errors[3].contains("unknown setting"), "{}", errors[3]);
+    }
+
+    #[test]
+    fn reports_conflicting_settings_together() {
+        let errors = errors(
+            "md2vid:\n  profile: 720p\n  platform: shorts\n  crf: 20\n  bitrate: 5M\n  target_size: 10MB\n  resolution: 1280x720\n  aspect: 4:3\n",
+        );
+        let keys: Vec<&str> = errors.iter().map(|line| line.split(':').next().unwrap()).collect();
+        assert_eq!(keys, ["md2vid.platform", "md2vid.resolution", "md2vid.target_size", "md2vid.crf"]);
+    }
+
+    #[test]
+    fn valid_settings_have_no_errors() {
+        let settings = NoteSettings::from_frontmatter("md2vid:\n  fps: 60\n  crf: 18\n  duration: 3s\n").unwrap();
+        assert_eq!(settings.profile.fps, 60);
+        assert_eq!(settings.duration, Some(3.0));
+        assert!(NoteSettings::from_frontmatter("title: no md2vid block\n").is_ok());
+    }
+}
@@ -0,0 +1,88 @@
+use crate::ffmpeg_command::Filter;
+use crate::render_profile::{Quality, RenderProfile, SafeZone};
+
+// Short-form video platforms with a preset for their upload requirements
+#[derive(Clone, Copy, PartialEq)]
+pub enum Platform {
+    Shorts,
+    Reels,
+    TikTok,
+}
+
+pub const PLATFORMS: [&str; 3] = ["shorts", "reels", "tiktok"];
+
+// Integrated loudness in LUFS and true peak in dBTP for ffmpeg's loudnorm filter
+#[derive(Clone, Copy)]
+pub struct Loudness {
+    pub integrated: f64,
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    pub fn filter(&self) -> Filter {
+        Filter::new("loudnorm")
+            .option("I", self.integrated)
+            .option("TP", self.true_peak)
+            .option("LRA", 11)
+    }
+}
+
+impl Platform {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().replace(['-', '_', ' '], "").as_str() {
+            "shorts" | "youtubeshorts" => Some(Platform::Shorts),
+            "reels" | "instagramreels" | "instagram" => Some(Platform::Reels),
+            "tiktok" => Some(Platform::TikTok),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Shorts => "YouTube Shorts",
+            Platform::Reels => "Instagram Reels",
+            Platform::TikTok => "TikTok",
+        }
+    }
+
+    // The vertical profile at the platform's recommended bitrate, with its safe zone
+    pub fn profile(&self) -> RenderProfile {
+        let mut profile = RenderProfile::preset("vertical").unwrap();
+        profile.quality = Quality::Bitrate(self.bitrate().to_string());
+        profile.safe_zone = self.safe_zone();
+        profile
+    }
+
+    fn bitrate(&self) -> &'static str {
+        match self {
+            Platform::Shorts => "8M",
+            Platform::Reels => "5M",
+            Platform::TikTok => "6M",
+        }
+    }
+
+    // Longest video the platform accepts, in seconds
+    pub fn max_duration(&self) -> f64 {
+        match self {
+            Platform::Shorts => 180.0,
+            Platform::Reels => 180.0,
+            Platform::TikTok => 600.0,
+        }
+    }
+
+    // All three normalise playback to about -14 LUFS, so louder audio is turned down anyway
+    pub fn loudness(&self) -> Loudness {
+        Loudness { integrated: -14.0, true_peak: -1.0 }
+    }
+
+    // Where the app draws its buttons, channel name and caption over the video: the caption
+    // and account name along the bottom, the like/comment/share column on the right and
+    // the search or camera bar along the top
+    fn safe_zone(&self) -> SafeZone {
+        match self {
+            Platform::Shorts => SafeZone { top: 0.10, right: 0.15, bottom: 0.25, left: 0.05 },
+            Platform::Reels => SafeZone { top: 0.12, right: 0.12, bottom: 0.25, left: 0.05 },
+            Platform::TikTok => SafeZone { top: 0.08, right: 0.14, bottom: 0.22, left: 0.06 },
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::ffmpeg_operations::FfmpegProgress;
+use crate::jobs::JobHandle;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Download,
+    Render,
+    Concatenate,
+    Merge,
+    Compress,
+}
+
+// Payload of the `progress` event: the whole job's percentage and estimated time left, and
+// the encoder speed of the stage currently running
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProgressReport {
+    pub percent: f64,
+    pub stage: Stage,
+    pub eta_seconds: Option<f64>,
+    pub fps: f64,
+    pub speed: f64,
+}
+
+struct StageProgress {
+    stage: Stage,
+    weight: f64,
+    // Seconds of video each part of the stage produces, and how many it has produced so far.
+    // Parts are the segments rendered side by side; other stages have a single part
+    totals: Vec<f64>,
+    done: Vec<f64>,
+    finished: bool,
+}
+
+impl StageProgress {
+    fn fraction(&self) -> f64 {
+        if self.finished {
+            return 1.0;
+        }
+        let total: f64 = self.totals.iter().sum();
+        if total <= 0.0 {
+            return 0.0;
+        }
+        let done: f64 = self.done.iter().zip(&self.totals).map(|(done, total)| done.min(*total)).sum();
+        done / total
+    }
+}
+
+struct ProgressState {
+    stages: Vec<StageProgress>,
+    current: Stage,
+    fps: f64,
+    speed: f64,
+    last_percent: f64,
+}
+
+// Combines the progress of every ffmpeg run of a job into one percentage and ETA.
+// Each stage counts for its weight, whatever the length of the video
+pub struct RenderProgress {
+    handle: JobHandle,
+    started: Instant,
+    state: Mutex<ProgressState>,
+}
+
+impl RenderProgress {
+    pub fn new(handle: JobHandle, stages: &[(Stage, f64)]) -> Self {
+        let stages = stages
+            .iter()
+            .map(|(stage, weight)| StageProgress {
+                stage: *stage,
+                weight: *weight,
+                totals: Vec::new(),
+                done: Vec::new(),
+                finished: false,
+            })
+            .collect();
+        RenderProgress {
+            handle,
+            started: Instant::now(),
+            state: Mutex::new(ProgressState {
+                stages,
+                current: Stage::Render,
+                fps: 0.0,
+                speed: 0.0,
+                last_percent: -1.0,
+            }),
+        }
+    }
+
+    // Start a stage made of parts producing `totals` seconds of video each
+    pub fn start(&self, stage: Stage, totals: Vec<f64>) {
+        self.change(stage, |progress| {
+            progress.done = vec![0.0; totals.len()];
+            progress.totals = totals;
+            progress.finished = false;
+        });
+    }
+
+    // An update from the ffmpeg run producing `part` of the stage
+    pub fn report(&self, stage: Stage, part: usize, update: FfmpegProgress) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.fps = update.fps;
+            state.speed = update.speed;
+        }
+        self.change(stage, |progress| {
+            if let Some(done) = progress.done.get_mut(part) {
+                *done = update.out_time;
+            }
+        });
+    }
+
+    // For work that only knows how far along it is
+    pub fn report_fraction(&self, stage: Stage, fraction: f64) {
+        self.change(stage, |progress| {
+            progress.totals = vec![1.0];
+            progress.done = vec![fraction];
+        });
+    }
+
+    pub fn finish_part(&self, stage: Stage, part: usize) {
+        self.change(stage, |progress| {
+            if let (Some(done), Some(total)) = (progress.done.get_mut(part), progress.totals.get(part)) {
+                *done = *total;
+            }
+        });
+    }
+
+    pub fn finish(&self, stage: Stage) {
+        self.change(stage, |progress| progress.finished = true);
+    }
+
+    fn change(&self, stage: Stage, change: impl FnOnce(&mut StageProgress)) {
+        let report = {
+            let mut state = self.state.lock().unwrap();
+            let progress = match state.stages.iter_mut().find(|progress| progress.stage == stage) {
+                Some(progress) => progress,
+                None => return,
+            };
+            change(progress);
+            state.current = stage;
+
+            let total_weight: f64 = state.stages.iter().map(|progress| progress.weight).sum();
+            let done_weight: f64 = state.stages.iter().map(|progress| progress.weight * progress.fraction()).sum();
+            let fraction = if total_weight > 0.0 { done_weight / total_weight } else { 0.0 };
+            let percent = (fraction * 1000.0).round() / 10.0;
+            // ffmpeg reports twice a second per run, so only changes worth showing are sent
+            if (percent - state.last_percent).abs() < 0.1 {
+                return;
+            }
+            state.last_percent = percent;
+
+            let elapsed = self.started.elapsed().as_secs_f64();
+            let eta_seconds = if (0.02..1.0).contains(&fraction) {
+                Some((elapsed / fraction * (1.0 - fraction)).round())
+            } else {
+                None
+            };
+            ProgressReport { percent, stage: state.current, eta_seconds, fps: state.fps, speed: state.speed }
+        };
+        let _ = self.handle.emit_progress(report);
+    }
+}
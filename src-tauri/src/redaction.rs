@@ -0,0 +1,391 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::file_utils;
+use crate::links::{self, Link};
+
+// What happens to redacted text
+#[derive(Clone, Copy, PartialEq)]
+pub enum RedactionMode {
+    // Rendered with a heavy blur so it can't be read
+    Blur,
+    // Replaced with the placeholder text
+    Placeholder,
+    // Left out entirely
+    Remove,
+}
+
+impl RedactionMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "blur" => Some(RedactionMode::Blur),
+            "placeholder" | "replace" => Some(RedactionMode::Placeholder),
+            "remove" => Some(RedactionMode::Remove),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RedactionRule {
+    pub name: String,
+    pub pattern: String,
+}
+
+// Redaction settings shared by every note, read from REDACTION_RULES_PATH (./redaction.json):
+// { "mode": "blur", "placeholder": "[redacted]", "rules": [{ "name": "phone", "pattern": "..." }] }
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct RedactionConfig {
+    pub mode: String,
+    pub placeholder: String,
+    pub rules: Vec<RedactionRule>,
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        RedactionConfig {
+            mode: "placeholder".to_string(),
+            placeholder: "[redacted]".to_string(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+fn rules_path() -> PathBuf {
+    PathBuf::from(std::env::var("REDACTION_RULES_PATH").unwrap_or_else(|_| "./redaction.json".to_string()))
+}
+
+// The shared config, or the defaults (no regex rules) when there is no rules file
+pub fn load_config() -> Result<RedactionConfig, String> {
+    let path = rules_path();
+    if !path.exists() {
+        return Ok(RedactionConfig::default());
+    }
+    let content = fs::read_to_string(&path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let config: RedactionConfig =
+        serde_json::from_str(&content).map_err(|e| format!("Invalid redaction rules in {}: {}", path.display(), e))?;
+    if RedactionMode::from_name(&config.mode).is_none() {
+        return Err(format!(
+            "Invalid redaction mode \"{}\" in {}, expected blur, placeholder or remove",
+            config.mode,
+            path.display()
+        ));
+    }
+    Ok(config)
+}
+
+// Private-use characters around blurred text. They become ASS blur tags once redaction is
+// done, so later rules never match inside the tags
+const BLUR_START: char = '\u{E002}';
+const BLUR_END: char = '\u{E003}';
+
+// libass only blurs the border of text that has one, so the border and shadow are turned off
+// to blur the glyphs themselves, strongly enough that text at any theme font size is
+// unreadable. Without a value \bord and \shad go back to the style's, keeping other overrides
+const BLUR_OPEN: &str = "{\\bord0\\shad0\\blur20}";
+const BLUR_CLOSE: &str = "{\\blur0\\bord\\shad}";
+
+// Stands in for blurred text wherever the text is used for something other than the screen
+const MASK: char = '\u{E004}';
+
+// Redact a note body before it is segmented. In order: `%%private%%` blocks, sections marked
+// `private: true`, the frontmatter `redact:` list and the regex rules. Returns the redacted
+// text and one report line for everything that was redacted
+pub fn redact(
+    text: &str,
+    frontmatter: &str,
+    config: &RedactionConfig,
+    mode: RedactionMode,
+) -> Result<(String, Vec<String>), String> {
+    let mut report = Vec::new();
+    let placeholder = config.placeholder.as_str();
+
+    let (text, blocks) = redact_private_blocks(text, mode, placeholder);
+    if blocks > 0 {
+        report.push(format!("{} private block(s)", blocks));
+    }
+
+    let (text, sections) = redact_private_sections(&text, mode, placeholder);
+    for section in sections {
+        report.push(format!("private section \"{}\"", section));
+    }
+
+    let mut text = text;
+    for term in file_utils::frontmatter_list(frontmatter, "redact") {
+        let re = term_regex(&term)?;
+        let count = re.find_iter(&text).count();
+        if count > 0 {
+            report.push(format!("\"{}\" (frontmatter): {} match(es)", term, count));
+            text = re
+                .replace_all(&text, |caps: &regex::Captures| redact_inline(&caps[0], mode, placeholder))
+                .into_owned();
+        }
+    }
+
+    for rule in &config.rules {
+        let re = Regex::new(&rule.pattern).map_err(|e| format!("Invalid redaction rule {}: {}", rule.name, e))?;
+        let count = re.find_iter(&text).count();
+        if count > 0 {
+            report.push(format!("rule {}: {} match(es)", rule.name, count));
+            text = re
+                .replace_all(&text, |caps: &regex::Captures| redact_inline(&caps[0], mode, placeholder))
+                .into_owned();
+        }
+    }
+
+    let text = text.replace(BLUR_START, BLUR_OPEN).replace(BLUR_END, BLUR_CLOSE);
+    Ok((text, report))
+}
+
+// Every blurred span replaced with a single mask character
+fn mask_blurred(text: &str) -> String {
+    let span = Regex::new(&format!("(?s){}.*?{}", regex::escape(BLUR_OPEN), regex::escape(BLUR_CLOSE))).unwrap();
+    span.replace_all(text, MASK.to_string().as_str()).into_owned()
+}
+
+// The text without its blurred parts, for plain-text outputs such as chapter titles
+pub fn without_blurred(text: &str) -> String {
+    mask_blurred(text).replace(MASK, " ").split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+// The links of the text, leaving out any link that is blurred in whole or in part. The blur
+// only hides them on screen, their URL would still reach the Sources card and QR codes
+pub fn unredacted_links(text: &str) -> Vec<Link> {
+    links::extract_links(&mask_blurred(text))
+        .into_iter()
+        .filter(|link| !link.text.contains(MASK) && !link.url.contains(MASK))
+        .collect()
+}
+
+// Case-insensitive, and whole words only so a short name doesn't eat the middle of other words
+fn term_regex(term: &str) -> Result<Regex, String> {
+    let mut pattern = regex::escape(term);
+    if term.starts_with(|c: char| c.is_alphanumeric()) {
+        pattern = format!(r"\b{}", pattern);
+    }
+    if term.ends_with(|c: char| c.is_alphanumeric()) {
+        pattern = format!(r"{}\b", pattern);
+    }
+    Regex::new(&format!("(?i){}", pattern)).map_err(|e| e.to_string())
+}
+
+fn redact_inline(matched: &str, mode: RedactionMode, placeholder: &str) -> String {
+    match mode {
+        RedactionMode::Blur => blur_words(matched),
+        RedactionMode::Placeholder => placeholder.to_string(),
+        RedactionMode::Remove => String::new(),
+    }
+}
+
+// Each word is blurred on its own so the blur survives however the text is split into segments
+fn blur_words(text: &str) -> String {
+    let word = Regex::new(r"\S+").unwrap();
+    word.replace_all(text, |word: &regex::Captures| format!("{}{}{}", BLUR_START, &word[0], BLUR_END))
+        .into_owned()
+}
+
+// Redact several lines of markdown. Blurring keeps the line structure (headings, list markers)
+fn redact_lines(text: &str, mode: RedactionMode, placeholder: &str) -> String {
+    match mode {
+        RedactionMode::Blur => {
+            let prefix = Regex::new(r"^(\s*(?:#{1,6}\s+|[-*+]\s+(?:\[.\]\s+)?|\d+[.)]\s+)?)(.*)$").unwrap();
+            text.split('\n')
+                .map(|line| {
+                    let caps = prefix.captures(line).unwrap();
+                    format!("{}{}", &caps[1], blur_words(&caps[2]))
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+        RedactionMode::Placeholder if text.contains('\n') => format!("\n{}\n", placeholder),
+        RedactionMode::Placeholder => placeholder.to_string(),
+        RedactionMode::Remove => String::new(),
+    }
+}
+
+// `%%private%%` ... `%%/private%%`. An unclosed block runs to the end of the note
+fn redact_private_blocks(text: &str, mode: RedactionMode, placeholder: &str) -> (String, usize) {
+    let re = Regex::new(r"(?s)%%\s*private\s*%%(.*?)(?:%%\s*/private\s*%%|\z)").unwrap();
+    let mut count = 0;
+    let result = re.replace_all(text, |caps: &regex::Captures| {
+        count += 1;
+        redact_lines(&caps[1], mode, placeholder)
+    });
+    (result.into_owned(), count)
+}
+
+// A `private: true` line (or the inline field `[private:: true]`) makes the section it sits in
+// private: from its heading up to the next heading of the same or a higher level.
+// Returns the redacted text and the titles of the redacted sections
+fn redact_private_sections(text: &str, mode: RedactionMode, placeholder: &str) -> (String, Vec<String>) {
+    let heading_re = Regex::new(r"^(#{1,6})\s+(.*?)\s*#*\s*$").unwrap();
+    let marker_re = Regex::new(r"(?i)^\s*(?:private:\s*true|\[private::\s*true\])\s*$").unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+
+    let headings: Vec<(usize, usize, String)> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| heading_re.captures(line).map(|caps| (i, caps[1].len(), caps[2].to_string())))
+        .collect();
+
+    // Line ranges [start, end) of the private sections
+    let mut ranges: Vec<(usize, usize, String)> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        if !marker_re.is_match(line) {
+            continue;
+        }
+        let (start, end, title) = match headings.iter().rev().find(|(h, _, _)| *h < i) {
+            Some((h, level, title)) => {
+                let end = headings
+                    .iter()
+                    .find(|(next, next_level, _)| next > h && next_level <= level)
+                    .map_or(lines.len(), |(next, _, _)| *next);
+                (*h, end, title.clone())
+            }
+            // Before the first heading the marker covers the note's introduction
+            None => (0, headings.first().map_or(lines.len(), |(h, _, _)| *h), "introduction".to_string()),
+        };
+        if !ranges.iter().any(|(s, _, _)| *s == start) {
+            ranges.push((start, end, title));
+        }
+    }
+
+    if ranges.is_empty() {
+        return (text.to_string(), Vec::new());
+    }
+
+    let mut result: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        match ranges.iter().find(|(start, _, _)| *start == i) {
+            Some((start, end, _)) => {
+                let section: Vec<&str> = lines[*start..*end]
+                    .iter()
+                    .copied()
+                    .filter(|line| !marker_re.is_match(line))
+                    .collect();
+                let redacted = redact_lines(&section.join("\n"), mode, placeholder);
+                if !redacted.trim().is_empty() {
+                    result.push(redacted.trim_matches('\n').to_string());
+                    result.push(String::new());
+                }
+                i = *end;
+            }
+            None => {
+                result.push(lines[i].to_string());
+                i += 1;
+            }
+        }
+    }
+
+    let titles = ranges.into_iter().map(|(_, _, title)| title).collect();
+    (result.join("\n"), titles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(rules: &[(&str, &str)]) -> RedactionConfig {
+        RedactionConfig {
+            rules: rules
+                .iter()
+                .map(|(name, pattern)| RedactionRule { name: name.to_string(), pattern: pattern.to_string() })
+                .collect(),
+            ..RedactionConfig::default()
+        }
+    }
+
+    fn redact_with(text: &str, frontmatter: &str, rules: &[(&str, &str)], mode: RedactionMode) -> (String, Vec<String>) {
+        redact(text, frontmatter, &config(rules), mode).unwrap()
+    }
+
+    #[test]
+    fn blur_turns_off_border_and_shadow_then_restores_them() {
+        let (text, report) = redact_with("Call John today", "redact: [John]", &[], RedactionMode::Blur);
+        assert_eq!(text, "Call {\\bord0\\shad0\\blur20}John{\\blur0\\bord\\shad} today");
+        assert_eq!(report, vec!["\"John\" (frontmatter): 1 match(es)"]);
+    }
+
+    #[test]
+    fn blur_wraps_each_word() {
+        let (text, _) = redact_with("Meet John Smith", "", &[("name", "John Smith")], RedactionMode::Blur);
+        assert_eq!(text, format!("Meet {o}John{c} {o}Smith{c}", o = BLUR_OPEN, c = BLUR_CLOSE));
+    }
+
+    #[test]
+    fn placeholder_and_remove() {
+        let (text, _) = redact_with("Call John today", "redact: [John]", &[], RedactionMode::Placeholder);
+        assert_eq!(text, "Call [redacted] today");
+        let (text, _) = redact_with("Call John today", "redact: [John]", &[], RedactionMode::Remove);
+        assert_eq!(text, "Call  today");
+    }
+
+    #[test]
+    fn terms_match_whole_words_only() {
+        let (text, _) = redact_with("Al and Alice", "redact: [al]", &[], RedactionMode::Placeholder);
+        assert_eq!(text, "[redacted] and Alice");
+    }
+
+    #[test]
+    fn overlapping_rules_redact_once() {
+        let rules = [("phone", r"\d{3}-\d{4}"), ("exchange", r"555-\d")];
+        let (text, report) = redact_with("Call 555-1234 now", "", &rules, RedactionMode::Placeholder);
+        assert_eq!(text, "Call [redacted] now");
+        assert_eq!(report, vec!["rule phone: 1 match(es)"]);
+    }
+
+    #[test]
+    fn later_rules_never_match_inside_blur_tags() {
+        // `bord` and `blur` would match inside the tags if they were already in the text
+        let rules = [("secret", "secret"), ("tags", r"bord|blur|shad")];
+        let (text, report) = redact_with("a secret plan", "", &rules, RedactionMode::Blur);
+        assert_eq!(text, format!("a {}secret{} plan", BLUR_OPEN, BLUR_CLOSE));
+        assert_eq!(report.len(), 1);
+    }
+
+    #[test]
+    fn invalid_rule_is_an_error() {
+        assert!(redact("text", "", &config(&[("broken", "(")]), RedactionMode::Blur).is_err());
+    }
+
+    #[test]
+    fn private_blocks_and_unclosed_blocks() {
+        let (text, report) =
+            redact_with("Intro\n%%private%%\nhidden\n%%/private%%\nOutro", "", &[], RedactionMode::Remove);
+        assert_eq!(text, "Intro\n\nOutro");
+        assert_eq!(report, vec!["1 private block(s)"]);
+
+        let (text, _) = redact_with("Intro\n%% private %%\nhidden to the end", "", &[], RedactionMode::Placeholder);
+        assert_eq!(text, "Intro\n\n[redacted]\n");
+    }
+
+    #[test]
+    fn private_section_runs_to_the_next_heading_of_the_same_level() {
+        let text = "# One\nkeep\n## Two\nprivate: true\nhidden\n### Three\nalso hidden\n## Four\nkeep too";
+        let (text, report) = redact_with(text, "", &[], RedactionMode::Remove);
+        assert_eq!(text, "# One\nkeep\n## Four\nkeep too");
+        assert_eq!(report, vec!["private section \"Two\""]);
+    }
+
+    #[test]
+    fn blurred_links_are_left_out() {
+        let text = format!(
+            "[{o}John{c}](https://a.example) {o}[Jane](https://b.example){c} [{o}John{c} Smith](https://c.example) \
+             https://d.example/{o}john{c} [public](https://e.example) https://f.example",
+            o = BLUR_OPEN,
+            c = BLUR_CLOSE
+        );
+        let urls: Vec<String> = unredacted_links(&text).into_iter().map(|link| link.url).collect();
+        assert_eq!(urls, vec!["https://e.example", "https://f.example"]);
+    }
+
+    #[test]
+    fn without_blurred_drops_blurred_words() {
+        let text = format!("Meeting with {o}John{c} {o}Smith{c} today", o = BLUR_OPEN, c = BLUR_CLOSE);
+        assert_eq!(without_blurred(&text), "Meeting with today");
+    }
+}
@@ -0,0 +1,227 @@
+use crate::ffmpeg_command::{self, OutputFile};
+use crate::image_resolution::ImageResolution;
+
+// Height of the canvas subtitles are laid out on, whatever the output resolution
+const CANVAS_HEIGHT: u32 = 720;
+
+// The coordinate space of the ASS scripts (PlayResX x PlayResY). It is always 720 units high
+// and as wide as the profile's aspect ratio allows, so theme font sizes and margins look the
+// same at every resolution; libass and the native renderer scale it to the frame
+#[derive(Clone, Copy)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    // The profile's safe zone, in canvas units
+    pub safe: Insets,
+}
+
+impl Canvas {
+    // Centre of the safe area, which is the centre of the canvas when there is no safe zone
+    pub fn centre(&self) -> (i32, i32) {
+        (
+            (self.safe.left + self.width - self.safe.right) as i32 / 2,
+            (self.safe.top + self.height - self.safe.bottom) as i32 / 2,
+        )
+    }
+}
+
+impl Default for Canvas {
+    fn default() -> Self {
+        Canvas { width: 1280, height: CANVAS_HEIGHT, safe: Insets::default() }
+    }
+}
+
+// Distances from each edge of the frame or canvas
+#[derive(Clone, Copy, Default)]
+pub struct Insets {
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+    pub left: u32,
+}
+
+impl Insets {
+    pub fn is_zero(&self) -> bool {
+        self.top == 0 && self.right == 0 && self.bottom == 0 && self.left == 0
+    }
+}
+
+// The edges of the frame a platform covers with its own buttons and captions, as fractions
+// of the frame's size. Text and images are kept inside the rest
+#[derive(Clone, Copy, Default)]
+pub struct SafeZone {
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+    pub left: f64,
+}
+
+impl SafeZone {
+    fn insets(&self, width: u32, height: u32) -> Insets {
+        let part = |fraction: f64, size: u32| (fraction * size as f64).round() as u32;
+        Insets {
+            top: part(self.top, height),
+            right: part(self.right, width),
+            bottom: part(self.bottom, height),
+            left: part(self.left, width),
+        }
+    }
+}
+
+// Software encoders only, so every profile renders with a stock ffmpeg build
+#[derive(Clone, Copy, PartialEq)]
+pub enum Codec {
+    H264,
+    H265,
+    Vp9,
+    Av1,
+}
+
+impl Codec {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().replace(['.', '-'], "").as_str() {
+            "h264" | "avc" | "x264" => Some(Codec::H264),
+            "h265" | "hevc" | "x265" => Some(Codec::H265),
+            "vp9" => Some(Codec::Vp9),
+            "av1" => Some(Codec::Av1),
+            _ => None,
+        }
+    }
+
+    fn encoder(&self) -> &'static str {
+        match self {
+            Codec::H264 => "libx264",
+            Codec::H265 => "libx265",
+            Codec::Vp9 => "libvpx-vp9",
+            Codec::Av1 => "libaom-av1",
+        }
+    }
+}
+
+// Constant quality or average bitrate
+#[derive(Clone, PartialEq)]
+pub enum Quality {
+    Crf(u32),
+    // ffmpeg bitrate such as 5M or 2500k
+    Bitrate(String),
+}
+
+// Size, frame rate and encoding of the rendered video
+#[derive(Clone)]
+pub struct RenderProfile {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub codec: Codec,
+    pub quality: Quality,
+    pub pixel_format: String,
+    pub safe_zone: SafeZone,
+}
+
+impl Default for RenderProfile {
+    fn default() -> Self {
+        RenderProfile::preset("720p").unwrap()
+    }
+}
+
+pub const PRESETS: [&str; 6] = ["720p", "1080p", "4k", "vertical", "square", "portrait"];
+
+impl RenderProfile {
+    pub fn preset(name: &str) -> Option<Self> {
+        let profile = |width, height, fps, codec, quality| RenderProfile {
+            width,
+            height,
+            fps,
+            codec,
+            quality,
+            pixel_format: "yuv420p".to_string(),
+            safe_zone: SafeZone::default(),
+        };
+        match name.to_lowercase().as_str() {
+            "720p" => Some(profile(1280, 720, 25, Codec::H264, Quality::Bitrate("5M".to_string()))),
+            "1080p" => Some(profile(1920, 1080, 30, Codec::H264, Quality::Crf(20))),
+            "4k" | "2160p" => Some(profile(3840, 2160, 30, Codec::H265, Quality::Crf(24))),
+            "vertical" | "9:16" => Some(profile(1080, 1920, 30, Codec::H264, Quality::Crf(20))),
+            "square" | "1:1" => Some(profile(1080, 1080, 30, Codec::H264, Quality::Crf(20))),
+            "portrait" | "4:5" => Some(profile(1080, 1350, 30, Codec::H264, Quality::Crf(20))),
+            _ => None,
+        }
+    }
+
+    pub fn canvas(&self) -> Canvas {
+        let width = (CANVAS_HEIGHT as f64 * self.width as f64 / self.height as f64).round() as u32;
+        Canvas { width, height: CANVAS_HEIGHT, safe: self.safe_zone.insets(width, CANVAS_HEIGHT) }
+    }
+
+    // The safe zone in frame pixels
+    pub fn insets(&self) -> Insets {
+        self.safe_zone.insets(self.width, self.height)
+    }
+
+    // Frame pixels per canvas unit
+    pub fn scale(&self) -> f64 {
+        self.height as f64 / CANVAS_HEIGHT as f64
+    }
+
+    // Size for ffmpeg sources, as WIDTHxHEIGHT
+    pub fn size(&self) -> String {
+        format!("{}x{}", self.width, self.height)
+    }
+
+    // Width of an overlay image: the resolution's width on the 720 canvas, scaled to the
+    // frame and never wider than its safe area
+    pub fn image_width(&self, resolution: ImageResolution) -> u32 {
+        let insets = self.insets();
+        ((resolution.width() as f64 * self.scale()).round() as u32).min(self.width - insets.left - insets.right)
+    }
+
+    // Top left corner of an overlay image of the given size: centred horizontally in the safe
+    // area and a quarter of the way down it
+    pub fn image_position(&self, width: u32, height: u32) -> (i64, i64) {
+        let insets = self.insets();
+        let free_width = self.width as i64 - (insets.left + insets.right) as i64 - width as i64;
+        let free_height = self.height as i64 - (insets.top + insets.bottom) as i64 - height as i64;
+        (insets.left as i64 + free_width / 2, insets.top as i64 + free_height / 4)
+    }
+
+    // Encoder options for a video rendered with this profile
+    pub fn encode(&self, output: OutputFile) -> OutputFile {
+        let mut output = output
+            .video_codec(self.codec.encoder())
+            .option("-pix_fmt", &self.pixel_format)
+            .option("-r", self.fps);
+        output = match self.codec {
+            Codec::H264 | Codec::H265 => output.preset("slow"),
+            // Row threading and a faster speed setting keep the libvpx and libaom encoders usable
+            Codec::Vp9 => output.option("-row-mt", 1).option("-cpu-used", 2),
+            Codec::Av1 => output.option("-row-mt", 1).option("-cpu-used", 6),
+        };
+        // Apple players only recognise HEVC in MP4 with this tag
+        if self.codec == Codec::H265 {
+            output = output.option("-tag:v", "hvc1");
+        }
+        match &self.quality {
+            // VP9 and AV1 only use constant quality mode without a target bitrate
+            Quality::Crf(crf) => match self.codec {
+                Codec::Vp9 | Codec::Av1 => output.option("-crf", crf).video_bitrate("0"),
+                _ => output.option("-crf", crf),
+            },
+            Quality::Bitrate(bitrate) => output.video_bitrate(bitrate),
+        }
+    }
+
+    // Encoder options for pass 1 or 2 of a two-pass encode at an average `bitrate` in bits
+    // per second. Both passes share the statistics file `passlog`
+    pub fn encode_pass(&self, output: OutputFile, bitrate: u64, pass: u32, passlog: &str) -> OutputFile {
+        let profile = RenderProfile { quality: Quality::Bitrate(format!("{}k", bitrate / 1000)), ..self.clone() };
+        let output = profile.encode(output);
+        match self.codec {
+            // The libx265 wrapper ignores -pass, x265 takes it in its own parameters
+            Codec::H265 => output.option(
+                "-x265-params",
+                ffmpeg_command::key_value_list(&[("pass", pass.to_string()), ("stats", passlog.to_string())]),
+            ),
+            _ => output.option("-pass", pass).option("-passlogfile", passlog),
+        }
+    }
+}
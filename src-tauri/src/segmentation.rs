@@ -0,0 +1,304 @@
+use regex::Regex;
+
+use crate::directives::Directive;
+use crate::note_settings::Segmentation;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SegmentKind {
+    Sentence,
+    // A whole markdown list, revealed item by item
+    List,
+    // An H1-H3 heading, shown as a title card of that level
+    Heading(usize),
+}
+
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub text: String,
+    // Pacing directives from the comments just before this segment
+    pub directives: Vec<Directive>,
+}
+
+impl Segment {
+    fn new(kind: SegmentKind, text: String) -> Self {
+        Segment { kind, text, directives: Vec::new() }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListMarker {
+    Bullet,
+    Number(u32),
+}
+
+pub struct ListItem {
+    pub depth: usize,
+    pub marker: ListMarker,
+    // Some(checked) for task-list items (`- [ ]` / `- [x]`)
+    pub checkbox: Option<bool>,
+    pub text: String,
+}
+
+// Title cards are made for H1-H3 only
+const TITLE_CARD_LEVELS: usize = 3;
+
+fn heading_regex() -> Regex {
+    Regex::new(r"^(#{1,6})\s+(.*?)\s*#*\s*$").unwrap()
+}
+
+fn list_item_regex() -> Regex {
+    Regex::new(r"^(\s*)([-*+]|\d+[.)])\s+(.*)$").unwrap()
+}
+
+// Split the note body into segments. Headings become their own segment, lists are kept
+// together as one segment and everything else is split by `mode` (sentences by default).
+// H4-H6 headings are shown as plain text without their markers
+pub fn segment_text(text: &str, mode: Segmentation) -> Vec<Segment> {
+    let heading_re = heading_regex();
+    let list_re = list_item_regex();
+    let mut segments = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut list: Vec<&str> = Vec::new();
+
+    for line in text.lines() {
+        if let Some(caps) = heading_re.captures(line) {
+            if !paragraph.is_empty() {
+                push_text(&mut segments, &paragraph.join("\n"), mode);
+                paragraph.clear();
+            }
+            if !list.is_empty() {
+                segments.push(Segment::new(SegmentKind::List, list.join("\n")));
+                list.clear();
+            }
+            let level = caps[1].len();
+            let kind = if level <= TITLE_CARD_LEVELS { SegmentKind::Heading(level) } else { SegmentKind::Sentence };
+            segments.push(Segment::new(kind, caps[2].to_string()));
+            continue;
+        }
+
+        let continues_list = !list.is_empty()
+            && !line.trim().is_empty()
+            && (line.starts_with(' ') || line.starts_with('\t'));
+
+        if list_re.is_match(line) || continues_list {
+            if !paragraph.is_empty() {
+                push_text(&mut segments, &paragraph.join("\n"), mode);
+                paragraph.clear();
+            }
+            list.push(line);
+        } else {
+            if !list.is_empty() {
+                segments.push(Segment::new(SegmentKind::List, list.join("\n")));
+                list.clear();
+            }
+            paragraph.push(line);
+        }
+    }
+
+    if !list.is_empty() {
+        segments.push(Segment::new(SegmentKind::List, list.join("\n")));
+    }
+    if !paragraph.is_empty() {
+        push_text(&mut segments, &paragraph.join("\n"), mode);
+    }
+
+    segments
+}
+
+fn push_text(segments: &mut Vec<Segment>, text: &str, mode: Segmentation) {
+    match mode {
+        Segmentation::Sentence => push_sentences(segments, text),
+        Segmentation::Line => {
+            for line in text.lines().filter(|line| !line.trim().is_empty()) {
+                segments.push(Segment::new(SegmentKind::Sentence, line.trim().to_string()));
+            }
+        }
+        Segmentation::Paragraph => {
+            let mut paragraph: Vec<&str> = Vec::new();
+            for line in text.lines().chain(std::iter::once("")) {
+                if !line.trim().is_empty() {
+                    paragraph.push(line.trim());
+                } else if !paragraph.is_empty() {
+                    segments.push(Segment::new(SegmentKind::Sentence, paragraph.join(" ")));
+                    paragraph.clear();
+                }
+            }
+        }
+    }
+}
+
+fn push_sentences(segments: &mut Vec<Segment>, text: &str) {
+    let sentences: Vec<&str> = text.split(". ")
+        .flat_map(|s| s.split(".\n"))
+        .flat_map(|s| s.split("? "))
+        .flat_map(|s| s.split("!\n"))
+        .flat_map(|s| s.split("! "))
+        .collect();
+
+    for sentence in sentences {
+        let sentence = sentence.replace("\n", " ");
+        if !sentence.trim().is_empty() {
+            segments.push(Segment::new(SegmentKind::Sentence, sentence));
+        }
+    }
+}
+
+// Parse the items of a list segment. Nesting depth comes from the indentation
+// relative to the enclosing items; indented lines that aren't items continue the previous one
+pub fn parse_list_items(text: &str) -> Vec<ListItem> {
+    let list_re = list_item_regex();
+    let checkbox_re = Regex::new(r"^\[([ xX])\]\s+(.*)$").unwrap();
+    let mut items: Vec<ListItem> = Vec::new();
+    let mut indents: Vec<usize> = Vec::new();
+
+    for line in text.lines() {
+        let caps = match list_re.captures(line) {
+            Some(caps) => caps,
+            None => {
+                if let Some(last) = items.last_mut() {
+                    last.text.push(' ');
+                    last.text.push_str(line.trim());
+                }
+                continue;
+            }
+        };
+
+        let indent = caps[1].replace('\t', "    ").len();
+        while indents.last().map_or(false, |&last| last > indent) {
+            indents.pop();
+        }
+        if indents.last().map_or(true, |&last| last < indent) {
+            indents.push(indent);
+        }
+
+        let marker = match caps[2].trim_end_matches(['.', ')']).parse::<u32>() {
+            Ok(number) => ListMarker::Number(number),
+            Err(_) => ListMarker::Bullet,
+        };
+
+        let (checkbox, text) = match checkbox_re.captures(&caps[3]) {
+            Some(checkbox) => (Some(&checkbox[1] != " "), checkbox[2].to_string()),
+            None => (None, caps[3].to_string()),
+        };
+
+        items.push(ListItem {
+            depth: indents.len() - 1,
+            marker,
+            checkbox,
+            text,
+        });
+    }
+
+    items
+}
+
+// Number the title cards of the top heading level used in the note, for "section N of M".
+// Returns one entry per segment, Some((n, m)) for the counted headings
+pub fn section_numbers(segments: &[Segment]) -> Vec<Option<(usize, usize)>> {
+    let top_level = segments
+        .iter()
+        .filter_map(|segment| match segment.kind {
+            SegmentKind::Heading(level) => Some(level),
+            _ => None,
+        })
+        .min();
+
+    let total = segments
+        .iter()
+        .filter(|segment| Some(segment.kind) == top_level.map(SegmentKind::Heading))
+        .count();
+
+    let mut current = 0;
+    segments
+        .iter()
+        .map(|segment| match (segment.kind, top_level) {
+            (SegmentKind::Heading(level), Some(top)) if level == top => {
+                current += 1;
+                Some((current, total))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(segments: &[Segment]) -> Vec<SegmentKind> {
+        segments.iter().map(|segment| segment.kind).collect()
+    }
+
+    #[test]
+    fn splits_headings_lists_and_sentences() {
+        let segments = segment_text(
+            "# Title #\nFirst sentence. Second one\n- one\n- two\n## Part\nAfter.",
+            Segmentation::Sentence,
+        );
+        assert_eq!(
+            kinds(&segments),
+            [
+                SegmentKind::Heading(1),
+                SegmentKind::Sentence,
+                SegmentKind::Sentence,
+                SegmentKind::List,
+                SegmentKind::Heading(2),
+                SegmentKind::Sentence,
+            ]
+        );
+        assert_eq!(segments[0].text, "Title");
+        assert_eq!(segments[3].text, "- one\n- two");
+    }
+
+    #[test]
+    fn shows_h4_to_h6_as_plain_text() {
+        for heading in ["#### Minor", "##### Minor", "###### Minor ##"] {
+            let segments = segment_text(&format!("Before.\n{}\nAfter.", heading), Segmentation::Sentence);
+            assert_eq!(segments.len(), 3, "{}", heading);
+            assert_eq!(segments[1].kind, SegmentKind::Sentence, "{}", heading);
+            assert_eq!(segments[1].text, "Minor", "{}", heading);
+        }
+    }
+
+    #[test]
+    fn leaves_tags_and_deeper_markers_in_the_text() {
+        let segments = segment_text("#tag in a sentence\n####### Seven", Segmentation::Line);
+        assert_eq!(kinds(&segments), [SegmentKind::Sentence, SegmentKind::Sentence]);
+        assert_eq!(segments[0].text, "#tag in a sentence");
+        assert_eq!(segments[1].text, "####### Seven");
+    }
+
+    #[test]
+    fn nests_list_items_by_indentation() {
+        let items = parse_list_items("- a\n  - b\n    - c\n      more of c\n  - d\n- e");
+        let depths: Vec<usize> = items.iter().map(|item| item.depth).collect();
+        assert_eq!(depths, [0, 1, 2, 1, 0]);
+        assert_eq!(items[2].text, "c more of c");
+    }
+
+    #[test]
+    fn reads_numbers_and_checkboxes() {
+        let items = parse_list_items("1. first\n2) [x] done\n- [ ] todo\n* plain");
+        let markers: Vec<ListMarker> = items.iter().map(|item| item.marker).collect();
+        assert_eq!(
+            markers,
+            [ListMarker::Number(1), ListMarker::Number(2), ListMarker::Bullet, ListMarker::Bullet]
+        );
+        let checkboxes: Vec<Option<bool>> = items.iter().map(|item| item.checkbox).collect();
+        assert_eq!(checkboxes, [None, Some(true), Some(false), None]);
+        assert_eq!(items[1].text, "done");
+        assert_eq!(items[2].text, "todo");
+    }
+
+    #[test]
+    fn numbers_the_top_heading_level() {
+        let segments = segment_text("## One\nText.\n### Sub\n## Two", Segmentation::Sentence);
+        assert_eq!(section_numbers(&segments), [Some((1, 2)), None, None, Some((2, 2))]);
+    }
+
+    #[test]
+    fn numbers_nothing_without_headings() {
+        let segments = segment_text("Just text. And more", Segmentation::Sentence);
+        assert_eq!(section_numbers(&segments), [None, None]);
+    }
+}
@@ -0,0 +1,220 @@
+use regex::Regex;
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::file_utils;
+
+// Values available to `{{variable}}` placeholders in the note body: every top-level
+// frontmatter key plus the built-ins title, date, filename, segment and total
+pub struct TemplateContext<'a> {
+    frontmatter: &'a str,
+    filename: String,
+    today: (i64, u32, u32),
+}
+
+const MONTHS: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June",
+    "July", "August", "September", "October", "November", "December",
+];
+
+const WEEKDAYS: [&str; 7] = ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"];
+
+impl<'a> TemplateContext<'a> {
+    // `filename` is the note's file name, with or without the .md extension
+    pub fn new(frontmatter: &'a str, filename: &str) -> Self {
+        let days = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as i64 / 86_400)
+            .unwrap_or(0);
+        TemplateContext {
+            frontmatter,
+            filename: filename.trim_end_matches(".md").to_string(),
+            today: civil_from_days(days),
+        }
+    }
+
+    // `counters` is the segment number and segment count, unknown before the note is segmented
+    fn lookup(&self, name: &str, counters: Option<(usize, usize)>) -> Option<String> {
+        if let Some(value) = file_utils::frontmatter_value(self.frontmatter, name) {
+            return Some(value_to_string(&value));
+        }
+        match name {
+            "title" => Some(self.filename.clone()),
+            "filename" => Some(self.filename.clone()),
+            "date" => {
+                let (year, month, day) = self.today;
+                Some(format!("{:04}-{:02}-{:02}", year, month, day))
+            }
+            "segment" => counters.map(|(segment, _)| segment.to_string()),
+            "total" => counters.map(|(_, total)| total.to_string()),
+            _ => None,
+        }
+    }
+
+    // Replace the placeholders of the whole note body, except {{segment}} and {{total}} which
+    // are left for `expand`. This runs before redaction, so private values that come from the
+    // frontmatter are redacted like the rest of the text
+    pub fn expand_note(&self, text: &str) -> (String, Vec<String>) {
+        self.expand_with(text, None)
+    }
+
+    // Replace the placeholders in a segment. `segment` is 1-based. Returns the expanded text
+    // and a message for every placeholder that couldn't be filled in, which is left as written
+    pub fn expand(&self, text: &str, segment: usize, total: usize) -> (String, Vec<String>) {
+        self.expand_with(text, Some((segment, total)))
+    }
+
+    fn expand_with(&self, text: &str, counters: Option<(usize, usize)>) -> (String, Vec<String>) {
+        let re = Regex::new(r"\{\{\s*([A-Za-z0-9_-]+)\s*(?:\|\s*([^}]*?)\s*)?\}\}").unwrap();
+        let mut warnings = Vec::new();
+
+        let expanded = re.replace_all(text, |caps: &regex::Captures| {
+            let name = &caps[1];
+            let value = match self.lookup(name, counters) {
+                Some(value) => value,
+                None if counters.is_none() && (name == "segment" || name == "total") => {
+                    return caps[0].to_string();
+                }
+                None => {
+                    warnings.push(format!("Unknown template variable {{{{{}}}}}", name));
+                    return caps[0].to_string();
+                }
+            };
+
+            match caps.get(2) {
+                None => value,
+                Some(format) => match parse_date(&value) {
+                    Some(date) => format_date(date, format.as_str()),
+                    None => {
+                        warnings.push(format!("{{{{{}}}}} is not a date (YYYY-MM-DD), ignoring the format", name));
+                        value
+                    }
+                },
+            }
+        });
+
+        (expanded.into_owned(), warnings)
+    }
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Number(number) => match number.as_f64() {
+            Some(n) if n.fract() == 0.0 => format!("{}", n as i64),
+            _ => number.to_string(),
+        },
+        other => other.to_string(),
+    }
+}
+
+fn parse_date(value: &str) -> Option<(i64, u32, u32)> {
+    let re = Regex::new(r"^(\d{4})-(\d{2})-(\d{2})").unwrap();
+    let caps = re.captures(value.trim())?;
+    let month = caps[2].parse::<u32>().ok()?;
+    let day = caps[3].parse::<u32>().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some((caps[1].parse().ok()?, month, day))
+}
+
+// Format a date with Obsidian (moment.js) style tokens: YYYY, YY, MMMM, MMM, MM, M, DD, D, dddd, ddd.
+// Text in [brackets] is copied as is
+fn format_date((year, month, day): (i64, u32, u32), format: &str) -> String {
+    let tokens = Regex::new(r"\[[^\]]*\]|YYYY|YY|MMMM|MMM|MM|M|DD|D|dddd|ddd").unwrap();
+    let month_name = MONTHS[month as usize - 1];
+    let weekday = WEEKDAYS[weekday_index(year, month, day)];
+
+    tokens
+        .replace_all(format, |caps: &regex::Captures| match &caps[0] {
+            "YYYY" => format!("{:04}", year),
+            "YY" => format!("{:02}", year.rem_euclid(100)),
+            "MMMM" => month_name.to_string(),
+            "MMM" => month_name[..3].to_string(),
+            "MM" => format!("{:02}", month),
+            "M" => month.to_string(),
+            "DD" => format!("{:02}", day),
+            "D" => day.to_string(),
+            "dddd" => weekday.to_string(),
+            "ddd" => weekday[..3].to_string(),
+            literal => literal.trim_start_matches('[').trim_end_matches(']').to_string(),
+        })
+        .into_owned()
+}
+
+// Days since 1970-01-01 to a (year, month, day) date in the proleptic Gregorian calendar
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// 0 is Monday
+fn weekday_index(year: i64, month: u32, day: u32) -> usize {
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * m as i64 + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    // 1970-01-01 was a Thursday
+    (days + 3).rem_euclid(7) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRONTMATTER: &str = "author: Ada\npublished: 2024-03-09\nviews: 1200";
+
+    #[test]
+    fn fills_frontmatter_and_built_ins() {
+        let context = TemplateContext::new(FRONTMATTER, "My Note.md");
+        let (text, warnings) = context.expand("{{ author }} wrote {{title}} ({{views}}), {{segment}}/{{total}}", 2, 5);
+        assert_eq!(text, "Ada wrote My Note (1200), 2/5");
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn formats_dates() {
+        let context = TemplateContext::new(FRONTMATTER, "note");
+        let (text, _) = context.expand("{{published|dddd D MMMM YYYY [at] MM/YY}}", 1, 1);
+        assert_eq!(text, "Saturday 9 March 2024 at 03/24");
+    }
+
+    #[test]
+    fn leaves_unknown_keys_as_written() {
+        let context = TemplateContext::new(FRONTMATTER, "note");
+        let (text, warnings) = context.expand("Hi {{ nickname }} and {{missing|YYYY}}", 1, 1);
+        assert_eq!(text, "Hi {{ nickname }} and {{missing|YYYY}}");
+        assert_eq!(warnings, ["Unknown template variable {{nickname}}", "Unknown template variable {{missing}}"]);
+    }
+
+    #[test]
+    fn warns_about_date_formats_on_other_values() {
+        let context = TemplateContext::new(FRONTMATTER, "note");
+        let (text, warnings) = context.expand("{{author|YYYY}}", 1, 1);
+        assert_eq!(text, "Ada");
+        assert_eq!(warnings, ["{{author}} is not a date (YYYY-MM-DD), ignoring the format"]);
+    }
+
+    #[test]
+    fn note_expansion_leaves_segment_counters() {
+        let context = TemplateContext::new(FRONTMATTER, "note");
+        let (text, warnings) = context.expand_note("By {{author}}, part {{segment}} of {{ total }}, {{nope}}");
+        assert_eq!(text, "By Ada, part {{segment}} of {{ total }}, {{nope}}");
+        assert_eq!(warnings, ["Unknown template variable {{nope}}"]);
+
+        let (text, warnings) = context.expand(&text, 3, 4);
+        assert_eq!(text, "By Ada, part 3 of 4, {{nope}}");
+        assert_eq!(warnings.len(), 1);
+    }
+}
@@ -1,112 +1,878 @@
 use regex::Regex;
+use serde_json::Value;
 use std::error::Error;
 
-pub fn process_sentence(sentence: &str) -> String {
-    // Regex for footnotes syntax (e.g. ^[{footnote text}])
-    let re = Regex::new(r"\^\[\{(.*?)\}\]").unwrap();
-    let result = re.replace_all(sentence, |caps: &regex::Captures| {
-        format!("{{\\up1\\c&HFFFFFF&}}{}{{\\up0\\c&HFFFFFF&}}", &caps[1])
-    });
+use crate::file_utils;
+use crate::links;
+use crate::segmentation::{ListItem, ListMarker};
+use crate::render_profile::Canvas;
+use crate::theme::{self, Theme};
+
+pub fn process_sentence(sentence: &str, theme: &Theme) -> String {
+    let text = theme::ass_colour(&theme.text_colour);
+
+    // Footnotes are pulled out beforehand by footnotes::extract_footnotes and shown as side notes
 
-    // Regex for md image syntax, replace with blue bold IMG text
+    let result = process_inline_html(sentence, theme);
+
+    // Regex for md image syntax, replace with bold IMG text
     // For local images: ![[image.png]]
     let re = Regex::new(r"!\[\[(.*?)\]\]").unwrap();
-    let result = re.replace_all(&result, |caps: &regex::Captures| {
-        format!("{{\\c&H0000FF&}}{{\\b1\\c&HFFFFFF&}}LOCAL IMG{{\\b0\\c&HFFFFFF&}}")
+    let result = re.replace_all(&result, |_: &regex::Captures| {
+        format!("{{\\b1\\c{}}}LOCAL IMG{{\\b0\\c{}}}", text, text)
     });
     // For hosted images: ![image](https://example.com/image.png)
     let re = Regex::new(r"!\[(.*?)\]\((.*?)\)").unwrap();
-    let result = re.replace_all(&result, |caps: &regex::Captures| {
-        format!("{{\\c&H0000FF&}}{{\\b1\\c&HFFFFFF&}}HOSTED IMG{{\\b0\\c&HFFFFFF&}}")
+    let result = re.replace_all(&result, |_: &regex::Captures| {
+        format!("{{\\b1\\c{}}}HOSTED IMG{{\\b0\\c{}}}", text, text)
     });
 
-    // Regex for text within double square brackets, remove brackets and make the text purple
+    // Regex for text within double square brackets, remove brackets and use the link colour
     let re = Regex::new(r"\[\[(.*?)\]\]").unwrap();
+    let link = theme::ass_colour(&theme.link_colour);
     let result = re.replace_all(&result, |caps: &regex::Captures| {
-        let text = &caps[1];
-        if let Some((_, alias)) = text.split_once(" |") {
-            format!("{{\\c&H800080&}}{}{{\\c&HFFFFFF&}}", alias.trim())
+        let target = &caps[1];
+        if let Some((_, alias)) = target.split_once(" |") {
+            format!("{{\\c{}}}{}{{\\c{}}}", link, alias.trim(), text)
         } else {
-            format!("{{\\c&H800080&}}{}{{\\c&HFFFFFF&}}", text)
+            format!("{{\\c{}}}{}{{\\c{}}}", link, target, text)
         }
     });
 
-    // // Regex of md link syntax, replace with underlined blue text
-    // // Sytnax: [link text](https://example.com)
-    // let re = Regex::new(r"\[(.*?)\]\((.*?)\)").unwrap();
-    // let result = re.replace_all(&result, |caps: &regex::Captures| {
-    //     format!("{{\\ul1\\c&H0000FF&}}{}{{\\ul0\\c&HFFFFFF&}}", &caps[1])
-    // });
+    // Markdown links and bare URLs, show the link text underlined in the link colour.
+    // The URLs themselves go on the Sources end card
+    let result = links::style_links(&result, |link_text| {
+        format!("{{\\u1\\c{}}}{}{{\\u0\\c{}}}", link, link_text, text)
+    });
 
-    // Regex for text wrapped with underscores, make it italic and green, then remove the underscores
+    // Regex for text wrapped with underscores, make it italic in the italic colour, then remove the underscores
     let re = Regex::new(r"_([^_]+)_").unwrap();
+    let italic = theme::ass_colour(&theme.italic_colour);
     let result = re.replace_all(&result, |caps: &regex::Captures| {
-        format!("{{\\i1\\c&H00FF00&}}{}{{\\i0\\c&HFFFFFF&}}", &caps[1])
+        format!("{{\\i1\\c{}}}{}{{\\i0\\c{}}}", italic, &caps[1], text)
     });
 
-    // Regex for text wrapped with asterisks, make it bold and red, then remove the asterisks
+    // Regex for text wrapped with asterisks, make it bold in the bold colour, then remove the asterisks
     let re = Regex::new(r"\*([^*]+)\*").unwrap();
+    let bold = theme::ass_colour(&theme.bold_colour);
     let result = re.replace_all(&result, |caps: &regex::Captures| {
-        format!("{{\\b1\\c&HFF0000&}}{}{{\\b0\\c&HFFFFFF&}}", &caps[1])
+        format!("{{\\b1\\c{}}}{}{{\\b0\\c{}}}", bold, &caps[1], text)
     });
 
-    // Regex for text wrapped with single backticks, make it monospace with gray background, then remove the backticks
+    // Regex for text wrapped with single backticks, use the code colour, then remove the backticks
     let re = Regex::new(r"`([^`]+)`").unwrap();
+    let code = theme::ass_colour(&theme.code_colour);
     let result = re.replace_all(&result, |caps: &regex::Captures| {
-        format!("{{\\c&H808080&}}{}{{\\c&HFFFFFF&}}", &caps[1])
+        format!("{{\\c{}}}{}{{\\c{}}}", code, &caps[1], text)
+    });
+
+    // Regex for text wrapped with double equals signs, draw a box in the highlight colour behind it
+    let re = Regex::new(r"==([^=]+)==").unwrap();
+    let result = re.replace_all(&result, |caps: &regex::Captures| highlight(&caps[1], theme));
+
+    // Regex for text wrapped with double tildes, strike it through
+    let re = Regex::new(r"~~([^~]+)~~").unwrap();
+    let result = re.replace_all(&result, |caps: &regex::Captures| {
+        format!("{{\\s1}}{}{{\\s0}}", &caps[1])
+    });
+
+    // Regex for inline tags (e.g. #travel/japan), show them as chips
+    let re = Regex::new(r"(^|\s)#([A-Za-z_][\w/-]*)").unwrap();
+    let tag_background = theme::ass_colour(&theme.tag_colour);
+    let tag_text = theme::ass_colour(&theme.tag_text_colour);
+    let outline = theme::ass_colour(&theme.outline_colour);
+    let result = re.replace_all(&result, |caps: &regex::Captures| {
+        format!(
+            "{}{{\\bord4\\3c{}\\c{}}}#{}{{\\bord{}\\3c{}\\c{}}}",
+            &caps[1], tag_background, tag_text, &caps[2], theme.outline, outline, text
+        )
     });
 
     result.into_owned()
 }
 
+// A box-style highlight: a thick outline in the highlight colour around dark text
+fn highlight(content: &str, theme: &Theme) -> String {
+    format!(
+        "{{\\bord5\\3c{}\\c{}}}{}{{\\bord{}\\3c{}\\c{}}}",
+        theme::ass_colour(&theme.highlight_colour),
+        theme::ass_colour(&theme.highlight_text_colour),
+        content,
+        theme.outline,
+        theme::ass_colour(&theme.outline_colour),
+        theme::ass_colour(&theme.text_colour)
+    )
+}
+
+const SUPERSCRIPTS: &[(char, char)] = &[
+    ('0', '⁰'), ('1', '¹'), ('2', '²'), ('3', '³'), ('4', '⁴'), ('5', '⁵'), ('6', '⁶'), ('7', '⁷'),
+    ('8', '⁸'), ('9', '⁹'), ('+', '⁺'), ('-', '⁻'), ('=', '⁼'), ('(', '⁽'), (')', '⁾'), ('n', 'ⁿ'),
+    ('i', 'ⁱ'),
+];
+
+const SUBSCRIPTS: &[(char, char)] = &[
+    ('0', '₀'), ('1', '₁'), ('2', '₂'), ('3', '₃'), ('4', '₄'), ('5', '₅'), ('6', '₆'), ('7', '₇'),
+    ('8', '₈'), ('9', '₉'), ('+', '₊'), ('-', '₋'), ('=', '₌'), ('(', '₍'), (')', '₎'), ('a', 'ₐ'),
+    ('e', 'ₑ'), ('o', 'ₒ'), ('x', 'ₓ'), ('h', 'ₕ'), ('k', 'ₖ'), ('l', 'ₗ'), ('m', 'ₘ'), ('n', 'ₙ'),
+    ('p', 'ₚ'), ('s', 'ₛ'), ('t', 'ₜ'),
+];
+
+// Superscript or subscript text. libass can't move text off the baseline, so the raised or
+// lowered Unicode forms are used when every character has one (x², H₂O). Anything else is
+// drawn smaller on the baseline with the `scale` tags
+fn shifted(text: &str, forms: &[(char, char)], scale: &str) -> String {
+    let mapped: Option<String> = text
+        .chars()
+        .map(|c| forms.iter().find(|(plain, _)| *plain == c).map(|(_, form)| *form))
+        .collect();
+    match mapped {
+        Some(mapped) if !mapped.is_empty() => mapped,
+        _ => format!("{{{}}}{}{{\\fscx100\\fscy100}}", scale, text),
+    }
+}
+
+// Translate the inline HTML that shows up in notes into ASS and strip anything else,
+// so raw tags never end up on screen
+fn process_inline_html(sentence: &str, theme: &Theme) -> String {
+    // Embedded elements are dropped together with their content
+    let re = Regex::new(r"(?is)<(iframe|script|style|video|audio|object)\b[^>]*>.*?</\s*(iframe|script|style|video|audio|object)\s*>").unwrap();
+    let result = re.replace_all(sentence, "");
+
+    let re = Regex::new(r"(?i)<br\s*/?>").unwrap();
+    let result = re.replace_all(&result, "\\N");
+
+    let re = Regex::new(r"(?is)<u>(.*?)</u>").unwrap();
+    let result = re.replace_all(&result, "{\\u1}$1{\\u0}");
 
+    let re = Regex::new(r"(?is)<mark>(.*?)</mark>").unwrap();
+    let result = re.replace_all(&result, |caps: &regex::Captures| highlight(&caps[1], theme));
+
+    let re = Regex::new(r"(?is)<sup>(.*?)</sup>").unwrap();
+    let result = re.replace_all(&result, |caps: &regex::Captures| shifted(&caps[1], SUPERSCRIPTS, "\\fscx60\\fscy60"));
+
+    let re = Regex::new(r"(?is)<sub>(.*?)</sub>").unwrap();
+    let result = re.replace_all(&result, |caps: &regex::Captures| {
+        // Flatter than superscripts, so the top sits lower on the line
+        shifted(&caps[1], SUBSCRIPTS, "\\fscx60\\fscy45")
+    });
 
+    // Any other tag is removed, keeping its text
+    let re = Regex::new(r"</?[a-zA-Z][^>]*>").unwrap();
+    re.replace_all(&result, "").into_owned()
+}
+
+// Karaoke lights up sentences and heading cards word by word. List segments already reveal
+// their items one at a time, so they are left as they are
+#[derive(Clone, Copy)]
+pub enum KaraokeStyle {
+    // Each word switches colour instantly (\k)
+    Step,
+    // Each word fills from left to right (\kf)
+    Fill,
+}
+
+impl KaraokeStyle {
+    // Read the `karaoke` frontmatter key: `true`/`step` for \k, `fill` for \kf
+    pub fn from_frontmatter(frontmatter: &str) -> Option<Self> {
+        match file_utils::frontmatter_value(frontmatter, "karaoke")? {
+            Value::Bool(true) => Some(KaraokeStyle::Step),
+            Value::String(style) => match style.to_lowercase().as_str() {
+                "step" | "k" => Some(KaraokeStyle::Step),
+                "fill" | "kf" => Some(KaraokeStyle::Fill),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn tag(&self) -> &'static str {
+        match self {
+            KaraokeStyle::Step => "k",
+            KaraokeStyle::Fill => "kf",
+        }
+    }
+}
 
+// Turn typeset text (process_sentence, then typography::typeset) into karaoke text where each
+// word lights up in turn. Word durations are weighted by syllable count and add up to the
+// segment duration. Line breaks are kept between the words they separate
+pub fn karaoke_sentence(sentence: &str, duration: f64, style: KaraokeStyle) -> String {
+    let words = split_karaoke_words(sentence);
+    if words.is_empty() {
+        return sentence.to_string();
+    }
 
-pub fn generate_ass_content_bottom(sentence: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
-    let ass_content = format!(
+    let total_cs = (duration * 100.0).round() as i64;
+    let weights: Vec<usize> = words
+        .iter()
+        .map(|(_, word)| estimate_syllables(&strip_ass_tags(word)))
+        .collect();
+    let total_weight: usize = weights.iter().sum();
+    let mut word_cs: Vec<i64> = weights
+        .iter()
+        .map(|w| total_cs * *w as i64 / total_weight as i64)
+        .collect();
+    // Give the rounding remainder to the last word so the sum matches the segment exactly
+    let assigned: i64 = word_cs.iter().sum();
+    if let Some(last) = word_cs.last_mut() {
+        *last += total_cs - assigned;
+    }
+
+    // Unspoken words are dimmed through the secondary colour
+    let mut result = String::from("{\\2c&H808080&}");
+    for ((separator, word), cs) in words.iter().zip(word_cs.iter()) {
+        result.push_str(separator);
+        result.push_str(&format!("{{\\{}{}}}{}", style.tag(), cs, word));
+    }
+    result
+}
+
+// Split ASS text into words, keeping override blocks attached to the word they precede.
+// Each word comes with what separated it from the previous one: a space or \N line breaks
+fn split_karaoke_words(text: &str) -> Vec<(String, String)> {
+    let mut words: Vec<(String, String)> = Vec::new();
+    let mut separator = String::new();
+    let mut current = String::new();
+    let mut in_tag = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let breaks_line = c == '\\' && !in_tag && chars.peek() == Some(&'N');
+        let ends_word = breaks_line || (c.is_whitespace() && !in_tag);
+        if ends_word {
+            if !strip_ass_tags(&current).is_empty() {
+                words.push((std::mem::take(&mut separator), std::mem::take(&mut current)));
+            }
+            if breaks_line {
+                chars.next();
+                if separator == " " {
+                    separator.clear();
+                }
+                separator.push_str("\\N");
+            } else if separator.is_empty() && !words.is_empty() {
+                separator.push(' ');
+            }
+            continue;
+        }
+        match c {
+            '{' => in_tag = true,
+            '}' => in_tag = false,
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        if strip_ass_tags(&current).is_empty() {
+            // Trailing override tags belong to the last word
+            match words.last_mut() {
+                Some((_, last)) => last.push_str(&current),
+                None => words.push((separator, current)),
+            }
+        } else {
+            words.push((separator, current));
+        }
+    }
+    words
+}
+
+pub fn strip_ass_tags(text: &str) -> String {
+    let re = Regex::new(r"\{[^}]*\}").unwrap();
+    re.replace_all(text, "").to_string()
+}
+
+// Rough syllable count: groups of vowels, minus a silent trailing "e"
+fn estimate_syllables(word: &str) -> usize {
+    let word = word.to_lowercase();
+    let letters: Vec<char> = word.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.is_empty() {
+        return 1;
+    }
+
+    let is_vowel = |c: char| "aeiouy".contains(c);
+    let mut syllables = 0;
+    let mut previous_vowel = false;
+    for &c in &letters {
+        let vowel = is_vowel(c);
+        if vowel && !previous_vowel {
+            syllables += 1;
+        }
+        previous_vowel = vowel;
+    }
+    if syllables > 1 && letters.ends_with(&['e']) && !letters.ends_with(&['l', 'e']) {
+        syllables -= 1;
+    }
+
+    syllables.max(1)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Edge {
+    Left,
+    Right,
+    Top,
+    Bottom,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TextEffect {
+    // Text simply appears and disappears
+    None,
+    Fade,
+    Slide(Edge),
+    Typewriter,
+    Pop,
+    BlurIn,
+}
+
+// Length of entrance and exit animations, in seconds
+const EFFECT_IN: f64 = 0.4;
+const EFFECT_OUT: f64 = 0.3;
+
+impl TextEffect {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "none" => Some(TextEffect::None),
+            "fade" => Some(TextEffect::Fade),
+            "slide" | "slide-left" => Some(TextEffect::Slide(Edge::Left)),
+            "slide-right" => Some(TextEffect::Slide(Edge::Right)),
+            "slide-top" => Some(TextEffect::Slide(Edge::Top)),
+            "slide-bottom" => Some(TextEffect::Slide(Edge::Bottom)),
+            "typewriter" => Some(TextEffect::Typewriter),
+            "pop" => Some(TextEffect::Pop),
+            "blur" | "blur-in" => Some(TextEffect::BlurIn),
+            _ => None,
+        }
+    }
+
+    // The effect for a note: the `effect` frontmatter key, then the TEXT_EFFECT env variable
+    pub fn from_frontmatter(frontmatter: &str) -> Self {
+        let from_note = match file_utils::frontmatter_value(frontmatter, "effect") {
+            Some(Value::String(name)) => TextEffect::from_name(&name),
+            _ => None,
+        };
+        from_note
+            .or_else(|| std::env::var("TEXT_EFFECT").ok().and_then(|name| TextEffect::from_name(&name)))
+            .unwrap_or(TextEffect::None)
+    }
+}
+
+// Pull an inline effect override (e.g. `[effect:: typewriter]`) out of a segment
+pub fn extract_effect_override(sentence: &str) -> (String, Option<TextEffect>) {
+    let re = Regex::new(r"\[effect::\s*([a-zA-Z-]+)\s*\]").unwrap();
+    let effect = re
+        .captures(sentence)
+        .and_then(|caps| TextEffect::from_name(&caps[1]));
+    let sentence = re.replace_all(sentence, "").trim().to_string();
+    (sentence, effect)
+}
+
+// A single timed line of text in the Events section
+pub struct AssEvent {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+// Build the events for a line of text with the given effect.
+// `anchor` is the position of the text on the canvas for the given ASS alignment
+pub fn effect_events(
+    text: &str,
+    duration: f64,
+    effect: TextEffect,
+    anchor: (i32, i32),
+    alignment: u8,
+    canvas: Canvas,
+) -> Vec<AssEvent> {
+    let (x, y) = anchor;
+    let event = |start: f64, end: f64, text: String| AssEvent { start, end, text };
+    let fade_in_ms = (EFFECT_IN * 1000.0) as i64;
+    let fade_out_ms = (EFFECT_OUT * 1000.0) as i64;
+    let end_ms = (duration * 1000.0) as i64;
+
+    // Text its effect doesn't move is placed by the style's alignment and margins, and middle
+    // alignment centres it on the whole canvas. With a safe zone it's pinned to the anchor
+    let text = match effect {
+        TextEffect::Slide(_) | TextEffect::Pop => text.to_string(),
+        _ if canvas.safe.is_zero() => text.to_string(),
+        _ => format!("{{\\an{}\\pos({},{})}}{}", alignment, x, y, text),
+    };
+    let text = text.as_str();
+
+    match effect {
+        TextEffect::None => vec![event(0.0, duration, text.to_string())],
+        TextEffect::Fade => vec![event(
+            0.0,
+            duration,
+            format!("{{\\fad({},{})}}{}", fade_in_ms, fade_out_ms, text),
+        )],
+        TextEffect::Slide(edge) => {
+            let (from_x, from_y) = match edge {
+                Edge::Left => (x - canvas.width as i32, y),
+                Edge::Right => (x + canvas.width as i32, y),
+                Edge::Top => (x, y - canvas.height as i32),
+                Edge::Bottom => (x, y + canvas.height as i32),
+            };
+            let hold_end = (duration - EFFECT_OUT).max(EFFECT_IN);
+            vec![
+                event(
+                    0.0,
+                    EFFECT_IN,
+                    format!("{{\\an{}\\move({},{},{},{})}}{}", alignment, from_x, from_y, x, y, text),
+                ),
+                event(EFFECT_IN, hold_end, format!("{{\\an{}\\pos({},{})}}{}", alignment, x, y, text)),
+                event(
+                    hold_end,
+                    duration,
+                    format!("{{\\an{}\\move({},{},{},{})}}{}", alignment, x, y, from_x, from_y, text),
+                ),
+            ]
+        }
+        TextEffect::Pop => vec![event(
+            0.0,
+            duration,
+            format!(
+                "{{\\an{}\\pos({},{})\\fscx0\\fscy0\\t(0,{},\\fscx110\\fscy110)\\t({},{},\\fscx100\\fscy100)\\t({},{},\\fscx0\\fscy0)}}{}",
+                alignment,
+                x,
+                y,
+                fade_in_ms * 2 / 3,
+                fade_in_ms * 2 / 3,
+                fade_in_ms,
+                end_ms - fade_out_ms,
+                end_ms,
+                text
+            ),
+        )],
+        TextEffect::BlurIn => vec![event(
+            0.0,
+            duration,
+            format!(
+                "{{\\blur20\\alpha&HFF&\\t(0,{},\\blur0\\alpha&H00&)\\t({},{},\\blur20\\alpha&HFF&)}}{}",
+                fade_in_ms,
+                end_ms - fade_out_ms,
+                end_ms,
+                text
+            ),
+        )],
+        TextEffect::Typewriter => typewriter_events(text, duration),
+    }
+}
+
+// One event per character: the typed prefix is visible and the rest is kept transparent,
+// so the line layout doesn't shift while typing
+fn typewriter_events(text: &str, duration: f64) -> Vec<AssEvent> {
+    let visible_count = visible_units(text);
+    if visible_count == 0 {
+        return vec![AssEvent { start: 0.0, end: duration, text: text.to_string() }];
+    }
+
+    let typing_time = (visible_count as f64 * 0.05).min(duration * 0.6);
+    let step = typing_time / visible_count as f64;
+    let mut events = Vec::new();
+
+    for typed in 1..=visible_count {
+        let start = step * (typed - 1) as f64;
+        let end = if typed == visible_count { duration } else { step * typed as f64 };
+        events.push(AssEvent {
+            start,
+            end,
+            text: hide_after_visible_chars(text, typed),
+        });
+    }
+    events
+}
+
+// Line breaks and hard spaces are written as two characters but are one unit on screen,
+// and an override block between the two would break them
+fn is_escape(c: char, next: Option<&char>) -> bool {
+    c == '\\' && matches!(next, Some('N' | 'n' | 'h'))
+}
+
+// Characters outside override blocks, each \N, \n or \h counting once
+fn visible_units(text: &str) -> usize {
+    let plain: Vec<char> = strip_ass_tags(text).chars().collect();
+    let escapes = plain.windows(2).filter(|pair| is_escape(pair[0], Some(&pair[1]))).count();
+    plain.len() - escapes
+}
+
+// Insert a transparency override after the first `count` visible characters
+fn hide_after_visible_chars(text: &str, count: usize) -> String {
+    let mut result = String::new();
+    let mut seen = 0;
+    let mut in_tag = false;
+    let mut hidden = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if !in_tag && c != '{' && !hidden && seen == count {
+            result.push_str("{\\alpha&HFF&}");
+            hidden = true;
+        }
+        match c {
+            '{' => in_tag = true,
+            '}' => in_tag = false,
+            _ if !in_tag => seen += 1,
+            _ => {}
+        }
+        result.push(c);
+        if !in_tag && is_escape(c, chars.peek()) {
+            result.extend(chars.next());
+        }
+    }
+    result
+}
+
+fn dialogue_lines(events: &[AssEvent], margins: &str) -> String {
+    events
+        .iter()
+        .map(|event| {
+            format!(
+                "Dialogue: 0,{},{},Default,,{},,{}",
+                format_ass_time(event.start),
+                format_ass_time(event.end),
+                margins,
+                event.text
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n        ")
+}
+
+// Footnotes appear in the lower right corner partway through the segment
+fn footnote_lines(footnotes: &[String], duration: f64) -> String {
+    if footnotes.is_empty() {
+        return String::new();
+    }
+
+    let start = duration * 0.4;
+    let text = footnotes.join("\\N");
+    format!(
+        "\n        Dialogue: 1,{},{},Footnote,,0,0,0,,{{\\fad(200,0)}}{}",
+        format_ass_time(start),
+        format_ass_time(duration),
+        text
+    )
+}
+
+fn footnote_style_line(theme: &Theme, canvas: Canvas) -> String {
+    format!(
+        "Style: Footnote, {}, {}, {}, {}, {}, {}, 0, -1, 0, 0, 100, 100, 0, 0, 1, {}, 0, 3, {}, {}, {}, 1",
+        theme.font,
+        (theme.font_size * 3 / 5).max(12),
+        theme::ass_style_colour(&theme.footnote_colour),
+        theme::ass_style_colour(&theme.footnote_colour),
+        theme::ass_style_colour(&theme.outline_colour),
+        theme::ass_style_colour(&theme.shadow_colour),
+        theme.outline,
+        theme.margin_l.max(20).max(canvas.safe.left),
+        theme.margin_r.max(20).max(canvas.safe.right),
+        theme.margin_v.max(20).max(canvas.safe.bottom)
+    )
+}
+
+// Format seconds as an ASS timestamp (H:MM:SS.cc)
+pub fn format_ass_time(seconds: f64) -> String {
+    let centiseconds = (seconds.max(0.0) * 100.0).round() as u64;
+    let hours = centiseconds / 360_000;
+    let minutes = (centiseconds / 6_000) % 60;
+    let secs = (centiseconds / 100) % 60;
+    let cs = centiseconds % 100;
+    format!("{}:{:02}:{:02}.{:02}", hours, minutes, secs, cs)
+}
+
+// The [V4+ Styles] Style line for a theme, at the given font size and alignment
+fn theme_style_line(theme: &Theme, font_size: u32, alignment: u8, canvas: Canvas) -> String {
+    let primary = theme::ass_style_colour(&theme.text_colour);
+    let (margin_l, margin_r, margin_v) = theme.margins_within(canvas);
+    format!(
+        "Style: Default, {}, {}, {}, {}, {}, {}, -1, 0, 0, 0, 100, 100, 0, 0, 1, {}, {}, {}, {}, {}, {}, 1",
+        theme.font,
+        font_size,
+        primary,
+        primary,
+        theme::ass_style_colour(&theme.outline_colour),
+        theme::ass_style_colour(&theme.shadow_colour),
+        theme.outline,
+        theme.shadow,
+        alignment,
+        margin_l,
+        margin_r,
+        margin_v
+    )
+}
+
+// Everything before the dialogue lines: the canvas size, the theme's Default style at the
+// given font size and alignment, and the Footnote style
+fn ass_header(theme: &Theme, font_size: u32, alignment: u8, canvas: Canvas) -> String {
+    format!(
         r#"[Script Info]
         Title: Default Aegisub file
         ScriptType: v4.00+
         WrapStyle: 0
-        PlayResX: 1280
-        PlayResY: 720
+        PlayResX: {}
+        PlayResY: {}
         ScaledBorderAndShadow: yes
         YCbCr Matrix: None
 
         [V4+ Styles]
         Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
-        Style: Default, Vera, 28, &HFFFFFF, &HFFFFFF, &H000000, &H000000, -1, 0, 0, 0, 100, 100, 0, 0, 1, 1, 1, 2, 10, 10, 30, 1
+        {}
+        {}
 
         [Events]
         Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
-        Dialogue: 0,0:00:00.00,0:00:05.00,Default,,10,10,30,,{}"#,
-        sentence
-    );
+        "#,
+        canvas.width,
+        canvas.height,
+        theme_style_line(theme, font_size, alignment, canvas),
+        footnote_style_line(theme, canvas)
+    )
+}
 
-    Ok(ass_content)
+pub fn generate_ass_content_bottom(
+    sentence: &str,
+    duration: f64,
+    effect: TextEffect,
+    theme: &Theme,
+    footnotes: &[String],
+    canvas: Canvas,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    Ok(format!(
+        "{}{}{}",
+        ass_header(theme, theme.font_size, theme.alignment, canvas),
+        dialogue_lines(
+            &effect_events(sentence, duration, effect, theme.anchor(canvas), theme.alignment, canvas),
+            &theme.margins(canvas)
+        ),
+        footnote_lines(footnotes, duration)
+    ))
 }
 
-pub fn generate_ass_content_centered(sentence: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
-    let ass_content = format!(
-        r#"[Script Info]
-        Title: Default Aegisub file
-        ScriptType: v4.00+
-        WrapStyle: 0
-        PlayResX: 1280
-        PlayResY: 720
-        ScaledBorderAndShadow: yes
-        YCbCr Matrix: None
-        
-        [V4+ Styles]
-        Format: Name, Fontname, Fontsize, PrimaryColour, Alignment
-        Style: Default, Vera, 42, &HFFFFFF, 8
-        
-        [Events]
-        Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
-        Dialogue: 0,0:00:00.00,0:00:05.00,Default,,320,320,355,,{}"#,
-        sentence
-    );
+// A list where items appear one at a time with their own duration.
+// Item text must already have gone through process_sentence
+pub fn generate_ass_content_list(
+    items: &[ListItem],
+    item_durations: &[f64],
+    theme: &Theme,
+    footnotes: &[String],
+    canvas: Canvas,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let duration: f64 = item_durations.iter().sum();
+    Ok(format!(
+        "{}{}{}",
+        ass_header(theme, theme.font_size, 4, canvas),
+        dialogue_lines(&list_events(items, item_durations, canvas), &theme.margins(canvas)),
+        footnote_lines(footnotes, duration)
+    ))
+}
+
+// Left and right margins of heading cards, also used to typeset them
+pub fn heading_margins(canvas: Canvas) -> (u32, u32) {
+    let margin = canvas.width / 16;
+    (margin.max(canvas.safe.left), margin.max(canvas.safe.right))
+}
+
+pub fn heading_font_size(level: usize, theme: &Theme) -> u32 {
+    let scale = match level {
+        1 => 3.0,
+        2 => 2.4,
+        _ => 2.0,
+    };
+    (theme.font_size as f64 * scale).round() as u32
+}
 
-    Ok(ass_content)
-}
\ No newline at end of file
+// A title card for an H1-H3 heading, centred and sized by level,
+// with an optional "Section N of M" line above it
+#[allow(clippy::too_many_arguments)]
+pub fn generate_ass_content_heading(
+    heading: &str,
+    level: usize,
+    section: Option<(usize, usize)>,
+    duration: f64,
+    effect: TextEffect,
+    theme: &Theme,
+    footnotes: &[String],
+    canvas: Canvas,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let font_size = heading_font_size(level, theme);
+    let (margin_l, margin_r) = heading_margins(canvas);
+
+    let text = match section {
+        Some((current, total)) => format!(
+            "{{\\fs{}\\b0}}Section {} of {}{{\\fs{}\\b1}}\\N{}",
+            theme.font_size,
+            current,
+            total,
+            font_size,
+            heading
+        ),
+        None => heading.to_string(),
+    };
+
+    Ok(format!(
+        "{}{}{}",
+        ass_header(theme, font_size, 5, canvas),
+        dialogue_lines(
+            &effect_events(&text, duration, effect, canvas.centre(), 5, canvas),
+            &format!("{},{},0", margin_l, margin_r)
+        ),
+        footnote_lines(footnotes, duration)
+    ))
+}
+
+// How long a ticked checkbox stays empty after its item appears, in seconds
+const CHECKBOX_TICK_DELAY: f64 = 0.4;
+
+// One event per change in the list: an item appearing or its checkbox being ticked.
+// Items that haven't appeared yet are drawn transparent so the block doesn't move
+fn list_events(items: &[ListItem], item_durations: &[f64], canvas: Canvas) -> Vec<AssEvent> {
+    // (time, number of visible items, number of visible items whose checkbox is ticked)
+    let mut states: Vec<(f64, usize, usize)> = Vec::new();
+    let mut time = 0.0;
+    for (i, (item, item_duration)) in items.iter().zip(item_durations).enumerate() {
+        states.push((time, i + 1, i));
+        if item.checkbox == Some(true) {
+            states.push((time + CHECKBOX_TICK_DELAY.min(item_duration / 2.0), i + 1, i + 1));
+        }
+        time += item_duration;
+    }
+
+    let mut events = Vec::new();
+    for (k, &(start, visible, ticked)) in states.iter().enumerate() {
+        let end = states.get(k + 1).map_or(time, |next| next.0);
+        events.push(AssEvent {
+            start,
+            end,
+            text: format!(
+                "{{\\an4\\pos({},{})}}{}",
+                (canvas.width / 8).max(canvas.safe.left),
+                canvas.centre().1,
+                list_text(items, visible, ticked)
+            ),
+        });
+    }
+    events
+}
+
+fn list_text(items: &[ListItem], visible: usize, ticked: usize) -> String {
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let hidden = if i == visible { "{\\alpha&HFF&}" } else { "" };
+            let indent = "\\h\\h\\h\\h".repeat(item.depth);
+            let marker = match (item.checkbox, item.marker) {
+                (Some(true), _) if i < ticked => "☑".to_string(),
+                (Some(_), _) => "☐".to_string(),
+                (None, ListMarker::Number(number)) => format!("{}.", number),
+                (None, ListMarker::Bullet) => match item.depth {
+                    0 => "•".to_string(),
+                    1 => "◦".to_string(),
+                    _ => "▪".to_string(),
+                },
+            };
+            format!("{}{}{}\\h{}", hidden, indent, marker, item.text)
+        })
+        .collect::<Vec<String>>()
+        .join("\\N")
+}
+
+pub fn generate_ass_content_centered(
+    sentence: &str,
+    duration: f64,
+    effect: TextEffect,
+    theme: &Theme,
+    footnotes: &[String],
+    canvas: Canvas,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    Ok(format!(
+        "{}{}{}",
+        ass_header(theme, theme.font_size * 3 / 2, 5, canvas),
+        dialogue_lines(
+            &effect_events(sentence, duration, effect, canvas.centre(), 5, canvas),
+            &format!(
+                "{},{},0",
+                (canvas.width / 4).max(canvas.safe.left),
+                (canvas.width / 4).max(canvas.safe.right)
+            )
+        ),
+        footnote_lines(footnotes, duration)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn superscripts_and_subscripts_differ() {
+        let theme = Theme::default();
+        assert_eq!(process_inline_html("x<sup>2</sup> and H<sub>2</sub>O", &theme), "x² and H₂O");
+        let sup = process_inline_html("1<sup>st</sup>", &theme);
+        let sub = process_inline_html("v<sub>avg</sub>", &theme);
+        assert_eq!(sup, "1{\\fscx60\\fscy60}st{\\fscx100\\fscy100}");
+        assert_eq!(sub, "v{\\fscx60\\fscy45}avg{\\fscx100\\fscy100}");
+    }
+
+    #[test]
+    fn typewriter_keeps_line_breaks_and_hard_spaces_whole() {
+        let text = "ab\\Nc\\hd\\ne";
+        let events = typewriter_events(text, 4.0);
+        // a, b, \N, c, \h, d, \n, e
+        assert_eq!(events.len(), 8);
+        for event in &events {
+            assert!(!event.text.contains("\\{"), "override block inside an escape: {}", event.text);
+            assert_eq!(strip_ass_tags(&event.text), text);
+        }
+        assert_eq!(events[1].text, "ab{\\alpha&HFF&}\\Nc\\hd\\ne");
+        assert_eq!(events[2].text, "ab\\N{\\alpha&HFF&}c\\hd\\ne");
+        assert_eq!(events[7].text, text);
+    }
+
+    #[test]
+    fn typewriter_skips_override_blocks() {
+        let events = typewriter_events("{\\b1}ab{\\b0}\\Nc", 2.0);
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0].text, "{\\b1}a{\\alpha&HFF&}b{\\b0}\\Nc");
+        assert_eq!(events[2].text, "{\\b1}ab{\\b0}\\N{\\alpha&HFF&}c");
+    }
+
+    fn karaoke_durations(text: &str) -> Vec<i64> {
+        Regex::new(r"\{\\kf?(\d+)\}")
+            .unwrap()
+            .captures_iter(text)
+            .map(|caps| caps[1].parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn karaoke_durations_add_up_to_the_segment() {
+        for (sentence, duration) in [("One", 1.0), ("A sentence with several words in it", 3.33), ("Syllables everywhere, unbelievably", 7.77)] {
+            let karaoke = karaoke_sentence(sentence, duration, KaraokeStyle::Step);
+            let durations = karaoke_durations(&karaoke);
+            assert_eq!(durations.len(), sentence.split(' ').count(), "{}", karaoke);
+            assert_eq!(durations.iter().sum::<i64>(), (duration * 100.0).round() as i64, "{}", karaoke);
+        }
+    }
+
+    #[test]
+    fn karaoke_weights_words_by_syllables() {
+        let karaoke = karaoke_sentence("cat elephant", 4.0, KaraokeStyle::Fill);
+        assert_eq!(karaoke, "{\\2c&H808080&}{\\kf100}cat {\\kf300}elephant");
+    }
+
+    #[test]
+    fn karaoke_keeps_typeset_line_breaks_and_tags() {
+        let karaoke = karaoke_sentence("{\\q2}one {\\b1}two\\Nthree 5\\hkm{\\b0}", 2.0, KaraokeStyle::Step);
+        assert_eq!(strip_ass_tags(&karaoke), "one two\\Nthree 5\\hkm");
+        assert!(karaoke.contains("{\\b1}two\\N{\\k"), "{}", karaoke);
+        assert_eq!(karaoke_durations(&karaoke).len(), 4);
+        assert_eq!(karaoke_durations(&karaoke).iter().sum::<i64>(), 200);
+    }
+
+    #[test]
+    fn visible_units_count_escapes_once() {
+        assert_eq!(visible_units("{\\i1}one\\Ntwo\\hthree"), 13);
+        assert_eq!(visible_units(""), 0);
+    }
+}
@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::file_utils;
+use crate::render_profile::Canvas;
+
+// A named look for the video, stored as `<name>.json` in the themes directory.
+// Colours are CSS-style hex strings (#rrggbb) so they can be edited from the frontend
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Theme {
+    pub name: String,
+    pub font: String,
+    pub font_size: u32,
+    pub text_colour: String,
+    pub bold_colour: String,
+    pub italic_colour: String,
+    pub code_colour: String,
+    pub link_colour: String,
+    pub footnote_colour: String,
+    pub highlight_colour: String,
+    pub highlight_text_colour: String,
+    pub tag_colour: String,
+    pub tag_text_colour: String,
+    pub outline_colour: String,
+    pub shadow_colour: String,
+    pub outline: f32,
+    pub shadow: f32,
+    pub margin_l: u32,
+    pub margin_r: u32,
+    pub margin_v: u32,
+    // ASS numpad alignment (1-9), 2 is bottom centre
+    pub alignment: u8,
+    pub background: String,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            name: "default".to_string(),
+            font: "Vera".to_string(),
+            font_size: 28,
+            text_colour: "#ffffff".to_string(),
+            bold_colour: "#ff0000".to_string(),
+            italic_colour: "#00ff00".to_string(),
+            code_colour: "#808080".to_string(),
+            link_colour: "#800080".to_string(),
+            footnote_colour: "#ffffff".to_string(),
+            highlight_colour: "#ffd400".to_string(),
+            highlight_text_colour: "#000000".to_string(),
+            tag_colour: "#3a3a8c".to_string(),
+            tag_text_colour: "#ffffff".to_string(),
+            outline_colour: "#000000".to_string(),
+            shadow_colour: "#000000".to_string(),
+            outline: 1.0,
+            shadow: 1.0,
+            margin_l: 10,
+            margin_r: 10,
+            margin_v: 30,
+            alignment: 2,
+            background: "#000000".to_string(),
+        }
+    }
+}
+
+impl Theme {
+    // Left, right and vertical margins on the canvas, pushed out of its safe zone
+    pub fn margins_within(&self, canvas: Canvas) -> (u32, u32, u32) {
+        let safe = canvas.safe;
+        let margin_v = match self.alignment {
+            7..=9 => self.margin_v.max(safe.top),
+            4..=6 => self.margin_v,
+            _ => self.margin_v.max(safe.bottom),
+        };
+        (self.margin_l.max(safe.left), self.margin_r.max(safe.right), margin_v)
+    }
+
+    // Position of the text anchor on the canvas for the theme's alignment
+    pub fn anchor(&self, canvas: Canvas) -> (i32, i32) {
+        let (width, height) = (canvas.width as i32, canvas.height as i32);
+        let (margin_l, margin_r, margin_v) = self.margins_within(canvas);
+        let (margin_l, margin_r, margin_v) = (margin_l as i32, margin_r as i32, margin_v as i32);
+        let x = match (self.alignment.max(1) - 1) % 3 {
+            0 => margin_l,
+            1 => width / 2 + (margin_l - margin_r) / 2,
+            _ => width - margin_r,
+        };
+        let y = match self.alignment {
+            7..=9 => margin_v,
+            4..=6 => canvas.centre().1,
+            _ => height - margin_v,
+        };
+        (x, y)
+    }
+
+    pub fn margins(&self, canvas: Canvas) -> String {
+        let (margin_l, margin_r, margin_v) = self.margins_within(canvas);
+        format!("{},{},{}", margin_l, margin_r, margin_v)
+    }
+
+    fn colours(&self) -> [(&'static str, &str); 13] {
+        [
+            ("text_colour", &self.text_colour),
+            ("bold_colour", &self.bold_colour),
+            ("italic_colour", &self.italic_colour),
+            ("code_colour", &self.code_colour),
+            ("link_colour", &self.link_colour),
+            ("footnote_colour", &self.footnote_colour),
+            ("highlight_colour", &self.highlight_colour),
+            ("highlight_text_colour", &self.highlight_text_colour),
+            ("tag_colour", &self.tag_colour),
+            ("tag_text_colour", &self.tag_text_colour),
+            ("outline_colour", &self.outline_colour),
+            ("shadow_colour", &self.shadow_colour),
+            ("background", &self.background),
+        ]
+    }
+
+    // Every colour that isn't #rrggbb, one per line
+    pub fn validate(&self) -> Result<(), String> {
+        let errors: Vec<String> = self
+            .colours()
+            .iter()
+            .filter_map(|(key, hex)| hex_to_rgb(hex).err().map(|e| format!("{}: {}", key, e)))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
+
+// Convert #rrggbb to an inline ASS colour (&HBBGGRR&)
+pub fn ass_colour(hex: &str) -> String {
+    let (r, g, b) = rgb_or_white(hex);
+    format!("&H{:02X}{:02X}{:02X}&", b, g, r)
+}
+
+// Convert #rrggbb to an ASS style colour (&H00BBGGRR)
+pub fn ass_style_colour(hex: &str) -> String {
+    let (r, g, b) = rgb_or_white(hex);
+    format!("&H00{:02X}{:02X}{:02X}", b, g, r)
+}
+
+// Convert #rrggbb to an ffmpeg colour (0xRRGGBB)
+pub fn ffmpeg_colour(hex: &str) -> String {
+    let (r, g, b) = rgb_or_white(hex);
+    format!("0x{:02X}{:02X}{:02X}", r, g, b)
+}
+
+pub fn hex_to_rgb(hex: &str) -> Result<(u8, u8, u8), String> {
+    let digits = hex.trim().trim_start_matches('#');
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("expected a colour such as #ff8800, got \"{}\"", hex));
+    }
+    // Only ASCII digits are left, so the byte offsets fall on characters
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or(255);
+    Ok((channel(0), channel(2), channel(4)))
+}
+
+// Themes are validated when loaded and saved, anything else invalid is drawn white
+pub fn rgb_or_white(hex: &str) -> (u8, u8, u8) {
+    hex_to_rgb(hex).unwrap_or((255, 255, 255))
+}
+
+fn themes_dir() -> PathBuf {
+    PathBuf::from(std::env::var("THEMES_DIR_PATH").unwrap_or_else(|_| "./themes".to_string()))
+}
+
+fn theme_path(name: &str) -> Result<PathBuf, String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ' ');
+    if !valid {
+        return Err(format!("Invalid theme name: {}", name));
+    }
+    Ok(themes_dir().join(format!("{}.json", name)))
+}
+
+pub fn list_themes() -> Result<Vec<String>, String> {
+    let mut names = vec![];
+    let entries = match fs::read_dir(themes_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(vec![Theme::default().name]),
+    };
+
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.extension().map_or(false, |ext| ext == "json") {
+            if let Some(stem) = path.file_stem() {
+                names.push(stem.to_string_lossy().to_string());
+            }
+        }
+    }
+
+    if !names.iter().any(|name| name == "default") {
+        names.push(Theme::default().name);
+    }
+    names.sort();
+    Ok(names)
+}
+
+pub fn load_theme(name: &str) -> Result<Theme, String> {
+    let path = theme_path(name)?;
+    if !path.exists() && name == "default" {
+        return Ok(Theme::default());
+    }
+
+    let content = fs::read_to_string(&path).map_err(|e| format!("Could not read theme {}: {}", name, e))?;
+    let mut theme: Theme = serde_json::from_str(&content).map_err(|e| format!("Invalid theme {}: {}", name, e))?;
+    theme.name = name.to_string();
+    theme.validate().map_err(|e| format!("Invalid theme {}:\n{}", name, e))?;
+    Ok(theme)
+}
+
+pub fn save_theme(theme: &Theme) -> Result<(), String> {
+    let path = theme_path(&theme.name)?;
+    theme.validate().map_err(|e| format!("Invalid theme {}:\n{}", theme.name, e))?;
+    fs::create_dir_all(themes_dir()).map_err(|e| e.to_string())?;
+    let content = serde_json::to_string_pretty(theme).map_err(|e| e.to_string())?;
+    fs::write(path, content).map_err(|e| e.to_string())
+}
+
+// The theme for a note: the `theme` frontmatter key, then the THEME env variable, then the default.
+// A theme that can't be loaded is replaced by the default, with a warning saying why
+pub fn theme_for_note(frontmatter: &str) -> (Theme, Option<String>) {
+    let name = match file_utils::frontmatter_value(frontmatter, "theme") {
+        Some(Value::String(name)) => name,
+        _ => std::env::var("THEME").unwrap_or_else(|_| "default".to_string()),
+    };
+
+    match load_theme(&name) {
+        Ok(theme) => (theme, None),
+        Err(e) => (Theme::default(), Some(format!("{}, falling back to the default theme", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_themes_fall_back_with_a_warning() {
+        let (theme, warning) = theme_for_note("theme: no-such-theme");
+        assert_eq!(theme.font, Theme::default().font);
+        assert!(warning.unwrap().ends_with("falling back to the default theme"));
+    }
+
+    #[test]
+    fn parses_hex_colours() {
+        assert_eq!(hex_to_rgb("#ff8800"), Ok((255, 136, 0)));
+        assert_eq!(hex_to_rgb(" 1E1E2E "), Ok((30, 30, 46)));
+    }
+
+    #[test]
+    fn rejects_invalid_colours_without_panicking() {
+        // Six bytes but not six characters
+        for hex in ["#éé00", "#ff88", "#gg0000", "", "#ff880011"] {
+            assert!(hex_to_rgb(hex).is_err(), "{}", hex);
+        }
+        assert_eq!(ass_colour("#éé00"), "&HFFFFFF&");
+    }
+
+    #[test]
+    fn validation_lists_every_invalid_colour() {
+        let theme = Theme { bold_colour: "red".to_string(), background: "#éé00".to_string(), ..Theme::default() };
+        let error = theme.validate().unwrap_err();
+        assert_eq!(error.lines().count(), 2, "{}", error);
+        assert!(error.starts_with("bold_colour: "), "{}", error);
+    }
+}
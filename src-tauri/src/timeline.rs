@@ -0,0 +1,328 @@
+use crate::ffmpeg_command::{Filter, FilterChain, Filtergraph};
+use crate::ffmpeg_operations;
+use crate::image_resolution::ImageResolution;
+use crate::note_settings::Transition;
+use crate::render_profile::{Canvas, RenderProfile};
+use crate::text_processing;
+
+// One stretch of the video: a segment, a pause or an end card. `ass_content` is a complete
+// ASS file timed from 0, as produced by the text_processing generators
+#[derive(Clone)]
+pub struct Clip {
+    pub ass_content: String,
+    pub duration: f64,
+    pub image: Option<String>,
+    pub qr_code: Option<String>,
+    pub transition: Transition,
+    // Chapter title starting at this clip
+    pub chapter: Option<String>,
+}
+
+// An image shown over the background between `start` and `end`
+pub struct Overlay {
+    pub path: String,
+    pub start: f64,
+    pub end: f64,
+    pub position: OverlayPosition,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OverlayPosition {
+    // Centred horizontally in the top part of the frame, like segment images
+    Image(ImageResolution),
+    // Top right corner
+    QrCode,
+}
+
+// The whole video as one ASS script plus the overlays to draw under it
+pub struct Timeline {
+    pub ass_content: String,
+    pub overlays: Vec<Overlay>,
+    pub duration: f64,
+    pub chapters: Vec<(f64, String)>,
+}
+
+// Length of the fade to and from the background between faded clips, in seconds
+pub const FADE_DURATION: f64 = 0.3;
+
+// Merge the clips into one timeline. Every clip's styles are renamed with a per-clip prefix
+// so clips with different font sizes don't clash, and its events are shifted to the clip's
+// start time. `fade_colour` is the ASS colour faded to between clips with a fade transition.
+// The clips must have been generated for `canvas`
+pub fn compile(clips: &[Clip], fade_colour: &str, canvas: Canvas) -> Timeline {
+    let mut styles: Vec<String> = vec![fade_style_line()];
+    let mut events: Vec<String> = Vec::new();
+    let mut overlays = Vec::new();
+    let mut chapters = Vec::new();
+    let mut start = 0.0;
+
+    for (i, clip) in clips.iter().enumerate() {
+        let prefix = format!("C{}", i);
+        let end = start + clip.duration;
+
+        for line in clip.ass_content.lines().map(str::trim) {
+            if let Some(style) = line.strip_prefix("Style:") {
+                styles.push(format!("Style: {}{}", prefix, style.trim_start()));
+            } else if let Some(event) = line.strip_prefix("Dialogue:") {
+                if let Some(event) = shift_event(event, start, &prefix) {
+                    events.push(event);
+                }
+            }
+        }
+
+        if clip.transition == Transition::Fade {
+            events.extend(fade_events(start, end, fade_colour, canvas));
+        }
+        if let Some(image) = &clip.image {
+            overlays.push(Overlay {
+                path: image.clone(),
+                start,
+                end,
+                position: OverlayPosition::Image(ImageResolution::Full),
+            });
+        }
+        if let Some(qr_code) = &clip.qr_code {
+            overlays.push(Overlay { path: qr_code.clone(), start, end, position: OverlayPosition::QrCode });
+        }
+        if let Some(chapter) = &clip.chapter {
+            chapters.push((start, chapter.clone()));
+        }
+
+        start = end;
+    }
+
+    let ass_content = format!(
+        "[Script Info]\nTitle: md2vid timeline\nScriptType: v4.00+\nWrapStyle: 0\nPlayResX: {}\nPlayResY: {}\nScaledBorderAndShadow: yes\nYCbCr Matrix: None\n\n\
+         [V4+ Styles]\nFormat: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n{}\n\n\
+         [Events]\nFormat: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n{}\n",
+        canvas.width,
+        canvas.height,
+        styles.join("\n"),
+        events.join("\n")
+    );
+
+    Timeline { ass_content, overlays, duration: start, chapters }
+}
+
+// Shift a Dialogue line (without the `Dialogue:` prefix) by `offset` seconds and point it
+// at the clip's renamed style
+fn shift_event(event: &str, offset: f64, prefix: &str) -> Option<String> {
+    let fields: Vec<&str> = event.trim_start().splitn(10, ',').collect();
+    if fields.len() < 10 {
+        return None;
+    }
+    let start = parse_ass_time(fields[1])? + offset;
+    let end = parse_ass_time(fields[2])? + offset;
+    Some(format!(
+        "Dialogue: {},{},{},{}{},{}",
+        fields[0],
+        text_processing::format_ass_time(start),
+        text_processing::format_ass_time(end),
+        prefix,
+        fields[3].trim(),
+        fields[4..].join(",")
+    ))
+}
+
+// Parse an ASS timestamp (H:MM:SS.cc) into seconds
+pub fn parse_ass_time(time: &str) -> Option<f64> {
+    let mut parts = time.trim().split(':');
+    let hours = parts.next()?.parse::<f64>().ok()?;
+    let minutes = parts.next()?.parse::<f64>().ok()?;
+    let seconds = parts.next()?.parse::<f64>().ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+fn fade_style_line() -> String {
+    "Style: Fade, Arial, 20, &H00000000, &H00000000, &H00000000, &H00000000, 0, 0, 0, 0, 100, 100, 0, 0, 1, 0, 0, 7, 0, 0, 0, 1"
+        .to_string()
+}
+
+// A full-frame box in `colour` that fades out at the start of the clip and back in at its end,
+// drawn above everything else including the overlays
+fn fade_events(start: f64, end: f64, colour: &str, canvas: Canvas) -> Vec<String> {
+    let fade = FADE_DURATION.min((end - start) / 2.0);
+    let fade_ms = (fade * 1000.0).round() as i64;
+    let frame = format!("m 0 0 l {w} 0 {w} {h} 0 {h}", w = canvas.width, h = canvas.height);
+    vec![
+        format!(
+            "Dialogue: 100,{},{},Fade,,0,0,0,,{{\\pos(0,0)\\c{}\\alpha&H00&\\t(0,{},\\alpha&HFF&)\\p1}}{}",
+            text_processing::format_ass_time(start),
+            text_processing::format_ass_time(start + fade),
+            colour,
+            fade_ms,
+            frame
+        ),
+        format!(
+            "Dialogue: 100,{},{},Fade,,0,0,0,,{{\\pos(0,0)\\c{}\\alpha&HFF&\\t(0,{},\\alpha&H00&)\\p1}}{}",
+            text_processing::format_ass_time(end - fade),
+            text_processing::format_ass_time(end),
+            colour,
+            fade_ms,
+            frame
+        ),
+    ]
+}
+
+// Input files for the single render, in input order after the background (input 0)
+pub fn overlay_inputs(timeline: &Timeline) -> Vec<(String, f64)> {
+    timeline
+        .overlays
+        .iter()
+        .map(|overlay| (overlay.path.clone(), overlay.end - overlay.start))
+        .collect()
+}
+
+// The filtergraph for the single render: each overlay is shifted to its start time and only
+// enabled while its clip is on screen, then the timeline's subtitles are burnt in on top
+pub fn filtergraph(timeline: &Timeline, ass_file_name: &str, fonts_dir: &str, profile: &RenderProfile) -> Filtergraph {
+    let mut filtergraph = Filtergraph::new();
+    let mut current = "0:v".to_string();
+
+    for (i, overlay) in timeline.overlays.iter().enumerate() {
+        let input = i + 1;
+        let (scale, position) = match overlay.position {
+            OverlayPosition::Image(resolution) => {
+                (Some(ffmpeg_operations::image_scale_filter(resolution, profile)), ffmpeg_operations::image_overlay_filter(profile))
+            }
+            OverlayPosition::QrCode => (None, ffmpeg_operations::qr_code_overlay_filter(profile)),
+        };
+        let shifted = format!("o{}", input);
+        filtergraph.push(
+            FilterChain::new()
+                .input(&format!("{}:v", input))
+                .filters(scale)
+                .filter(Filter::new("setpts").arg(format!("PTS-STARTPTS+{:.3}/TB", overlay.start)))
+                .output(&shifted),
+        );
+        let overlaid = format!("v{}", input);
+        filtergraph.push(
+            FilterChain::new()
+                .input(&current)
+                .input(&shifted)
+                .filter(
+                    position
+                        .option("enable", format!("between(t,{:.3},{:.3})", overlay.start, overlay.end))
+                        .option("eof_action", "pass"),
+                )
+                .output(&overlaid),
+        );
+        current = overlaid;
+    }
+
+    filtergraph.push(
+        FilterChain::new()
+            .input(&current)
+            .filter(ffmpeg_operations::subtitles_filter(ass_file_name, fonts_dir)),
+    );
+    filtergraph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip(duration: f64, dialogue: &[&str]) -> Clip {
+        let events: Vec<String> = dialogue.iter().map(|line| format!("        Dialogue: {}", line)).collect();
+        Clip {
+            ass_content: format!(
+                "[Script Info]\n        PlayResX: 1280\n\n        [V4+ Styles]\n        Style: Default,Arial,48\n        Style: Footnote,Arial,24\n\n        [Events]\n{}",
+                events.join("\n")
+            ),
+            duration,
+            image: None,
+            qr_code: None,
+            transition: Transition::Cut,
+            chapter: None,
+        }
+    }
+
+    fn lines<'a>(timeline: &'a Timeline, prefix: &str) -> Vec<&'a str> {
+        timeline.ass_content.lines().filter(|line| line.starts_with(prefix)).collect()
+    }
+
+    #[test]
+    fn renames_styles_per_clip() {
+        let clips = [clip(2.0, &[]), clip(3.0, &[])];
+        let timeline = compile(&clips, "&H000000&", Canvas::default());
+        assert_eq!(
+            lines(&timeline, "Style:")[1..],
+            [
+                "Style: C0Default,Arial,48",
+                "Style: C0Footnote,Arial,24",
+                "Style: C1Default,Arial,48",
+                "Style: C1Footnote,Arial,24",
+            ]
+        );
+    }
+
+    #[test]
+    fn shifts_events_to_their_clip() {
+        let clips = [
+            clip(2.0, &["0,0:00:00.00,0:00:02.00,Default,,0,0,0,,One"]),
+            clip(3.0, &["0,0:00:00.50,0:00:03.00,Default,,0,0,0,,Two, with a comma", "5,0:00:01.00,0:00:02.00,Footnote,,0,0,0,,Note"]),
+            clip(61.5, &["0,0:00:00.00,0:01:01.50,Default,,0,0,0,,Three"]),
+        ];
+        let timeline = compile(&clips, "&H000000&", Canvas::default());
+        assert_eq!(
+            lines(&timeline, "Dialogue:"),
+            [
+                "Dialogue: 0,0:00:00.00,0:00:02.00,C0Default,,0,0,0,,One",
+                "Dialogue: 0,0:00:02.50,0:00:05.00,C1Default,,0,0,0,,Two, with a comma",
+                "Dialogue: 5,0:00:03.00,0:00:04.00,C1Footnote,,0,0,0,,Note",
+                "Dialogue: 0,0:00:05.00,0:01:06.50,C2Default,,0,0,0,,Three",
+            ]
+        );
+        assert_eq!(timeline.duration, 66.5);
+    }
+
+    #[test]
+    fn fades_cover_the_clip_edges() {
+        let mut faded = clip(2.0, &[]);
+        faded.transition = Transition::Fade;
+        let timeline = compile(&[clip(1.0, &[]), faded], "&H112233&", Canvas::default());
+        let fades = lines(&timeline, "Dialogue: 100,");
+        assert_eq!(fades.len(), 2);
+        assert!(fades[0].starts_with("Dialogue: 100,0:00:01.00,0:00:01.30,Fade,"), "{}", fades[0]);
+        assert!(fades[1].starts_with("Dialogue: 100,0:00:02.70,0:00:03.00,Fade,"), "{}", fades[1]);
+        assert!(fades.iter().all(|fade| fade.contains("\\c&H112233&")));
+    }
+
+    #[test]
+    fn overlays_and_chapters_follow_their_clips() {
+        let mut pictured = clip(3.0, &[]);
+        pictured.image = Some("image.png".to_string());
+        pictured.qr_code = Some("qr.png".to_string());
+        pictured.chapter = Some("Second".to_string());
+        let mut first = clip(2.0, &[]);
+        first.chapter = Some("First".to_string());
+        let timeline = compile(&[first, pictured], "&H000000&", Canvas::default());
+
+        let windows: Vec<(&str, f64, f64)> =
+            timeline.overlays.iter().map(|overlay| (overlay.path.as_str(), overlay.start, overlay.end)).collect();
+        assert_eq!(windows, [("image.png", 2.0, 5.0), ("qr.png", 2.0, 5.0)]);
+        assert_eq!(timeline.chapters, [(0.0, "First".to_string()), (2.0, "Second".to_string())]);
+        assert_eq!(overlay_inputs(&timeline), [("image.png".to_string(), 3.0), ("qr.png".to_string(), 3.0)]);
+    }
+
+    #[test]
+    fn filtergraph_enables_overlays_only_during_their_clip() {
+        let mut pictured = clip(2.5, &[]);
+        pictured.image = Some("image.png".to_string());
+        pictured.qr_code = Some("qr.png".to_string());
+        let timeline = compile(&[clip(1.25, &[]), pictured], "&H000000&", Canvas::default());
+        let filtergraph = filtergraph(&timeline, "timeline.ass", "fonts", &RenderProfile::default()).to_string();
+
+        let chains: Vec<&str> = filtergraph.split(';').map(str::trim).collect();
+        assert_eq!(chains.len(), 5);
+        assert!(chains[0].starts_with("[1:v]"));
+        assert!(chains[0].ends_with("setpts=PTS-STARTPTS+1.250/TB[o1]"), "{}", chains[0]);
+        assert!(chains[1].starts_with("[0:v][o1]overlay="), "{}", chains[1]);
+        assert!(chains[1].contains("between(t\\,1.250\\,3.750)"), "{}", chains[1]);
+        assert!(chains[1].ends_with("[v1]"));
+        assert!(chains[2].starts_with("[2:v]setpts=PTS-STARTPTS+1.250/TB[o2]"), "{}", chains[2]);
+        assert!(chains[3].starts_with("[v1][o2]overlay="), "{}", chains[3]);
+        assert!(chains[3].contains("between(t\\,1.250\\,3.750)"), "{}", chains[3]);
+        assert_eq!(chains[4], "[v2]ass=filename=timeline.ass:fontsdir=fonts");
+    }
+}
@@ -0,0 +1,183 @@
+use regex::Regex;
+use serde_json::Value;
+
+use crate::file_utils;
+
+// Reading-speed settings used to work out how long each segment stays on screen
+pub struct TimingConfig {
+    pub words_per_minute: f64,
+    pub min_duration: f64,
+    pub max_duration: f64,
+    pub image_duration: f64,
+    // Fixed duration for every segment of the note, ignores the reading speed
+    pub fixed_duration: Option<f64>,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        TimingConfig {
+            words_per_minute: 180.0,
+            min_duration: 2.5,
+            max_duration: 12.0,
+            image_duration: 2.0,
+            fixed_duration: None,
+        }
+    }
+}
+
+impl TimingConfig {
+    // Override the defaults with any timing keys found in the note's frontmatter:
+    // wpm, min_duration, max_duration, image_duration and duration.
+    // Every invalid value is reported, one per line
+    pub fn from_frontmatter(frontmatter: &str) -> Result<Self, String> {
+        let mut config = TimingConfig::default();
+        let mut errors = Vec::new();
+
+        let mut read = |key: &str, allow_zero: bool| match number_from_frontmatter(frontmatter, key) {
+            Some(Ok(number)) if number > 0.0 || (allow_zero && number == 0.0) => Some(number),
+            Some(Ok(number)) => {
+                let bound = if allow_zero { "0 or more" } else { "greater than 0" };
+                errors.push(format!("{}: expected a number {}, got {}", key, bound, number));
+                None
+            }
+            Some(Err(value)) => {
+                errors.push(format!("{}: expected a number, got {}", key, value));
+                None
+            }
+            None => None,
+        };
+
+        if let Some(wpm) = read("wpm", false) {
+            config.words_per_minute = wpm;
+        }
+        if let Some(min) = read("min_duration", false) {
+            config.min_duration = min;
+        }
+        if let Some(max) = read("max_duration", false) {
+            config.max_duration = max;
+        }
+        if let Some(image) = read("image_duration", true) {
+            config.image_duration = image;
+        }
+        config.fixed_duration = read("duration", false);
+
+        if config.min_duration > config.max_duration {
+            errors.push(format!(
+                "min_duration: {} is longer than max_duration {}",
+                config.min_duration, config.max_duration
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(format!("Invalid timing settings in frontmatter:\n{}", errors.join("\n")))
+        }
+    }
+}
+
+// The key's value if it's a number, or the value as written if it's something else
+fn number_from_frontmatter(frontmatter: &str, key: &str) -> Option<Result<f64, Value>> {
+    match file_utils::frontmatter_value(frontmatter, key)? {
+        Value::Number(number) => number.as_f64().map(Ok),
+        other => Some(Err(other)),
+    }
+}
+
+// Pull an inline duration override (Dataview style, e.g. `[duration:: 8]`) out of a segment.
+// Returns the segment without the field and the override in seconds, if any
+pub fn extract_duration_override(sentence: &str) -> (String, Option<f64>) {
+    let re = Regex::new(r"\[duration::\s*([0-9]+(?:\.[0-9]+)?)\s*s?\]").unwrap();
+    let duration = re
+        .captures(sentence)
+        .and_then(|caps| caps[1].parse::<f64>().ok());
+    let sentence = re.replace_all(sentence, "").trim().to_string();
+    (sentence, duration)
+}
+
+// Count the words a viewer actually has to read, ignoring image embeds
+pub fn count_words(sentence: &str) -> usize {
+    let re = Regex::new(r"!\[\[.*?\]\]|!\[.*?\]\(.*?\)").unwrap();
+    let without_images = re.replace_all(sentence, " ");
+    without_images
+        .split_whitespace()
+        .filter(|word| word.chars().any(|c| c.is_alphanumeric()))
+        .count()
+}
+
+// Duration of a segment in seconds, from its word count and the reading speed,
+// clamped to the configured bounds with extra time when an image is shown
+pub fn segment_duration(sentence: &str, has_image: bool, config: &TimingConfig) -> f64 {
+    if let Some(fixed) = config.fixed_duration {
+        return fixed;
+    }
+
+    let words = count_words(sentence) as f64;
+    let reading_time = words / config.words_per_minute * 60.0;
+    let mut duration = reading_time.max(config.min_duration).min(config.max_duration);
+
+    if has_image {
+        duration += config.image_duration;
+    }
+
+    duration
+}
+
+// Shortest time a single list item is shown before the next one appears
+const MIN_ITEM_DURATION: f64 = 1.0;
+
+// Durations for the items of a list, each from its own length. When the whole list
+// has a fixed duration (inline or frontmatter override) it is shared out by length instead
+pub fn list_item_durations(items: &[&str], config: &TimingConfig, total_override: Option<f64>) -> Vec<f64> {
+    let durations: Vec<f64> = items
+        .iter()
+        .map(|item| {
+            let words = count_words(item) as f64;
+            (words / config.words_per_minute * 60.0).max(MIN_ITEM_DURATION).min(config.max_duration)
+        })
+        .collect();
+
+    match total_override.or(config.fixed_duration) {
+        Some(total) => {
+            let sum: f64 = durations.iter().sum();
+            durations.iter().map(|d| d / sum * total).collect()
+        }
+        None => durations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_valid_timing() {
+        let config = TimingConfig::from_frontmatter("wpm: 200\nmin_duration: 1.5\nmax_duration: 8\nduration: 4").unwrap();
+        assert_eq!(config.words_per_minute, 200.0);
+        assert_eq!(config.min_duration, 1.5);
+        assert_eq!(config.max_duration, 8.0);
+        assert_eq!(config.fixed_duration, Some(4.0));
+    }
+
+    #[test]
+    fn reports_every_invalid_duration() {
+        let error = TimingConfig::from_frontmatter("duration: 0\nmin_duration: -1\nmax_duration: soon").err().unwrap();
+        let lines: Vec<&str> = error.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                "min_duration: expected a number greater than 0, got -1",
+                "max_duration: expected a number, got \"soon\"",
+                "duration: expected a number greater than 0, got 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_min_longer_than_max() {
+        let error = TimingConfig::from_frontmatter("min_duration: 10\nmax_duration: 5").err().unwrap();
+        assert!(error.ends_with("min_duration: 10 is longer than max_duration 5"), "{}", error);
+        // The default maximum counts too
+        assert!(TimingConfig::from_frontmatter("min_duration: 20").is_err());
+    }
+}
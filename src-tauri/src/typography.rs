@@ -0,0 +1,233 @@
+use regex::Regex;
+use rusttype::{Font, Scale};
+
+use crate::fonts::PlannedFont;
+use crate::render_profile::Canvas;
+use crate::text_processing;
+
+// Typographic pass over ASS text (the output of process_sentence): smart punctuation,
+// non-breaking spaces before units and lines balanced across the canvas width. Lines are
+// measured with `font`, the note's primary font; without one libass wraps them instead
+pub fn typeset(
+    text: &str,
+    font: Option<&PlannedFont>,
+    font_size: u32,
+    margin_l: u32,
+    margin_r: u32,
+    canvas: Canvas,
+) -> String {
+    let text = smarten(text);
+    let max_width = canvas.width as f32 - margin_l as f32 - margin_r as f32;
+    match font {
+        Some(font) => balance_lines(&text, font, font_size as f32, max_width),
+        None => text,
+    }
+}
+
+// Smart quotes, dashes, ellipses and non-breaking spaces before units.
+// Override blocks ({...}) are left untouched
+pub fn smarten(text: &str) -> String {
+    let mut result = String::new();
+    let mut run = String::new();
+    let mut last_visible = None;
+    let mut in_tag = false;
+
+    for c in text.chars() {
+        match c {
+            '{' if !in_tag => {
+                result.push_str(&smarten_run(&run, last_visible));
+                last_visible = run.chars().last().or(last_visible);
+                run.clear();
+                in_tag = true;
+                result.push(c);
+            }
+            '}' if in_tag => {
+                in_tag = false;
+                result.push(c);
+            }
+            _ if in_tag => result.push(c),
+            _ => run.push(c),
+        }
+    }
+    result.push_str(&smarten_run(&run, last_visible));
+    result
+}
+
+// `previous` is the last character before the run, used to decide which way quotes face
+fn smarten_run(run: &str, previous: Option<char>) -> String {
+    let run = run.replace("...", "…").replace("---", "—").replace("--", "—").replace(" - ", " – ");
+
+    let mut result = String::new();
+    let mut previous = previous;
+    for c in run.chars() {
+        let opening = previous.map_or(true, |p| p.is_whitespace() || "([{—–‘“".contains(p));
+        let replaced = match c {
+            '"' if opening => '“',
+            '"' => '”',
+            '\'' if opening => '‘',
+            '\'' => '’',
+            _ => c,
+        };
+        result.push(replaced);
+        previous = Some(c);
+    }
+
+    let units = Regex::new(r"(\d) +(%|(?:km|cm|mm|m|kg|mg|g|ms|min|s|h|ml|l|L|KB|MB|GB|TB|px|fps|mph|°C|°F)\b)").unwrap();
+    units.replace_all(&result, "$1\\h$2").into_owned()
+}
+
+fn measure(font: &Font, scale: Scale, text: &str) -> f32 {
+    let text = text_processing::strip_ass_tags(text).replace("\\h", " ");
+    font.layout(&text, scale, rusttype::point(0.0, 0.0))
+        .last()
+        .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0)
+}
+
+// Break text into the fewest lines that fit `max_width`, with line lengths as even as possible
+// so a single word never ends up alone on the last line. Existing \N breaks are kept. Words are
+// measured at the size libass draws `font_size` at
+pub fn balance_lines(text: &str, planned: &PlannedFont, font_size: f32, max_width: f32) -> String {
+    let font = &planned.font;
+    let scale = planned.scale(font_size);
+
+    let mut broken = false;
+    let paragraphs: Vec<String> = text
+        .split("\\N")
+        .map(|paragraph| {
+            let words: Vec<&str> = paragraph.split(' ').filter(|word| !word.is_empty()).collect();
+            let space = measure(font, scale, " ");
+            let widths: Vec<f32> = words.iter().map(|word| measure(font, scale, word)).collect();
+            let total: f32 = widths.iter().sum::<f32>() + space * words.len().saturating_sub(1) as f32;
+
+            if total <= max_width || words.len() < 2 {
+                return paragraph.to_string();
+            }
+
+            broken = true;
+            let lines = (total / max_width).ceil() as usize;
+            let target = total / lines as f32;
+            let breaks = best_breaks(&widths, space, max_width, target);
+            breaks
+                .windows(2)
+                .map(|range| words[range[0]..range[1]].join(" "))
+                .collect::<Vec<String>>()
+                .join("\\N")
+        })
+        .collect();
+
+    let result = paragraphs.join("\\N");
+    if broken {
+        // Stop libass from re-wrapping the lines we just balanced
+        format!("{{\\q2}}{}", result)
+    } else {
+        result
+    }
+}
+
+// Minimum-raggedness line breaking: choose break points so every line fits and the squared
+// difference from the target width is as small as possible. Returns word indices of line starts
+// plus the word count at the end
+fn best_breaks(widths: &[f32], space: f32, max_width: f32, target: f32) -> Vec<usize> {
+    let n = widths.len();
+    let mut cost = vec![f32::INFINITY; n + 1];
+    let mut previous = vec![0; n + 1];
+    cost[0] = 0.0;
+
+    for end in 1..=n {
+        let mut width = 0.0;
+        for start in (0..end).rev() {
+            width += widths[start] + if start + 1 < end { space } else { 0.0 };
+            // A single word wider than the line still gets a line of its own
+            if width > max_width && start + 1 < end {
+                break;
+            }
+            let line_cost = (target - width).powi(2);
+            if cost[start] + line_cost < cost[end] {
+                cost[end] = cost[start] + line_cost;
+                previous[end] = start;
+            }
+        }
+    }
+
+    let mut breaks = vec![n];
+    let mut end = n;
+    while end > 0 {
+        end = previous[end];
+        breaks.push(end);
+    }
+    breaks.reverse();
+    breaks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fonts::FontFace;
+    use std::path::PathBuf;
+
+    fn helvetica() -> PlannedFont {
+        let face = FontFace {
+            family: "Helvetica".to_string(),
+            path: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets/Helvetica.ttf"),
+        };
+        PlannedFont::load(&face).expect("the bundled Helvetica should load")
+    }
+
+    #[test]
+    fn smartens_quotes_dashes_and_ellipses() {
+        assert_eq!(smarten("\"Quoted\" and 'single'"), "“Quoted” and ‘single’");
+        assert_eq!(smarten("It's (\"nested\")"), "It’s (“nested”)");
+        assert_eq!(smarten("wait... then -- go --- now - ok"), "wait… then — go — now – ok");
+    }
+
+    #[test]
+    fn smarten_leaves_override_blocks_alone() {
+        assert_eq!(smarten("{\\c&H00FF00&}\"green\"{\\c}"), "{\\c&H00FF00&}“green”{\\c}");
+        // The quote after a tag faces the way the text before the tag says
+        assert_eq!(smarten("say {\\b1}\"this\"{\\b0}"), "say {\\b1}“this”{\\b0}");
+    }
+
+    #[test]
+    fn keeps_units_with_their_numbers() {
+        assert_eq!(smarten("5 km in 20 min at 50 %"), "5\\hkm in 20\\hmin at 50\\h%");
+        assert_eq!(smarten("3 apples"), "3 apples");
+        assert_eq!(smarten("2 kmh"), "2 kmh");
+    }
+
+    #[test]
+    fn sizes_like_libass() {
+        let planned = helvetica();
+        let v_metrics = planned.font.v_metrics(planned.scale(100.0));
+        // The scaled ascent - descent span is the ASS size times the font's size factor
+        assert!((v_metrics.ascent - v_metrics.descent - 100.0 * planned.size_factor).abs() < 0.01);
+        assert!(planned.size_factor > 0.5 && planned.size_factor <= 1.5, "{}", planned.size_factor);
+    }
+
+    #[test]
+    fn short_lines_are_left_alone() {
+        assert_eq!(balance_lines("Short line", &helvetica(), 48.0, 1000.0), "Short line");
+    }
+
+    #[test]
+    fn balances_long_lines_evenly() {
+        let planned = helvetica();
+        let text = "one two three four five six seven eight nine ten eleven twelve";
+        let width = measure(&planned.font, planned.scale(48.0), text);
+        let balanced = balance_lines(text, &planned, 48.0, width * 0.6);
+
+        let lines: Vec<&str> = balanced.trim_start_matches("{\\q2}").split("\\N").collect();
+        assert!(balanced.starts_with("{\\q2}"));
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines.join(" "), text);
+        let widths: Vec<f32> = lines.iter().map(|line| measure(&planned.font, planned.scale(48.0), line)).collect();
+        assert!(widths.iter().all(|line_width| *line_width <= width * 0.6));
+        assert!((widths[0] - widths[1]).abs() < width * 0.2, "{:?}", widths);
+    }
+
+    #[test]
+    fn keeps_existing_breaks() {
+        let balanced = balance_lines("first\\Nsecond", &helvetica(), 48.0, 1000.0);
+        assert_eq!(balanced, "first\\Nsecond");
+    }
+}
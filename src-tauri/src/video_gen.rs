@@ -1,12 +1,12 @@
 use std::{error::Error, fs};
-use tokio::process::Command;
-use tauri::Window;
 use regex::Regex;
 use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 use std::fs::File;
 use std::io::Write;
 use reqwest::Client;
-use tokio::io::AsyncWriteExt;
 use crate::image_resolution::ImageResolution;
 
 
@@ -15,59 +15,289 @@ use crate::image_resolution::ImageResolution;
 use crate::yt_downloader;
 use crate::log_utils;
 use crate::text_processing;
-use crate::ffmpeg_operations;
+use crate::ffmpeg_operations::{self, OnProgress};
+use crate::directives::{self, Directive};
+use crate::file_utils;
+use crate::fonts::{self, FontPlan};
+use crate::job_dir::{self, JobDir};
+use crate::jobs::JobHandle;
+use crate::frame_renderer::{self, FontSet};
+use crate::note_settings::{AudioSource, NoteSettings, Renderer, Transition};
+use crate::progress::{RenderProgress, Stage};
+use crate::render_profile::RenderProfile;
+use crate::footnotes;
+use crate::links;
+use crate::redaction::{self, RedactionMode};
+use crate::segmentation::{self, SegmentKind};
+use crate::templates::TemplateContext;
+use crate::theme;
+use crate::timeline::{self, Clip, Timeline};
+use crate::timing::{self, TimingConfig};
+use crate::typography;
+
+// Links listed on each Sources end card
+const SOURCES_PER_CARD: usize = 8;
+
+// Load a note and render it, as one job of the render queue
+pub async fn render_note(handle: JobHandle, path: &str) -> Result<(), String> {
+    // Extract frontmatter and text content from the file
+    let frontmatter = file_utils::extract_frontmatter(path).map_err(|e| e.to_string())?;
+    let text_content = file_utils::extract_text_content(path).map_err(|e| e.to_string())?;
+
+    // Per-note render settings from the `md2vid:` block, rejected before anything is rendered
+    let settings = NoteSettings::from_frontmatter(&frontmatter)?;
+
+    let audio = match &settings.audio {
+        Some(audio) => audio.clone(),
+        None => {
+            // Attempt to extract YouTube URL from the frontmatter
+            let youtube_url_from_frontmatter = file_utils::extract_youtube_url_from_text_content(&frontmatter).ok();
+
+            // If no URL is found in the frontmatter, attempt to extract it from the text content
+            let youtube_url = youtube_url_from_frontmatter
+                .or_else(|| file_utils::extract_youtube_url_from_text_content(&text_content).ok())
+                .unwrap_or_else(|| "https://www.youtube.com/watch?v=H0j_xIm4fW0".to_string());
+            AudioSource::YouTube(youtube_url)
+        }
+    };
+
+    let filename = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    // Set KEEP_INTERMEDIATES=true to keep the job's working files for debugging
+    let keep_intermediates = std::env::var("KEEP_INTERMEDIATES").map_or(false, |value| value == "true" || value == "1");
+
+    create_video_with_ffmpeg(handle, &frontmatter, &filename, &text_content, &settings, &audio, keep_intermediates)
+        .await
+        .map_err(|e| e.to_string())
+}
 
 pub async fn create_video_with_ffmpeg(
-    window: Window,
+    handle: JobHandle,
     frontmatter: &str,
+    filename: &str,
     text_content: &str,
-    youtube_url: &str,
-    delete_temp_videos: bool,
-    delete_downloaded_images: bool,
+    settings: &NoteSettings,
+    audio: &AudioSource,
+    keep_intermediates: bool,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    log_utils::print_pretty_log("Removing old audio file...", "blue");
-
-    let old_audio_path = "./temp_files/audio.mp3";
-    if Path::new(old_audio_path).exists() {
-        fs::remove_file(old_audio_path)?;
+    // Invalid timing is rejected before any audio is downloaded
+    let mut timing_config = TimingConfig::from_frontmatter(frontmatter)?;
+    if settings.duration.is_some() {
+        timing_config.fixed_duration = settings.duration;
     }
 
-    log_utils::print_pretty_log("Downloading YouTube video as MP3...", "blue");
-    emit_stage_event(&window, "Downloading Audio")?;
+    // Removed with everything in it when this function returns, unless intermediates are kept
+    let job = JobDir::create(keep_intermediates)?;
 
-    let download_result = yt_downloader::download_youtube_as_mp3(youtube_url);
-    match download_result {
-        Ok(_) => println!("Video downloaded successfully"),
-        Err(e) => {
-            println!("Failed to download video: {}", e);
-            return Err(e.into());
+    // Rough share of the job's time each stage takes, for the overall percentage and ETA
+    let mut stages = Vec::new();
+    if matches!(audio, AudioSource::YouTube(_)) {
+        stages.push((Stage::Download, 0.1));
+    }
+    stages.push((Stage::Render, 0.75));
+    if settings.renderer == Renderer::Segments {
+        stages.push((Stage::Concatenate, 0.05));
+    }
+    stages.push((Stage::Merge, 0.1));
+    if settings.target_size.is_some() {
+        stages.push((Stage::Compress, 0.3));
+    }
+    let progress = Arc::new(RenderProgress::new(handle.clone(), &stages));
+
+    // Fill in the note's {{variables}} first, so values taken from the frontmatter are redacted
+    // too. {{segment}} and {{total}} are filled in per segment
+    let template_context = TemplateContext::new(frontmatter, filename);
+    let mut template_warnings: Vec<String> = Vec::new();
+    let (text_content, warnings) = template_context.expand_note(text_content);
+    for warning in warnings {
+        if !template_warnings.contains(&warning) {
+            log_utils::print_pretty_log(&warning, "red");
+            emit_warning_event(&handle, &warning)?;
+            template_warnings.push(warning);
         }
     }
 
-    log_utils::print_pretty_log("Generating videos for each sentence...", "blue");
-    emit_stage_event(&window, "Generating Videos")?;
+    // Private content is taken out before anything else sees the text
+    let redaction_config = redaction::load_config()?;
+    let redaction_mode = settings
+        .redaction
+        .or_else(|| RedactionMode::from_name(&redaction_config.mode))
+        .unwrap_or(RedactionMode::Placeholder);
+    let (text_content, redaction_report) =
+        redaction::redact(&text_content, frontmatter, &redaction_config, redaction_mode)?;
+    for line in &redaction_report {
+        log_utils::print_pretty_log(&format!("Redacted {}", line), "blue");
+    }
+    emit_redaction_event(&handle, &redaction_report)?;
+    let text_content = text_content.as_str();
+
+    let audio_file = match audio {
+        AudioSource::YouTube(youtube_url) => {
+            log_utils::print_pretty_log("Downloading YouTube video as MP3...", "blue");
+            emit_stage_event(&handle, "Downloading Audio")?;
+
+            let audio_path = job.file("audio.mp3");
+            let download_result = yt_downloader::download_youtube_as_mp3(youtube_url, &audio_path).await;
+            match download_result {
+                Ok(_) => {
+                    println!("Video downloaded successfully");
+                    progress.finish(Stage::Download);
+                }
+                Err(e) => {
+                    println!("Failed to download video: {}", e);
+                    return Err(e.into());
+                }
+            }
+            Some(audio_path)
+        }
+        AudioSource::File(path) => Some(path.clone()),
+        AudioSource::Silent => None,
+    };
 
-    let sentences: Vec<&str> = text_content.split(". ")
-        .flat_map(|s| s.split(".\n"))
-        .flat_map(|s| s.split("? "))
-        .flat_map(|s| s.split("!\n"))
-        .flat_map(|s| s.split("! "))
-        .collect();
+    log_utils::print_pretty_log("Generating videos for each sentence...", "blue");
+    emit_stage_event(&handle, "Generating Videos")?;
 
-    let sentences: Vec<String> = sentences.iter().map(|s| s.replace("\n", " ")).collect();
+    // Comments never reach the screen; md2vid directives in them pace the following segment
+    let (text_content, pacing_directives, directive_warnings) = directives::extract_directives(text_content);
+    for warning in &directive_warnings {
+        log_utils::print_pretty_log(warning, "red");
+        emit_warning_event(&handle, warning)?;
+    }
 
-    let mut file_list = String::new();
-    let mut downloaded_images = Vec::new();
+    // Every hosted link, for the Sources end card. Blurred links stay private
+    let note_links = redaction::unredacted_links(&text_content);
+
+    // Footnote definitions are resolved per segment and must not be rendered as text
+    let (text_content, footnote_definitions) = footnotes::extract_definitions(&text_content);
+
+    let segments = directives::attach_directives(segmentation::segment_text(&text_content, settings.segmentation), &pacing_directives);
+    let section_numbers = segmentation::section_numbers(&segments);
+    let show_section_counter = matches!(
+        file_utils::frontmatter_value(frontmatter, "section_counter"),
+        Some(serde_json::Value::Bool(true))
+    );
+
+    let karaoke_style = text_processing::KaraokeStyle::from_frontmatter(frontmatter);
+    let note_effect = text_processing::TextEffect::from_frontmatter(frontmatter);
+    let mut theme = match &settings.theme {
+        Some(name) => theme::load_theme(name)?,
+        None => {
+            let (theme, warning) = theme::theme_for_note(frontmatter);
+            if let Some(warning) = warning {
+                log_utils::print_pretty_log(&warning, "red");
+                emit_warning_event(&handle, &warning)?;
+            }
+            theme
+        }
+    };
+    let background = theme::ffmpeg_colour(&theme.background);
+    let profile = &settings.profile;
+    let canvas = profile.canvas();
+
+    // Pick fonts for every script in the note and give libass only those files
+    let fonts_dir = job.file("fonts");
+    // Planned on the processed text, which has characters the source doesn't, such as smart
+    // quotes and sub- and superscript digits
+    let processed = typography::smarten(&text_processing::process_sentence(&text_content, &theme));
+    let font_plan = fonts::plan_fonts(&processed, &theme.font);
+    match &font_plan {
+        Some(plan) => {
+            if !plan.primary.face.family.eq_ignore_ascii_case(&theme.font) {
+                let message = format!("Font {} not found, using {}", theme.font, plan.primary.face.family);
+                log_utils::print_pretty_log(&message, "red");
+                emit_warning_event(&handle, &message)?;
+            }
+            if !plan.missing.is_empty() {
+                let missing: String = plan.missing.iter().collect();
+                let message = format!("No installed font can draw these characters: {}", missing);
+                log_utils::print_pretty_log(&message, "red");
+                emit_warning_event(&handle, &message)?;
+            }
+            theme.font = plan.primary.face.family.clone();
+            fonts::prepare_fonts_dir(plan, &fonts_dir)?;
+        }
+        None => {
+            log_utils::print_pretty_log("No fonts found, leaving font selection to libass", "red");
+        }
+    }
+    let fonts_dir = if font_plan.is_some() { fonts_dir.as_str() } else { "./" };
+    // Line breaks are balanced with the font libass will draw them in
+    let measure_font = font_plan.as_ref().map(|plan| &plan.primary);
+
+    let total_segments = segments
+        .iter()
+        .filter(|segment| !segment.directives.contains(&Directive::Skip))
+        .count();
+    let mut segment_number = 0;
+
+    let mut clips: Vec<Clip> = Vec::new();
+    let mut last_image_path: Option<String> = None;
+    let mut footnote_counter = 0;
+
+    // Building the clips stops between segments when the job is paused
+    handle.set_pausable(true).await;
+    for (i, segment) in segments.iter().enumerate() {
+        if segment.directives.contains(&Directive::Skip) {
+            continue;
+        }
+        handle.wait_while_paused().await;
+        segment_number += 1;
+
+        let pause: f64 = segment
+            .directives
+            .iter()
+            .map(|directive| match directive {
+                Directive::Pause(seconds) => *seconds,
+                _ => 0.0,
+            })
+            .sum();
+        if pause > 0.0 {
+            let ass_content = text_processing::generate_ass_content_bottom(
+                "",
+                pause,
+                text_processing::TextEffect::None,
+                &theme,
+                &[],
+                canvas,
+            )?;
+            clips.push(Clip {
+                ass_content,
+                duration: pause,
+                image: None,
+                qr_code: None,
+                transition: Transition::Cut,
+                chapter: None,
+            });
+        }
 
-    for (i, sentence) in sentences.iter().enumerate() {
-        let sentence = sentence.trim();
+        let (sentence, duration_override) = timing::extract_duration_override(segment.text.trim());
+        let duration_override = duration_override.or_else(|| {
+            segment.directives.iter().find_map(|directive| match directive {
+                Directive::Duration(seconds) => Some(*seconds),
+                _ => None,
+            })
+        });
+        let (sentence, effect_override) = text_processing::extract_effect_override(&sentence);
+
+        // Fill in {{variables}} before any markup is processed
+        let (sentence, warnings) = template_context.expand(&sentence, segment_number, total_segments);
+        for warning in warnings {
+            if !template_warnings.contains(&warning) {
+                log_utils::print_pretty_log(&warning, "red");
+                emit_warning_event(&handle, &warning)?;
+                template_warnings.push(warning);
+            }
+        }
+        let sentence = sentence.as_str();
         let mut image_file_path = None;
 
-        if let Some(image_path_or_url) = check_image_in_text(sentence) {
+        if let Some(image_path_or_url) = check_image_in_text(sentence)? {
             if image_path_or_url.starts_with("http") {
                 // Hosted image
-                let image_path = format!("./temp_files/image{}.png", i);
-                downloaded_images.push(image_file_path.clone());
+                let image_path = job.file(&format!("image{}.png", i));
                 download_image(&image_path_or_url, &image_path).await?;
                 image_file_path = Some(image_path);
             } else {
@@ -76,71 +306,538 @@ pub async fn create_video_with_ffmpeg(
             }
         }
 
-        let sentence_with_color = text_processing::process_sentence(sentence);
-                let ass_content = text_processing::generate_ass_content_bottom(&sentence_with_color)?;
-        let ass_file_name = format!("sentence{}.ass", i);
-        write_ass_file(&ass_file_name, &ass_content)?;
+        if image_file_path.is_some() {
+            last_image_path = image_file_path.clone();
+        } else if segment.directives.contains(&Directive::ImageHold) {
+            image_file_path = last_image_path.clone();
+        }
 
-        let command_output = if let Some(image_path) = image_file_path {
-            ffmpeg_operations::generate_video_with_text_and_image(&ass_file_name, &image_path, i, ImageResolution::Full).await?
+        let (ass_content, duration) = if let SegmentKind::Heading(level) = segment.kind {
+            let (heading, heading_footnotes) =
+                footnotes::extract_footnotes(sentence, &footnote_definitions, &mut footnote_counter);
+            let duration = duration_override.unwrap_or_else(|| {
+                timing::segment_duration(&heading, image_file_path.is_some(), &timing_config)
+            });
+            let heading_footnotes: Vec<String> = heading_footnotes
+                .iter()
+                .map(|note| display_text(note, &theme, &font_plan))
+                .collect();
+            let section = if show_section_counter { section_numbers[i] } else { None };
+            let (margin_l, margin_r) = text_processing::heading_margins(canvas);
+            let mut heading = typography::typeset(
+                &text_processing::process_sentence(&heading, &theme),
+                measure_font,
+                text_processing::heading_font_size(level, &theme),
+                margin_l,
+                margin_r,
+                canvas,
+            );
+            if let Some(style) = karaoke_style {
+                heading = text_processing::karaoke_sentence(&heading, duration, style);
+            }
+            let heading = with_font_fallbacks(&heading, &font_plan);
+            let ass_content = text_processing::generate_ass_content_heading(
+                &heading,
+                level,
+                section,
+                duration,
+                effect_override.unwrap_or(note_effect),
+                &theme,
+                &heading_footnotes,
+                canvas,
+            )?;
+            (ass_content, duration)
+        } else if segment.kind == SegmentKind::List {
+            let mut items = segmentation::parse_list_items(sentence);
+            let item_texts: Vec<&str> = items.iter().map(|item| item.text.as_str()).collect();
+            let item_durations = timing::list_item_durations(&item_texts, &timing_config, duration_override);
+
+            let mut sentence_footnotes = Vec::new();
+            for item in items.iter_mut() {
+                let (text, item_footnotes) =
+                    footnotes::extract_footnotes(&item.text, &footnote_definitions, &mut footnote_counter);
+                item.text = display_text(&text, &theme, &font_plan);
+                sentence_footnotes.extend(item_footnotes);
+            }
+            let sentence_footnotes: Vec<String> = sentence_footnotes
+                .iter()
+                .map(|note| display_text(note, &theme, &font_plan))
+                .collect();
+
+            let ass_content = text_processing::generate_ass_content_list(
+                &items,
+                &item_durations,
+                &theme,
+                &sentence_footnotes,
+                canvas,
+            )?;
+            (ass_content, item_durations.iter().sum())
         } else {
-            ffmpeg_operations::execute_ffmpeg_command(&ass_file_name, i).await?
+            let (sentence, sentence_footnotes) =
+                footnotes::extract_footnotes(sentence, &footnote_definitions, &mut footnote_counter);
+            let duration = duration_override.unwrap_or_else(|| {
+                timing::segment_duration(&sentence, image_file_path.is_some(), &timing_config)
+            });
+
+            let sentence_footnotes: Vec<String> = sentence_footnotes
+                .iter()
+                .map(|note| display_text(note, &theme, &font_plan))
+                .collect();
+            let sentence_with_color = text_processing::process_sentence(&sentence, &theme);
+            let (margin_l, margin_r, _) = theme.margins_within(canvas);
+            let mut sentence_with_color =
+                typography::typeset(&sentence_with_color, measure_font, theme.font_size, margin_l, margin_r, canvas);
+            // Timed after typesetting, which would otherwise move line breaks between the words
+            if let Some(style) = karaoke_style {
+                sentence_with_color = text_processing::karaoke_sentence(&sentence_with_color, duration, style);
+            }
+            let sentence_with_color = with_font_fallbacks(&sentence_with_color, &font_plan);
+            let ass_content = text_processing::generate_ass_content_bottom(
+                &sentence_with_color,
+                duration,
+                effect_override.unwrap_or(note_effect),
+                &theme,
+                &sentence_footnotes,
+                canvas,
+            )?;
+            (ass_content, duration)
         };
 
-        if command_output.status.success() {
-            let progress = (i + 1) as f64 / sentences.len() as f64 * 100.0;
-            emit_progress_event(&window, progress)?;
-            file_list.push_str(&format!("file 'output{}.mp4'\n", i));
-        } else {
-            eprintln!(
-                "Error: {}",
-                String::from_utf8_lossy(&command_output.stderr)
+        // A QR code for the first link of the segment
+        let qr_code_file = match redaction::unredacted_links(sentence).first() {
+            Some(link) if settings.qr_codes => {
+                let qr_code_file = job.file(&format!("qr{}.png", i));
+                links::write_qr_code(&link.url, &qr_code_file)?;
+                Some(qr_code_file)
+            }
+            _ => None,
+        };
+
+        // Chapter titles are plain text in the file's metadata, so blurred words and footnotes
+        // are left out
+        let chapter = match segment.kind {
+            SegmentKind::Heading(_) => {
+                let title = footnotes::remove_footnotes(&redaction::without_blurred(sentence));
+                Some(text_processing::strip_ass_tags(&text_processing::process_sentence(&title, &theme)))
+                    .filter(|title| !title.trim().is_empty())
+            }
+            _ => None,
+        };
+
+        clips.push(Clip {
+            ass_content,
+            duration,
+            image: image_file_path,
+            qr_code: qr_code_file,
+            transition: settings.transition,
+            chapter,
+        });
+    }
+
+    if settings.sources && !note_links.is_empty() {
+        let link_colour = theme::ass_colour(&theme.link_colour);
+        let text_colour = theme::ass_colour(&theme.text_colour);
+
+        for (page, page_links) in note_links.chunks(SOURCES_PER_CARD).enumerate() {
+            let card_text = links::sources_card_text(page_links, &link_colour, &text_colour);
+            let card_text = with_font_fallbacks(&card_text, &font_plan);
+            let plain_text = text_processing::strip_ass_tags(&card_text);
+            let duration = timing::segment_duration(&plain_text, false, &timing_config);
+            let ass_content = text_processing::generate_ass_content_centered(
+                &card_text,
+                duration,
+                text_processing::TextEffect::None,
+                &theme,
+                &[],
+                canvas,
+            )?;
+            clips.push(Clip {
+                ass_content,
+                duration,
+                image: None,
+                qr_code: None,
+                transition: settings.transition,
+                chapter: if page == 0 { Some("Sources".to_string()) } else { None },
+            });
+        }
+    }
+
+    let timeline = timeline::compile(&clips, &theme::ass_colour(&theme.background), canvas);
+
+    if let Some(platform) = settings.platform {
+        if timeline.duration > platform.max_duration() {
+            let warning = format!(
+                "The video is {:.0}s long, {} only accepts videos up to {:.0}s",
+                timeline.duration,
+                platform.name(),
+                platform.max_duration()
             );
+            log_utils::print_pretty_log(&warning, "red");
+            emit_warning_event(&handle, &warning)?;
         }
+    }
 
-        delete_ass_file(&ass_file_name)?;
+    match settings.renderer {
+        Renderer::Timeline => {
+            render_timeline(&handle, &progress, &job, &timeline, &background, fonts_dir, profile).await?;
+        }
+        Renderer::Segments => {
+            render_segments(&handle, &progress, &job, &clips, &background, fonts_dir, profile, segment_workers(settings)).await?;
+        }
+        Renderer::Native => {
+            // Anything the native renderer can't draw goes through libass instead
+            let unsupported = frame_renderer::unsupported_features(&clips, profile);
+            let font_set = match &font_plan {
+                Some(plan) if unsupported.is_empty() => Some(FontSet::from_plan(plan)),
+                _ => None,
+            };
+            match font_set {
+                Some(font_set) => render_native(&handle, &progress, &job, clips, font_set, profile, &theme.background).await?,
+                None => {
+                    let warning = if unsupported.is_empty() {
+                        "No fonts found for the native renderer, using the timeline renderer".to_string()
+                    } else {
+                        format!(
+                            "The native renderer can't draw {}, using the timeline renderer",
+                            unsupported.join(", ")
+                        )
+                    };
+                    log_utils::print_pretty_log(&warning, "red");
+                    emit_warning_event(&handle, &warning)?;
+                    render_timeline(&handle, &progress, &job, &timeline, &background, fonts_dir, profile).await?;
+                }
+            }
+        }
     }
 
-    log_utils::print_pretty_log("Generate file list...", "blue");
+    // A dry run prints the ffmpeg commands without running them, so there is no video to check or move
+    let dry_run = ffmpeg_operations::dry_run();
 
-    write_file_list(&file_list)?;
+    let rendered_video = job.file("output.mp4");
+    if !dry_run && fs::metadata(&rendered_video).is_err() {
+        return Err("Rendered video (output.mp4) not found".into());
+    }
 
-    log_utils::print_pretty_log("Concatenating....", "blue");
-    emit_stage_event(&window, "Concatenating videos");
+    let chapters_file = if timeline.chapters.is_empty() {
+        None
+    } else {
+        let chapters_file = job.file("chapters.txt");
+        write_chapters_file(&chapters_file, &timeline.chapters, timeline.duration)?;
+        Some(chapters_file)
+    };
+
+    // Merged inside the job directory so a failed merge never replaces a previous video
+    emit_stage_event(&handle, "Merging audio")?;
+    let merged_video = job.file("final_output.mp4");
+    progress.start(Stage::Merge, vec![timeline.duration]);
+    ffmpeg_operations::merge_audio_with_video(&rendered_video, chapters_file.as_deref(), audio_file.as_deref(), settings.platform.map(|platform| platform.loudness()), &merged_video, &|update| progress.report(Stage::Merge, 0, update)).await?;
+    progress.finish(Stage::Merge);
+
+    let final_video = match settings.target_size {
+        Some(target_size) => {
+            let fitted_video = job.file("fitted_output.mp4");
+            fit_to_size(&handle, &progress, &job, &merged_video, &fitted_video, target_size, timeline.duration, audio_file.is_some(), profile).await?;
+            fitted_video
+        }
+        None => merged_video,
+    };
 
-    ffmpeg_operations::concatenate_videos().await?;
+    let output_file = format!("{}.mp4", settings.output.as_deref().unwrap_or("final_output"));
+    if !dry_run {
+        job_dir::move_file(&final_video, &output_file)?;
+    }
+
+    emit_stage_event(&handle, "Done")?;
+
+    Ok(())
+}
+
+// Render the compiled timeline with a single ffmpeg run into the job's output.mp4
+async fn render_timeline(
+    handle: &JobHandle,
+    progress: &RenderProgress,
+    job: &JobDir,
+    timeline: &Timeline,
+    background: &str,
+    fonts_dir: &str,
+    profile: &RenderProfile,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // One ffmpeg pass, which a pause wouldn't stop
+    handle.set_pausable(false).await;
+    log_utils::print_pretty_log("Rendering timeline...", "blue");
+    emit_stage_event(handle, "Rendering video")?;
+
+    let ass_file_name = job.file("timeline.ass");
+    write_ass_file(&ass_file_name, &timeline.ass_content)?;
+    let filtergraph = timeline::filtergraph(timeline, &ass_file_name, fonts_dir, profile);
+    progress.start(Stage::Render, vec![timeline.duration]);
+    ffmpeg_operations::render_timeline(
+        filtergraph,
+        &timeline::overlay_inputs(timeline),
+        timeline.duration,
+        background,
+        &job.file("output.mp4"),
+        profile,
+        &|update| progress.report(Stage::Render, 0, update),
+    )
+    .await?;
+    progress.finish(Stage::Render);
+    Ok(())
+}
 
-    if !fs::metadata("output.mp4").is_ok() {
-        return Err("Concatenated video (output.mp4) not found".into());
+// Draw and encode the frames in process into the job's output.mp4, off the async runtime
+async fn render_native(
+    handle: &JobHandle,
+    progress: &Arc<RenderProgress>,
+    job: &JobDir,
+    clips: Vec<Clip>,
+    font_set: FontSet,
+    profile: &RenderProfile,
+    background: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    log_utils::print_pretty_log("Rendering frames...", "blue");
+    emit_stage_event(handle, "Rendering video")?;
+
+    // There is no ffmpeg command to print for frames drawn in process
+    if ffmpeg_operations::dry_run() {
+        log_utils::print_pretty_log("Dry run, skipping the native frame render", "blue");
+        handle.set_pausable(false).await;
+        return Ok(());
     }
 
-    emit_stage_event(&window, "Merging audio");
-    ffmpeg_operations::merge_audio_with_video().await?;
+    let progress = progress.clone();
+    let profile = profile.clone();
+    let background = background.to_string();
+    let output_file = job.file("output.mp4");
+    // The frame loop runs on its own thread, which aborting the job doesn't stop, so it checks
+    // for pause and cancel before every frame
+    let frame_handle = handle.clone();
+    tokio::task::spawn_blocking(move || {
+        frame_renderer::render(
+            &clips,
+            &font_set,
+            &profile,
+            &background,
+            &output_file,
+            |percent| progress.report_fraction(Stage::Render, percent / 100.0),
+            || frame_handle.should_stop(),
+        )?;
+        progress.finish(Stage::Render);
+        Ok::<_, Box<dyn Error + Send + Sync>>(())
+    })
+    .await??;
+    handle.set_pausable(false).await;
+    Ok(())
+}
 
-    if delete_temp_videos {
-        delete_temporary_videos(&sentences.iter().map(|s| s.as_str()).collect::<Vec<&str>>())?;
-        delete_file_list()?;
+// Render every clip to its own MP4, up to `workers` at a time, and stream-copy them together
+// into the job's output.mp4 in clip order. The first failure stops the other renders
+#[allow(clippy::too_many_arguments)]
+async fn render_segments(
+    handle: &JobHandle,
+    progress: &Arc<RenderProgress>,
+    job: &JobDir,
+    clips: &[Clip],
+    background: &str,
+    fonts_dir: &str,
+    profile: &RenderProfile,
+    workers: usize,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let permits = Arc::new(Semaphore::new(workers.max(1)));
+    let mut renders = JoinSet::new();
+    progress.start(Stage::Render, clips.iter().map(|clip| clip.duration).collect());
+
+    for (i, clip) in clips.iter().cloned().enumerate() {
+        let permits = permits.clone();
+        let handle = handle.clone();
+        let progress = progress.clone();
+        let ass_file_name = job.file(&format!("sentence{}.ass", i));
+        let output_video = job.file(&format!("output{}.mp4", i));
+        let background = background.to_string();
+        let fonts_dir = fonts_dir.to_string();
+        let profile = profile.clone();
+        renders.spawn(async move {
+            let _permit = permits.acquire_owned().await?;
+            handle.wait_while_paused().await;
+            let on_progress = |update| progress.report(Stage::Render, i, update);
+            render_clip(i, &clip, &ass_file_name, &output_video, &background, &fonts_dir, &profile, &on_progress).await?;
+            progress.finish_part(Stage::Render, i);
+            Ok::<_, Box<dyn Error + Send + Sync>>((i, output_video))
+        });
     }
 
-    if delete_downloaded_images {
-        for image_file_name in downloaded_images {
-            if let Some(file_name) = image_file_name {
-                fs::remove_file(file_name)?;
+    // Videos by clip index, so the concat order doesn't depend on which render finished first
+    let mut rendered: Vec<Option<String>> = vec![None; clips.len()];
+
+    while let Some(joined) = renders.join_next().await {
+        let result = match joined {
+            Ok(result) => result,
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok((i, output_video)) => {
+                rendered[i] = Some(output_video);
+            }
+            Err(e) => {
+                // Aborting the other renders drops their ffmpeg processes, which kills them
+                renders.abort_all();
+                while renders.join_next().await.is_some() {}
+                return Err(e);
             }
-            // log_utils::print_pretty_log(
-            // detele not working
         }
     }
 
-    emit_stage_event(&window, "Done");
+    let file_list: String = rendered
+        .iter()
+        .flatten()
+        .map(|output_video| format!("file '{}'\n", Path::new(output_video).file_name().unwrap_or_default().to_string_lossy()))
+        .collect();
+
+    log_utils::print_pretty_log("Generate file list...", "blue");
+
+    let file_list_path = job.file("file_list.txt");
+    write_file_list(&file_list_path, &file_list)?;
+
+    handle.set_pausable(false).await;
+    log_utils::print_pretty_log("Concatenating....", "blue");
+    emit_stage_event(handle, "Concatenating videos")?;
+
+    let total_duration = clips.iter().map(|clip| clip.duration).sum();
+    progress.finish(Stage::Render);
+    progress.start(Stage::Concatenate, vec![total_duration]);
+    ffmpeg_operations::concatenate_videos(&file_list_path, &job.file("output.mp4"), &|update| {
+        progress.report(Stage::Concatenate, 0, update)
+    })
+    .await?;
+    progress.finish(Stage::Concatenate);
 
     Ok(())
 }
 
+// Render one clip to `output_video`
+#[allow(clippy::too_many_arguments)]
+async fn render_clip(
+    i: usize,
+    clip: &Clip,
+    ass_file_name: &str,
+    output_video: &str,
+    background: &str,
+    fonts_dir: &str,
+    profile: &RenderProfile,
+    on_progress: OnProgress<'_>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    write_ass_file(ass_file_name, &clip.ass_content)?;
+
+    let command_output = if let Some(image_path) = &clip.image {
+        ffmpeg_operations::generate_video_with_text_and_image(ass_file_name, image_path, output_video, ImageResolution::Full, clip.duration, background, fonts_dir, clip.transition, clip.qr_code.as_deref(), profile, on_progress).await?
+    } else {
+        ffmpeg_operations::execute_ffmpeg_command(ass_file_name, output_video, clip.duration, background, fonts_dir, clip.transition, clip.qr_code.as_deref(), profile, on_progress).await?
+    };
+
+    if command_output.status.success() {
+        Ok(())
+    } else {
+        eprintln!(
+            "Error: {}",
+            String::from_utf8_lossy(&command_output.stderr)
+        );
+        Err(format!("Failed to render segment {}", i + 1).into())
+    }
+}
+
+// Share of a target size left for the MP4 container's own data
+const CONTAINER_OVERHEAD: f64 = 0.02;
+// Below this the video is unwatchable, so a target size that needs less is refused
+const MIN_VIDEO_BITRATE: u64 = 100_000;
+// Encodes tried before giving up on a target size
+const MAX_SIZE_ATTEMPTS: usize = 3;
+
+// Re-encode `input_video` into `output_video` so it's no bigger than `target_size` bytes. The
+// video bitrate is what's left of the size once the audio is accounted for; if the encoder
+// still overshoots, the bitrate is lowered by the overshoot and the encode repeated
+#[allow(clippy::too_many_arguments)]
+async fn fit_to_size(
+    handle: &JobHandle,
+    progress: &RenderProgress,
+    job: &JobDir,
+    input_video: &str,
+    output_video: &str,
+    target_size: u64,
+    duration: f64,
+    has_audio: bool,
+    profile: &RenderProfile,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    log_utils::print_pretty_log(&format!("Fitting the video into {}...", format_size(target_size)), "blue");
+    emit_stage_event(handle, "Fitting to target size")?;
+
+    let total_bitrate = target_size as f64 * 8.0 * (1.0 - CONTAINER_OVERHEAD) / duration.max(0.1);
+    let audio_bitrate = if has_audio { ffmpeg_operations::AUDIO_BITRATE as f64 } else { 0.0 };
+    let mut video_bitrate = (total_bitrate - audio_bitrate).max(0.0) as u64;
+    let passlog = job.file("passlog");
+
+    let mut attempt = 1;
+    loop {
+        if video_bitrate < MIN_VIDEO_BITRATE {
+            return Err(format!(
+                "{} is too small for a {:.0}s video, it leaves {} kbit/s for the picture",
+                format_size(target_size),
+                duration,
+                video_bitrate / 1000
+            )
+            .into());
+        }
+
+        progress.start(Stage::Compress, vec![duration, duration]);
+        ffmpeg_operations::encode_two_pass(input_video, output_video, video_bitrate, profile, &passlog, &|pass, update| {
+            progress.report(Stage::Compress, pass, update)
+        })
+        .await?;
+        progress.finish(Stage::Compress);
+
+        // A dry run writes nothing to measure
+        if ffmpeg_operations::dry_run() {
+            return Ok(());
+        }
+        let size = fs::metadata(output_video)?.len();
+        if size <= target_size {
+            log_utils::print_pretty_log(&format!("Video is {}", format_size(size)), "green");
+            return Ok(());
+        }
 
+        if attempt == MAX_SIZE_ATTEMPTS {
+            return Err(format!(
+                "Could not fit the video into {}, it is still {} after {} attempts",
+                format_size(target_size),
+                format_size(size),
+                MAX_SIZE_ATTEMPTS
+            )
+            .into());
+        }
 
-// 
+        // Scale the bitrate down by the overshoot, with a little extra so the next try lands under
+        video_bitrate = (video_bitrate as f64 * target_size as f64 / size as f64 * 0.95) as u64;
+        let message = format!(
+            "Video came out at {}, over the {} target, re-encoding at {} kbit/s",
+            format_size(size),
+            format_size(target_size),
+            video_bitrate / 1000
+        );
+        log_utils::print_pretty_log(&message, "red");
+        emit_warning_event(handle, &message)?;
+        attempt += 1;
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / 1e6)
+}
 
+// One ffmpeg per CPU unless the note asks for a different number
+fn segment_workers(settings: &NoteSettings) -> usize {
+    settings
+        .workers
+        .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1))
+}
 
 fn write_ass_file(file_name: &str, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
     fs::write(file_name, content)?;
@@ -149,51 +846,71 @@ fn write_ass_file(file_name: &str, content: &str) -> Result<(), Box<dyn Error +
 
 
 
-fn emit_progress_event(
-    window: &Window,
-    progress: f64,
+fn emit_warning_event(
+    handle: &JobHandle,
+    message: &str,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    window.emit("progress", Some(progress))?;
+    handle.emit_warning(message)?;
     Ok(())
 }
 
-fn emit_stage_event(
-    window: &Window,
-    stage: &str,
+// What was redacted, sent before rendering starts so it can be checked
+fn emit_redaction_event(
+    handle: &JobHandle,
+    report: &[String],
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    window.emit("stage", Some(stage))?;
+    handle.emit("redaction", report)?;
     Ok(())
 }
 
-fn delete_ass_file(file_name: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
-    fs::remove_file(file_name)?;
+fn emit_stage_event(
+    handle: &JobHandle,
+    stage: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    handle.emit_stage(stage)?;
     Ok(())
 }
 
-fn write_file_list(file_list: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
-    fs::write("file_list.txt", file_list)?;
+// Write heading chapters as an FFMETADATA file, each chapter running until the next one
+fn write_chapters_file(path: &str, chapters: &[(f64, String)], total: f64) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut content = String::from(";FFMETADATA1\n");
+    for (i, (start, title)) in chapters.iter().enumerate() {
+        let end = chapters.get(i + 1).map_or(total, |next| next.0);
+        let title = title
+            .replace('\\', "\\\\")
+            .replace('=', "\\=")
+            .replace(';', "\\;")
+            .replace('#', "\\#");
+        content.push_str(&format!(
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            (start * 1000.0).round() as u64,
+            (end * 1000.0).round() as u64,
+            title
+        ));
+    }
+    fs::write(path, content)?;
     Ok(())
 }
 
-
-
-fn delete_temporary_videos(
-    sentences: &[&str],
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    for (i, _) in sentences.iter().enumerate() {
-        fs::remove_file(format!("output{}.mp4", i))?;
+fn with_font_fallbacks(text: &str, font_plan: &Option<FontPlan>) -> String {
+    match font_plan {
+        Some(plan) => fonts::apply_fallbacks(text, plan),
+        None => text.to_string(),
     }
-    Ok(())
 }
 
-fn delete_file_list() -> Result<(), Box<dyn Error + Send + Sync>> {
-    fs::remove_file("file_list.txt")?;
+// Markdown to finished ASS text for the lines that aren't typeset: list items and footnotes
+fn display_text(text: &str, theme: &theme::Theme, font_plan: &Option<FontPlan>) -> String {
+    with_font_fallbacks(&typography::smarten(&text_processing::process_sentence(text, theme)), font_plan)
+}
+
+fn write_file_list(path: &str, file_list: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    fs::write(path, file_list)?;
     Ok(())
 }
 
-fn check_image_in_text(text: &str) -> Option<String> {
-    // let local_image_directory = env::var("LOCAL_IMAGE_DIR_PATH").expect("LOCAL_IMAGE_DIR_PATH not found in .env file");
-    let local_image_directory = std::env::var("LOCAL_IMAGE_DIR_PATH").expect("LOCAL_IMAGE_DIR_PATH not found in .env file");
+// Only local images need LOCAL_IMAGE_DIR_PATH, so a note without them renders without it
+fn check_image_in_text(text: &str) -> Result<Option<String>, String> {
     // Local image syntax: ![[image.png]]
     let local_image_regex = Regex::new(r"!\[\[([^\]]+)\]\]").unwrap();
     // Hosted image syntax: ![alt](url.png)
@@ -202,14 +919,16 @@ fn check_image_in_text(text: &str) -> Option<String> {
     if let Some(captures) = local_image_regex.captures(text) {
         // Extract the image path from the capture group
         let image_path = captures.get(1).unwrap().as_str();
+        let local_image_directory = std::env::var("LOCAL_IMAGE_DIR_PATH")
+            .map_err(|_| format!("LOCAL_IMAGE_DIR_PATH is not set in the .env file, so ![[{}]] can't be found", image_path))?;
         let full_image_path = Path::new(&local_image_directory).join(image_path);
-        Some(full_image_path.to_str().unwrap().to_string())
+        Ok(Some(full_image_path.to_string_lossy().to_string()))
     } else if let Some(captures) = hosted_image_regex.captures(text) {
         // Extract the image URL from the capture group
         let image_url = captures.get(2).unwrap().as_str().to_string();
-        Some(image_url)
+        Ok(Some(image_url))
     } else {
-        None
+        Ok(None)
     }
 }
 
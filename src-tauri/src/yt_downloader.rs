@@ -1,20 +1,22 @@
-use std::process::{Command, Output};
+use std::process::Output;
+use tokio::process::Command;
 
-pub fn download_youtube_as_mp3(url: &str) -> Result<Output, std::io::Error> {
-    let output_dir = "./temp_files/audio.mp3"; // Assuming you want the output in the ../output directory
-    
+// yt-dlp is killed if the job is cancelled while it downloads
+pub async fn download_youtube_as_mp3(url: &str, output_dir: &str) -> Result<Output, std::io::Error> {    
     println!("Downloading YouTube video as MP3...");
     println!("URL: {}", url);
 
     let output = Command::new("yt-dlp")
-        .args(&[
+        .kill_on_drop(true)
+        .args([
             "--extract-audio",
             "--audio-format", "mp3",
             "-f", "bestaudio",
             "-o", output_dir,
             url,
         ])
-        .output();
+        .output()
+        .await;
 
     println!("Command done!");
 
@@ -1,4 +1,4 @@
-import React, { useEffect, useState, useRef } from "react";
+import React, { useCallback, useEffect, useState, useRef } from "react";
 import useVideoLoader from "../hooks/useVideoLoader";
 import useFileContentLoader from "../hooks/useFileContentLoader";
 import useEventListeners from "../hooks/useEventListeners";
@@ -6,17 +6,41 @@ import { formatDate } from "../hooks/useFormatDate";
 import { invoke } from "@tauri-apps/api";
 
 
+const formatEta = (seconds) =>
+  seconds >= 60 ? `${Math.floor(seconds / 60)}m ${Math.round(seconds % 60)}s` : `${Math.round(seconds)}s`;
+
 const Overlay = ({ file, onClick }) => {
   const [visible, setVisible] = useState(false);
   const imageRef = useRef(null);
   const [progress, setProgress] = useState(0);
+  const [eta, setEta] = useState(null);
   const [stage, setStage] = useState("");
   const [videoReady, setVideoReady] = useState(false);
+  const [redactionReport, setRedactionReport] = useState([]);
+  const [warnings, setWarnings] = useState([]);
+  const [jobId, setJobId] = useState(null);
+  const [jobState, setJobState] = useState("");
+  // Pausing is only offered while the render can stop, not during a single ffmpeg pass
+  const [jobPausable, setJobPausable] = useState(false);
+
+  const trackJob = useCallback((job) => {
+    setJobState(job.state);
+    setJobPausable(job.pausable);
+    setWarnings(job.warnings);
+  }, []);
 
-  const createVideo = useVideoLoader(file);
+  const createVideo = useVideoLoader(file, setJobId, trackJob);
   const getTextContent = useFileContentLoader(file);
 
-  useEventListeners(setProgress, setStage);
+  useEventListeners(jobId, setProgress, setStage, setRedactionReport, trackJob, setEta);
+
+  const controlJob = async (command) => {
+    try {
+      await invoke(command, { id: jobId });
+    } catch (error) {
+      console.error(error);
+    }
+  };
 
   useEffect(() => {
     if (stage === "Done") {
@@ -184,6 +208,32 @@ const Overlay = ({ file, onClick }) => {
         >
           Generate Video
         </button>
+        {(jobState === "running" || jobState === "paused") && (
+          <>
+            {(jobState === "paused" || jobPausable) && (
+              <button
+                onClick={() => controlJob(jobState === "paused" ? "resume_job" : "pause_job")}
+                className="bg-gray-600 text-white px-3 py-2 rounded-md my-10 mr-2"
+              >
+                {jobState === "paused" ? "Resume" : "Pause"}
+              </button>
+            )}
+            <button
+              onClick={() => controlJob("cancel_job")}
+              className="bg-red-500 text-white px-3 py-2 rounded-md my-10"
+            >
+              Cancel
+            </button>
+          </>
+        )}
+        {(jobState === "failed" || jobState === "cancelled") && (
+          <button
+            onClick={() => controlJob("rerun_job")}
+            className="bg-gray-600 text-white px-3 py-2 rounded-md my-10"
+          >
+            Re-run
+          </button>
+        )}
         {/* add a button in which the image is https://immage and there is no text */}
 
         <button
@@ -201,8 +251,29 @@ const Overlay = ({ file, onClick }) => {
 
         
         <br />
+        {redactionReport.length > 0 && (
+          <div className="mb-2 text-sm text-gray-300">
+            Redacted:
+            <ul className="list-disc ml-6">
+              {redactionReport.map((line, index) => (
+                <li key={index}>{line}</li>
+              ))}
+            </ul>
+          </div>
+        )}
+        {warnings.length > 0 && (
+          <div className="mb-2 text-sm text-yellow-300">
+            Warnings:
+            <ul className="list-disc ml-6">
+              {warnings.map((warning, index) => (
+                <li key={index}>{warning}</li>
+              ))}
+            </ul>
+          </div>
+        )}
         <span className="mb-2">
-          {stage}</span>
+          {stage}{jobState && ` (${jobState})`}
+          {eta != null && progress < 100 && ` - about ${formatEta(eta)} left`}</span>
         <div className="progress-bar">
           <div
             className="progress-bar-fill"
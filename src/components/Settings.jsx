@@ -1,17 +1,75 @@
-import { useState, useEffect } from "react";
+import { useState, useEffect, useRef } from "react";
+import { invoke } from "@tauri-apps/api";
 import TextFormattingComponent from "./TextFormattingComponent";
 
+// Colour pickers fire on every drag step, so saves wait until the colour settles
+const SAVE_DELAY = 400;
+
 export default function Settings({ onClick }) {
   const [visible, setVisible] = useState(false);
   const [boldText, setBoldText] = useState("**Bold text**");
   const [italicText, setItalicText] = useState("*Italic text*");
   const [boldColor, setBoldColor] = useState("#ff0000");
   const [italicColor, setItalicColor] = useState("#00ff00");
+  const [theme, setTheme] = useState(null);
+  const [themeNames, setThemeNames] = useState(["default"]);
+  const [selectedTheme, setSelectedTheme] = useState("default");
+  const pendingSave = useRef(null);
+
+  // Write the theme waiting to be saved, if any, straight away
+  const flushSave = () => {
+    if (!pendingSave.current) return;
+    clearTimeout(pendingSave.current.timer);
+    invoke("save_theme", { theme: pendingSave.current.theme }).catch((error) =>
+      console.error(error)
+    );
+    pendingSave.current = null;
+  };
 
   useEffect(() => {
     setVisible(true);
+    invoke("list_themes")
+      .then((names) => setThemeNames(names))
+      .catch((error) => console.error(error));
+    return flushSave;
   }, []);
 
+  useEffect(() => {
+    invoke("load_theme", { name: selectedTheme })
+      .then((loadedTheme) => {
+        setTheme(loadedTheme);
+        setBoldColor(loadedTheme.bold_colour);
+        setItalicColor(loadedTheme.italic_colour);
+      })
+      .catch((error) => console.error(error));
+  }, [selectedTheme]);
+
+  const saveThemeColour = (key, colour) => {
+    if (!theme) return;
+    const updatedTheme = { ...theme, [key]: colour };
+    setTheme(updatedTheme);
+    if (pendingSave.current) clearTimeout(pendingSave.current.timer);
+    pendingSave.current = {
+      theme: updatedTheme,
+      timer: setTimeout(flushSave, SAVE_DELAY),
+    };
+  };
+
+  const handleThemeChange = (event) => {
+    flushSave();
+    setSelectedTheme(event.target.value);
+  };
+
+  const handleBoldColorChange = (event) => {
+    setBoldColor(event.target.value);
+    saveThemeColour("bold_colour", event.target.value);
+  };
+
+  const handleItalicColorChange = (event) => {
+    setItalicColor(event.target.value);
+    saveThemeColour("italic_colour", event.target.value);
+  };
+
   const handleOverlayClick = (event) => {
     if (event.target === event.currentTarget) {
       setVisible(false);
@@ -67,10 +125,47 @@ export default function Settings({ onClick }) {
             className="border border-gray-300 rounded-md px-3 py-2 focus:outline-none focus:ring-2 focus:ring-blue-500 focus:border-blue-500 bg-gray-700 text-white w-full"
           />
         </div>
+        <div className="mt-4">
+          <h3 className="text-md font-semibold text-white">Theme</h3>
+          <select
+            value={selectedTheme}
+            onChange={handleThemeChange}
+            className="border border-gray-300 rounded-md px-3 py-2 focus:outline-none focus:ring-2 focus:ring-blue-500 focus:border-blue-500 bg-gray-700 text-white w-full"
+          >
+            {themeNames.map((name) => (
+              <option key={name} value={name}>
+                {name}
+              </option>
+            ))}
+          </select>
+        </div>
         <div className="mt-4 bg-gray-900 p-4 rounded-md">
           <h2>Text Formatting</h2>
           <TextFormattingComponent />
         </div>
+        <div className="mt-4">
+          <h3 className="text-md font-semibold text-white">Bold</h3>
+          <div className="flex items-center space-x-2">
+            <input
+              type="text"
+              value={boldText}
+              onChange={handleBoldChange}
+              className="border border-gray-300 rounded-md px-3 py-2 focus:outline-none focus:ring-2 focus:ring-blue-500 focus:border-blue-500 bg-gray-700 text-white flex-1"
+            />
+            <input
+              type="color"
+              value={boldColor}
+              onChange={handleBoldColorChange}
+              className="border border-gray-300 rounded-md h-10 w-10"
+            />
+          </div>
+          <div
+            className="mt-2 p-2 rounded-md"
+            style={{ backgroundColor: boldColor }}
+          >
+            <strong>{boldText}</strong>
+          </div>
+        </div>
         <div className="mt-4">
           <h3 className="text-md font-semibold text-white">Italic</h3>
           <div className="flex items-center space-x-2">
@@ -83,7 +178,7 @@ export default function Settings({ onClick }) {
             <input
               type="color"
               value={italicColor}
-              onChange={(e) => setItalicColor(e.target.value)}
+              onChange={handleItalicColorChange}
               className="border border-gray-300 rounded-md h-10 w-10"
             />
           </div>
@@ -1,24 +1,54 @@
 import { useEffect } from "react";
 import { listen } from "@tauri-apps/api/event";
+import { invoke } from "@tauri-apps/api";
 
-const useEventListeners = (setProgress, setStage) => {
+// Events are scoped to a render job: only those for `jobId` are passed on.
+// Progress reports carry the overall percentage and the estimated seconds left; `onJobStatus`
+// gets the job's whole status, warnings included, whenever it changes and once the listeners
+// are in place, for anything sent before they were
+const useEventListeners = (jobId, setProgress, setStage, setRedactionReport, onJobStatus, setEta) => {
     useEffect(() => {
       let unlistenProgress;
       let unlistenStage;
-  
+      let unlistenRedaction;
+      let unlistenJob;
+
       listen('progress', (event) => {
-        setProgress(event.payload);
+        if (event.payload.jobId !== jobId) return;
+        const report = event.payload.payload;
+        setProgress(report.percent);
+        setEta && setEta(report.etaSeconds);
       }).then(unlisten => { unlistenProgress = unlisten; });
   
       listen('stage', (event) => {
-        setStage(event.payload);
+        if (event.payload.jobId === jobId) setStage(event.payload.payload);
       }).then(unlisten => { unlistenStage = unlisten; });
+
+      if (setRedactionReport) {
+        listen('redaction', (event) => {
+          if (event.payload.jobId === jobId) setRedactionReport(event.payload.payload || []);
+        }).then(unlisten => { unlistenRedaction = unlisten; });
+      }
+
+      if (onJobStatus) {
+        listen('job', (event) => {
+          if (event.payload.id === jobId) onJobStatus(event.payload);
+        }).then(unlisten => {
+          unlistenJob = unlisten;
+          return jobId != null ? invoke('list_jobs') : [];
+        }).then(jobs => {
+          const job = jobs.find(job => job.id === jobId);
+          if (job) onJobStatus(job);
+        }).catch(console.error);
+      }
   
       return () => {
         unlistenProgress && unlistenProgress();
         unlistenStage && unlistenStage();
+        unlistenRedaction && unlistenRedaction();
+        unlistenJob && unlistenJob();
       };
-    }, [setProgress, setStage]);
+    }, [jobId, setProgress, setStage, setRedactionReport, onJobStatus, setEta]);
   };
   
-  export default useEventListeners;
\ No newline at end of file
+  export default useEventListeners;
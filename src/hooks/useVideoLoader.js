@@ -1,10 +1,14 @@
 import { invoke } from "@tauri-apps/api";
 
-const useVideoLoader = (file) => {
+// Queues a render of the file; `setJobId` receives the new job's id and `onJobStatus` its status.
+// The status comes from the command because a job that starts straight away announces it
+// before the job's events are listened for
+const useVideoLoader = (file, setJobId, onJobStatus) => {
   const createVideo = async () => {
     try {
-      const response = await invoke("create_video_with_ffmpeg", { path: file.filepath });
-      console.log(response);
+      const job = await invoke("create_video_with_ffmpeg", { path: file.filepath });
+      setJobId(job.id);
+      onJobStatus(job);
     } catch (error) {
       console.error(error);
     }
@@ -13,4 +17,4 @@ const useVideoLoader = (file) => {
   return createVideo;
 };
 
-export default useVideoLoader;
\ No newline at end of file
+export default useVideoLoader;